@@ -0,0 +1,33 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use whisper::{
+	codec::{Framing, DEFAULT_MAX_FRAME_LENGTH},
+	pty::{StreamRead, StreamWrite},
+};
+use wisp_mux::ws::{LockedWebSocketWrite, WebSocketRead};
+
+// Feeds arbitrary bytes through the same decoding path as `PtyRead::wisp_read_frame`. The first
+// byte picks the framing. Decoding must never panic or allocate past the maximum frame length.
+fuzz_target!(|data: &[u8]| {
+	let Some((framing, data)) = data.split_first() else {
+		return;
+	};
+	let framing = if framing & 1 == 0 {
+		Framing::Length
+	} else {
+		Framing::Sync
+	};
+
+	let tx = LockedWebSocketWrite::new(Box::new(StreamWrite::new(
+		tokio::io::sink(),
+		DEFAULT_MAX_FRAME_LENGTH,
+	)));
+	let mut rx = StreamRead::with_framing(data, framing, DEFAULT_MAX_FRAME_LENGTH);
+
+	futures::executor::block_on(async {
+		while let Ok(frame) = rx.wisp_read_frame(&tx).await {
+			assert!(frame.payload.len() <= DEFAULT_MAX_FRAME_LENGTH);
+		}
+	});
+});
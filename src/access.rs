@@ -0,0 +1,156 @@
+//! Cloudflare Access.
+//!
+//! Wisp servers behind Cloudflare Access only accept the WebSocket upgrade with credentials. Whisper
+//! sends either a service token (`CF-Access-Client-Id`/`CF-Access-Client-Secret`) or a
+//! `CF_Authorization` token from a file, e.g. one written by `cloudflared access token`. The file is
+//! read again on every connect, so a token refreshed by another tool is picked up on the next
+//! reconnect. When Access hands out a new `CF_Authorization` cookie, whisper writes it back to the
+//! file.
+
+use std::{
+	error::Error,
+	fs::{OpenOptions, Permissions},
+	io::{self, Write},
+	os::unix::fs::{OpenOptionsExt, PermissionsExt},
+	path::{Path, PathBuf},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use clap::Args;
+use hyper::{header::SET_COOKIE, HeaderMap};
+use log::{info, warn};
+
+use crate::util::WhisperError;
+
+pub const CLIENT_ID_HEADER: &str = "CF-Access-Client-Id";
+pub const CLIENT_SECRET_HEADER: &str = "CF-Access-Client-Secret";
+pub const TOKEN_COOKIE: &str = "CF_Authorization";
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct AccessOptions {
+	/// Connect through Cloudflare Access. The URL may also use http:// or https://.
+	#[arg(short, long)]
+	pub cf: bool,
+	/// Client ID of a Cloudflare Access service token
+	#[arg(long, requires = "cf_client_secret")]
+	pub cf_client_id: Option<String>,
+	/// Client secret of a Cloudflare Access service token
+	#[arg(long, requires = "cf_client_id")]
+	pub cf_client_secret: Option<String>,
+	/// File with a CF_Authorization token. Read on every connect and updated when Access sends a
+	/// new one.
+	#[arg(long)]
+	pub cf_token_file: Option<PathBuf>,
+}
+
+impl AccessOptions {
+	pub fn enabled(&self) -> bool {
+		self.cf || self.cf_client_id.is_some() || self.cf_token_file.is_some()
+	}
+
+	fn token(&self) -> Result<Option<String>, io::Error> {
+		let Some(path) = &self.cf_token_file else {
+			return Ok(None);
+		};
+		let token = match std::fs::read_to_string(path) {
+			Ok(token) => token.trim().to_string(),
+			// a service token can fill the file in later
+			Err(err) if err.kind() == io::ErrorKind::NotFound && self.cf_client_id.is_some() => {
+				return Ok(None)
+			}
+			Err(err) => {
+				return Err(io::Error::new(
+					err.kind(),
+					format!("{}: {}", path.display(), err),
+				))
+			}
+		};
+		if token.is_empty() {
+			return Ok(None);
+		}
+		if let Some(exp) = expiry(&token) {
+			let now = SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |x| x.as_secs());
+			if exp <= now {
+				warn!(
+					"Cloudflare Access token in {} expired {}s ago",
+					path.display(),
+					now - exp
+				);
+				if self.cf_client_id.is_some() {
+					return Ok(None);
+				}
+			}
+		}
+		Ok(Some(token))
+	}
+
+	/// Headers to add to the WebSocket upgrade.
+	pub fn headers(&self) -> Result<Vec<(&'static str, String)>, Box<dyn Error>> {
+		if self.cf && self.cf_client_id.is_none() && self.cf_token_file.is_none() {
+			return Err(Box::new(WhisperError::AccessNoCredentials));
+		}
+		let mut headers = Vec::new();
+		if let (Some(id), Some(secret)) = (&self.cf_client_id, &self.cf_client_secret) {
+			headers.push((CLIENT_ID_HEADER, id.clone()));
+			headers.push((CLIENT_SECRET_HEADER, secret.clone()));
+		}
+		if let Some(token) = self.token()? {
+			headers.push(("Cookie", format!("{}={}", TOKEN_COOKIE, token)));
+		}
+		Ok(headers)
+	}
+
+	/// Saves a `CF_Authorization` cookie from the upgrade response to the token file.
+	pub fn store(&self, headers: &HeaderMap) {
+		let Some(path) = &self.cf_token_file else {
+			return;
+		};
+		let Some(token) = headers
+			.get_all(SET_COOKIE)
+			.iter()
+			.filter_map(|x| x.to_str().ok())
+			.find_map(|x| {
+				let (name, value) = x.split(';').next()?.split_once('=')?;
+				(name.trim() == TOKEN_COOKIE).then(|| value.trim().to_string())
+			})
+		else {
+			return;
+		};
+		if std::fs::read_to_string(path).is_ok_and(|x| x.trim() == token) {
+			return;
+		}
+		match write_token(path, &token) {
+			Ok(()) => info!("Stored new Cloudflare Access token in {}", path.display()),
+			Err(err) => warn!(
+				"Failed to store Cloudflare Access token in {}: {}",
+				path.display(),
+				err
+			),
+		}
+	}
+}
+
+/// Writes a token file readable only by the current user, like a Noise private key.
+fn write_token(path: &Path, token: &str) -> Result<(), io::Error> {
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.mode(0o600)
+		.open(path)?;
+	// the mode only applies to a new file, an existing one may be readable by others
+	file.set_permissions(Permissions::from_mode(0o600))?;
+	file.write_all(token.as_bytes())
+}
+
+/// `exp` claim of a JWT, without checking the signature.
+fn expiry(token: &str) -> Option<u64> {
+	let claims = URL_SAFE_NO_PAD.decode(token.split('.').nth(1)?).ok()?;
+	let claims = String::from_utf8(claims).ok()?;
+	let exp = claims.split("\"exp\":").nth(1)?.trim_start();
+	let end = exp.find(|x: char| !x.is_ascii_digit()).unwrap_or(exp.len());
+	exp[..end].parse().ok()
+}
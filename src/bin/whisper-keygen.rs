@@ -0,0 +1,28 @@
+use std::{error::Error, path::PathBuf};
+
+use clap::Parser;
+use whisper::noise::{generate_private_key, load_private_key, public_key, write_private_key};
+
+/// Generates Noise static keys for whisper's --noise-key and prints their public key.
+#[derive(Debug, Parser)]
+#[command(version = clap::crate_version!())]
+struct Cli {
+	/// Private key file to create
+	key: PathBuf,
+	/// Print the public key of an existing private key instead of creating one
+	#[arg(long)]
+	show: bool,
+}
+
+fn main() -> Result<(), Box<dyn Error + 'static>> {
+	let opts = Cli::parse();
+	let private_key = if opts.show {
+		load_private_key(&opts.key)?
+	} else {
+		let private_key = generate_private_key()?;
+		write_private_key(&opts.key, &private_key)?;
+		private_key
+	};
+	println!("{}", public_key(&private_key)?);
+	Ok(())
+}
@@ -1,84 +1,106 @@
-#![feature(let_chains)]
-use std::{error::Error, net::TcpListener, process::abort};
+use std::{error::Error, time::Instant};
 
-use clap::Parser;
-use hyper::Uri;
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use log::{error, info, LevelFilter};
-use simplelog::{Config, SimpleLogger};
-use tokio::{net::lookup_host, process::Command, sync::mpsc::unbounded_channel};
+use simplelog::{Config, SimpleLogger, WriteLogger};
+use tokio::sync::mpsc::unbounded_channel;
 use tun2::{create_as_async, Configuration};
+#[cfg(not(target_os = "linux"))]
+use whisper::util::WhisperError;
+use whisper::{flow::FlowContext, pool::ServerPool, route::Router, start_whisper, Cli};
+#[cfg(target_os = "linux")]
 use whisper::{
-	start_whisper,
-	util::{connect_to_wisp, WhisperError},
-	Cli, WispServer,
+	gateway::Gateway,
+	netns::Namespace,
+	start_whisper_on,
+	transparent::{start_transparent, Firewall},
+	Mode,
 };
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), Box<dyn Error + 'static>> {
-	SimpleLogger::init(LevelFilter::Info, Config::default())?;
+fn main() -> Result<(), Box<dyn Error + 'static>> {
 	let opts = Cli::parse();
+	if opts.wisp.stdio {
+		// stdout carries the Wisp connection, keep logs off of it
+		WriteLogger::init(LevelFilter::Info, Config::default(), std::io::stderr())?;
+	} else {
+		SimpleLogger::init(LevelFilter::Info, Config::default())?;
+	}
+	let needs_tun = matches!(opts.mode, None | Some(Mode::Gateway(_)));
+	if needs_tun && opts.tun.is_none() {
+		Cli::command()
+			.error(ErrorKind::MissingRequiredArgument, "--tun is required")
+			.exit();
+	}
 
-	let (mux, socketaddr) = if let Some(ref url) = opts.wisp.url
-		&& opts.cf
-	{
-		let free_port = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
-		// this can fail but ehhh
-		let mut cloudflared_command = Command::new("cloudflared")
-			.arg("access")
-			.arg("tcp")
-			.arg("--hostname")
-			.arg(url.to_string())
-			.arg("--listener")
-			.arg(&free_port.to_string())
-			.kill_on_drop(true)
-			.spawn()?;
-		tokio::spawn(async move {
-			if let Err(err) = cloudflared_command.wait().await {
-				error!("error in cloudflared command: {:?}", err);
-			}
-			abort();
-		});
+	// the namespace is forked off while whisper is still single-threaded
+	#[cfg(target_os = "linux")]
+	let namespace = match &opts.mode {
+		Some(Mode::Exec(exec)) => Some(Namespace::spawn(&opts, exec)?),
+		Some(Mode::Transparent(_) | Mode::Gateway(_)) | None => None,
+	};
+	#[cfg(not(target_os = "linux"))]
+	if opts.mode.is_some() {
+		return Err(Box::new(WhisperError::LinuxOnly));
+	}
 
-		let tls = match url.scheme_str().ok_or(WhisperError::UriHasNoScheme)? {
-			"https" => Ok(true),
-			"http" => Ok(false),
-			_ => Err(Box::new(WhisperError::UriHasInvalidScheme)),
-		}?;
-		let host = url.host().ok_or(WhisperError::UriHasNoHost)?;
-		let port = url.port_u16().unwrap_or(if tls { 443 } else { 80 });
+	tokio::runtime::Builder::new_multi_thread()
+		.enable_all()
+		.build()?
+		.block_on(async move {
+			#[cfg(target_os = "linux")]
+			return run(opts, namespace).await;
+			#[cfg(not(target_os = "linux"))]
+			return run(opts).await;
+		})
+}
+
+async fn run(
+	opts: Cli,
+	#[cfg(target_os = "linux")] namespace: Option<Namespace>,
+) -> Result<(), Box<dyn Error + 'static>> {
+	let (pool, mut socketaddrs) = ServerPool::connect(&opts.wisp, opts.wisp_v2).await?;
+	let (router, outbound_socketaddrs) =
+		Router::connect(pool, &opts.wisp, &opts.route, opts.wisp_v2).await?;
+	socketaddrs.extend(outbound_socketaddrs);
+	let context = FlowContext::new(&opts.flow)?;
+	reload_on_hangup(&context)?;
+	log_client_stats(&context);
+
+	#[cfg(target_os = "linux")]
+	if let Some(mut namespace) = namespace {
+		let packets = namespace.packets(opts.mtu)?;
+		namespace.start()?;
+		let (_tx, rx) = unbounded_channel();
+		return tokio::select! {
+			ret = start_whisper_on(router, context, packets, opts.mtu, rx) => ret,
+			code = namespace.wait() => std::process::exit(code?),
+		};
+	}
 
-		let socketaddr = lookup_host(format!("{}:{}", host, port)).await?;
-		info!(
-			"IP addresses of Wisp server (whitelist these): {:?}",
-			socketaddr.collect::<Vec<_>>()
-		);
-		let mut local_url = Uri::builder().scheme("ws").authority(free_port.to_string());
-		if let Some(path_and_query) = url.path_and_query() {
-			local_url = local_url.path_and_query(path_and_query.clone());
+	#[cfg(target_os = "linux")]
+	if let Some(Mode::Transparent(transparent)) = &opts.mode {
+		for socketaddr in socketaddrs {
+			info!("IP address of Wisp server (whitelist this): {}", socketaddr);
 		}
-		(
-			connect_to_wisp(
-				&WispServer {
-					pty: None,
-					url: Some(local_url.build()?),
-				},
-				opts.wisp_v2,
-			)
-			.await?
-			.0,
-			None,
-		)
-	} else {
-		connect_to_wisp(&opts.wisp, opts.wisp_v2).await?
-	};
+		// dropped on the way out, which removes the rules again
+		let _firewall = transparent
+			.firewall
+			.then(|| Firewall::install(transparent))
+			.transpose()?;
+		return tokio::select! {
+			ret = start_transparent(router, context, transparent) => ret,
+			ret = terminated() => ret,
+		};
+	}
 
-	info!("Creating TUN device with name: {:?}", opts.tun);
+	let name = opts.tun.unwrap_or_default();
+	info!("Creating TUN device with name: {:?}", name);
 	let mut cfg = Configuration::default();
 	cfg.address(opts.ip)
 		.netmask(opts.mask)
 		.destination(opts.dest)
 		.mtu(opts.mtu)
-		.tun_name(opts.tun)
+		.tun_name(&name)
 		.up();
 	#[cfg(any(target_os = "linux", windows))]
 	cfg.platform_config(|c| {
@@ -89,10 +111,69 @@ async fn main() -> Result<(), Box<dyn Error + 'static>> {
 	});
 	let tun = create_as_async(&cfg)?;
 
-	if let Some(socketaddr) = socketaddr {
+	for socketaddr in socketaddrs {
 		info!("IP address of Wisp server (whitelist this): {}", socketaddr);
 	}
 
 	let (_tx, rx) = unbounded_channel();
-	start_whisper(mux, tun, opts.mtu, rx).await
+	#[cfg(target_os = "linux")]
+	if let Some(Mode::Gateway(gateway)) = &opts.mode {
+		// dropped on the way out, which restores the routing
+		let _gateway = Gateway::install(gateway, &name)?;
+		return tokio::select! {
+			ret = start_whisper(router, context, tun, opts.mtu, rx) => ret,
+			ret = terminated() => ret,
+		};
+	}
+	start_whisper(router, context, tun, opts.mtu, rx).await
+}
+
+/// Waits for Ctrl-C or SIGTERM.
+#[cfg(target_os = "linux")]
+async fn terminated() -> Result<(), Box<dyn Error + 'static>> {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut terminate = signal(SignalKind::terminate())?;
+	tokio::select! {
+		ret = tokio::signal::ctrl_c() => ret?,
+		_ = terminate.recv() => {}
+	}
+	info!("Shutting down");
+	Ok(())
+}
+
+/// Logs the statistics of the clients that were active since the last log.
+fn log_client_stats(context: &FlowContext) {
+	let Some(interval) = context.clients().interval() else {
+		return;
+	};
+	let context = context.clone();
+	tokio::spawn(async move {
+		let mut since = Instant::now();
+		loop {
+			tokio::time::sleep(interval).await;
+			context.clients().log_stats(since);
+			since = Instant::now();
+		}
+	});
+}
+
+/// Reloads the blocklists on SIGHUP.
+fn reload_on_hangup(context: &FlowContext) -> Result<(), Box<dyn Error + 'static>> {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+
+		let mut hangup = signal(SignalKind::hangup())?;
+		let context = context.clone();
+		tokio::spawn(async move {
+			while hangup.recv().await.is_some() {
+				info!("Reloading blocklists");
+				if let Err(err) = context.blocklist().reload() {
+					error!("Failed to reload blocklists: {}", err);
+				}
+			}
+		});
+	}
+	Ok(())
 }
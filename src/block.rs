@@ -0,0 +1,450 @@
+//! Domain blocklists.
+//!
+//! With `--blocklist`, DNS queries to port 53 are checked before they are forwarded, and queries
+//! for a blocked name are answered by whisper itself. Lists are read line by line, `#` starts a
+//! comment, and two formats can be mixed:
+//!
+//! ```text
+//! # hosts format: an address, then names that are blocked exactly
+//! 0.0.0.0 ads.example.com tracker.example.com
+//! # domain list: a name that is blocked with all of its subdomains
+//! example.net
+//! # only the subdomains of a name
+//! *.example.org
+//! ```
+//!
+//! Names matching `--allowlist`, which takes the same formats, are never blocked. Blocked queries
+//! get NXDOMAIN, or with `--block-response null` an answer of `0.0.0.0` or `::`. The lists are
+//! loaded again on SIGHUP, and the number of blocked queries per list entry is logged then.
+
+use std::{
+	collections::{HashMap, HashSet},
+	io,
+	net::IpAddr,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex, RwLock,
+	},
+};
+
+use clap::{Args, ValueEnum};
+use log::info;
+
+use crate::dns::{query, Question, CLASS_IN, TYPE_A, TYPE_AAAA};
+
+/// TTL of answers for blocked names.
+const BLOCK_TTL: u32 = 60;
+/// Entries listed when the hit counts are logged.
+const TOP_HITS: usize = 10;
+/// Names in hosts files that are not meant to be blocked.
+const HOSTS_NAMES: &[&str] = &[
+	"localhost",
+	"localhost.localdomain",
+	"local",
+	"broadcasthost",
+	"ip6-localhost",
+	"ip6-loopback",
+	"ip6-localnet",
+	"ip6-mcastprefix",
+	"ip6-allnodes",
+	"ip6-allrouters",
+	"ip6-allhosts",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BlockResponse {
+	/// Answer that the name doesn't exist
+	Nxdomain,
+	/// Answer A queries with 0.0.0.0 and AAAA queries with ::
+	Null,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct BlockOptions {
+	/// Hosts file or domain list of names to block. Can be given multiple times.
+	#[arg(long)]
+	pub blocklist: Vec<PathBuf>,
+	/// Hosts file or domain list of names to never block. Can be given multiple times.
+	#[arg(long)]
+	pub allowlist: Vec<PathBuf>,
+	/// Answer for queries of blocked names
+	#[arg(long, value_enum, default_value_t = BlockResponse::Nxdomain)]
+	pub block_response: BlockResponse,
+}
+
+impl Default for BlockOptions {
+	fn default() -> Self {
+		Self {
+			blocklist: Vec::new(),
+			allowlist: Vec::new(),
+			block_response: BlockResponse::Nxdomain,
+		}
+	}
+}
+
+/// A name and its parent domains, e.g. `a.b.c`, `b.c` and `c`.
+fn suffixes(name: &str) -> impl Iterator<Item = &str> {
+	std::iter::successors(Some(name), |x| x.split_once('.').map(|(_, rest)| rest))
+}
+
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[derive(Debug, Default)]
+struct List {
+	/// Names that match exactly, from hosts files.
+	exact: HashSet<String>,
+	/// Names that match along with their subdomains.
+	domains: HashSet<String>,
+	/// Names whose subdomains match, from `*.` entries.
+	subdomains: HashSet<String>,
+}
+
+impl List {
+	fn load(paths: &[PathBuf]) -> Result<Self, io::Error> {
+		let mut list = Self::default();
+		for path in paths {
+			let file = std::fs::read_to_string(path)
+				.map_err(|x| io::Error::new(x.kind(), format!("{}: {}", path.display(), x)))?;
+			for line in file.lines() {
+				list.add(line);
+			}
+		}
+		Ok(list)
+	}
+
+	fn add(&mut self, line: &str) {
+		let line = line.split('#').next().unwrap_or_default();
+		let mut words = line.split_whitespace();
+		let Some(first) = words.next() else {
+			return;
+		};
+		if first.parse::<IpAddr>().is_ok() {
+			for name in words.map(normalize) {
+				if !HOSTS_NAMES.contains(&name.as_str()) && name.parse::<IpAddr>().is_err() {
+					self.exact.insert(name);
+				}
+			}
+		} else if let Some(name) = first.strip_prefix("*.") {
+			self.subdomains.insert(normalize(name));
+		} else {
+			self.domains.insert(normalize(first));
+		}
+	}
+
+	fn len(&self) -> usize {
+		self.exact.len() + self.domains.len() + self.subdomains.len()
+	}
+
+	/// Entry that matches `name`.
+	fn find(&self, name: &str) -> Option<String> {
+		if self.exact.contains(name) {
+			return Some(name.to_string());
+		}
+		for (i, suffix) in suffixes(name).enumerate() {
+			if self.domains.contains(suffix) {
+				return Some(suffix.to_string());
+			}
+			if i > 0 && self.subdomains.contains(suffix) {
+				return Some(format!("*.{}", suffix));
+			}
+		}
+		None
+	}
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+	block: List,
+	allow: List,
+}
+
+impl Lists {
+	fn load(opts: &BlockOptions) -> Result<Self, io::Error> {
+		let lists = Self {
+			block: List::load(&opts.blocklist)?,
+			allow: List::load(&opts.allowlist)?,
+		};
+		if !opts.blocklist.is_empty() {
+			info!(
+				"Loaded blocklists: {} blocked and {} allowed names",
+				lists.block.len(),
+				lists.allow.len()
+			);
+		}
+		Ok(lists)
+	}
+}
+
+/// Blocklists with the number of queries each entry blocked.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+	opts: BlockOptions,
+	lists: RwLock<Lists>,
+	blocked: AtomicU64,
+	hits: Mutex<HashMap<String, u64>>,
+}
+
+impl Blocklist {
+	pub fn load(opts: &BlockOptions) -> Result<Self, io::Error> {
+		Ok(Self {
+			opts: opts.clone(),
+			lists: RwLock::new(Lists::load(opts)?),
+			blocked: AtomicU64::new(0),
+			hits: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/// Reads the lists again. The old lists stay in use if that fails.
+	pub fn reload(&self) -> Result<(), io::Error> {
+		self.log_hits();
+		let lists = Lists::load(&self.opts)?;
+		*self.lists.write().unwrap() = lists;
+		Ok(())
+	}
+
+	/// Answer for a DNS query if it asks for a blocked name.
+	pub fn check(&self, msg: &[u8]) -> Option<Vec<u8>> {
+		let lists = self.lists.read().unwrap();
+		if lists.block.len() == 0 {
+			return None;
+		}
+		let question = query(msg)?;
+		if lists.allow.find(&question.name).is_some() {
+			return None;
+		}
+		let entry = lists.block.find(&question.name)?;
+		drop(lists);
+
+		info!("blocked dns: {} ({})", question.name, entry);
+		self.blocked.fetch_add(1, Ordering::Relaxed);
+		*self.hits.lock().unwrap().entry(entry).or_default() += 1;
+		Some(self.answer(msg, &question))
+	}
+
+	/// Number of queries blocked so far.
+	pub fn blocked(&self) -> u64 {
+		self.blocked.load(Ordering::Relaxed)
+	}
+
+	/// Queries blocked by each list entry, most first.
+	pub fn hits(&self) -> Vec<(String, u64)> {
+		let mut hits: Vec<_> = self
+			.hits
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(entry, hits)| (entry.clone(), *hits))
+			.collect();
+		hits.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+		hits
+	}
+
+	fn log_hits(&self) {
+		let top = self
+			.hits()
+			.into_iter()
+			.take(TOP_HITS)
+			.map(|(entry, hits)| format!("{} ({})", entry, hits))
+			.collect::<Vec<_>>();
+		if top.is_empty() {
+			info!("Blocked {} queries", self.blocked());
+		} else {
+			info!(
+				"Blocked {} queries, most by: {}",
+				self.blocked(),
+				top.join(", ")
+			);
+		}
+	}
+
+	fn answer(&self, msg: &[u8], question: &Question) -> Vec<u8> {
+		let mut answer = msg[..question.end].to_vec();
+		// QR with RD copied from the query, then RA
+		answer[2] = 0x80 | (msg[2] & 0x01);
+		answer[3] = 0x80;
+		answer[6..12].fill(0);
+		let data: &[u8] = match (self.opts.block_response, question.kind) {
+			(BlockResponse::Null, TYPE_A) => &[0; 4],
+			(BlockResponse::Null, TYPE_AAAA) => &[0; 16],
+			// no records of other types
+			(BlockResponse::Null, _) => return answer,
+			(BlockResponse::Nxdomain, _) => {
+				answer[3] |= 3;
+				return answer;
+			}
+		};
+		answer[7] = 1;
+		// pointer to the name in the question
+		answer.extend_from_slice(&[0xc0, 0x0c]);
+		answer.extend_from_slice(&question.kind.to_be_bytes());
+		answer.extend_from_slice(&CLASS_IN.to_be_bytes());
+		answer.extend_from_slice(&BLOCK_TTL.to_be_bytes());
+		answer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+		answer.extend_from_slice(data);
+		answer
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dns::{addresses, build_query};
+
+	fn list(lines: &str) -> List {
+		let mut list = List::default();
+		for line in lines.lines() {
+			list.add(line);
+		}
+		list
+	}
+
+	fn lists(block: &str, allow: &str, block_response: BlockResponse) -> Blocklist {
+		let blocklist = Blocklist::load(&BlockOptions {
+			block_response,
+			..Default::default()
+		})
+		.unwrap();
+		*blocklist.lists.write().unwrap() = Lists {
+			block: list(block),
+			allow: list(allow),
+		};
+		blocklist
+	}
+
+	#[test]
+	fn parse() {
+		let list = list(
+			"# a comment
+			0.0.0.0 Ads.Example.com. tracker.example.com # trailing comment
+			127.0.0.1 localhost
+			::1 ip6-localhost ip6-loopback
+			0.0.0.0 0.0.0.0
+			example.net
+			*.example.org
+
+			   # indented comment",
+		);
+		assert_eq!(
+			list.exact,
+			HashSet::from(["ads.example.com".into(), "tracker.example.com".into()])
+		);
+		assert_eq!(list.domains, HashSet::from(["example.net".into()]));
+		assert_eq!(list.subdomains, HashSet::from(["example.org".into()]));
+		assert_eq!(list.len(), 4);
+	}
+
+	#[test]
+	fn find() {
+		let list = list(
+			"0.0.0.0 ads.example.com
+			example.net
+			*.example.org",
+		);
+		assert_eq!(
+			list.find("ads.example.com").as_deref(),
+			Some("ads.example.com")
+		);
+		assert_eq!(list.find("x.ads.example.com"), None);
+		assert_eq!(list.find("example.com"), None);
+		assert_eq!(list.find("example.net").as_deref(), Some("example.net"));
+		assert_eq!(list.find("a.b.example.net").as_deref(), Some("example.net"));
+		assert_eq!(list.find("notexample.net"), None);
+		assert_eq!(list.find("example.org"), None);
+		assert_eq!(list.find("a.example.org").as_deref(), Some("*.example.org"));
+	}
+
+	#[test]
+	fn check() {
+		let blocklist = lists("example.com", "ok.example.com", BlockResponse::Nxdomain);
+		assert_eq!(
+			blocklist.check(&build_query(1, "ok.example.com", TYPE_A)),
+			None
+		);
+		assert_eq!(
+			blocklist.check(&build_query(1, "a.ok.example.com", TYPE_A)),
+			None
+		);
+		assert_eq!(
+			blocklist.check(&build_query(1, "example.org", TYPE_A)),
+			None
+		);
+
+		let query = build_query(0x1234, "ads.example.com", TYPE_A);
+		let answer = blocklist.check(&query).unwrap();
+		assert_eq!(answer[..2], [0x12, 0x34]);
+		// QR, RD, RA and NXDOMAIN
+		assert_eq!(answer[2..4], [0x81, 0x83]);
+		assert_eq!(answer[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+		assert_eq!(answer[12..], query[12..]);
+		assert_eq!(addresses(&answer), None);
+
+		// answers aren't checked
+		assert_eq!(blocklist.check(&answer), None);
+		blocklist.check(&build_query(2, "example.com", TYPE_AAAA));
+		blocklist.check(&build_query(3, "example.com", TYPE_A));
+		assert_eq!(blocklist.blocked(), 3);
+		assert_eq!(blocklist.hits(), vec![("example.com".into(), 3)]);
+
+		// nothing is blocked without a blocklist, even with an allowlist
+		let empty = lists("", "example.com", BlockResponse::Nxdomain);
+		assert_eq!(empty.check(&query), None);
+	}
+
+	#[test]
+	fn null_response() {
+		let blocklist = lists("example.com", "", BlockResponse::Null);
+		let answer = blocklist
+			.check(&build_query(1, "example.com", TYPE_A))
+			.unwrap();
+		assert_eq!(
+			addresses(&answer),
+			Some((
+				"example.com".into(),
+				vec![(IpAddr::from([0, 0, 0, 0]), BLOCK_TTL)]
+			))
+		);
+		let answer = blocklist
+			.check(&build_query(1, "example.com", TYPE_AAAA))
+			.unwrap();
+		assert_eq!(
+			addresses(&answer),
+			Some((
+				"example.com".into(),
+				vec![(IpAddr::from([0u16; 8]), BLOCK_TTL)]
+			))
+		);
+		// other types get an empty answer
+		let answer = blocklist.check(&build_query(1, "example.com", 16)).unwrap();
+		assert_eq!(answer[3], 0x80);
+		assert_eq!(addresses(&answer), Some(("example.com".into(), Vec::new())));
+	}
+
+	#[test]
+	fn reload() {
+		let path = std::env::temp_dir().join(format!("whisper-blocklist-{}", std::process::id()));
+		std::fs::write(&path, "example.com\n").unwrap();
+		let blocklist = Blocklist::load(&BlockOptions {
+			blocklist: vec![path.clone()],
+			..Default::default()
+		})
+		.unwrap();
+		let query = build_query(1, "example.com", TYPE_A);
+		assert!(blocklist.check(&query).is_some());
+
+		std::fs::write(&path, "example.org\n").unwrap();
+		blocklist.reload().unwrap();
+		assert_eq!(blocklist.check(&query), None);
+
+		// the old lists stay when the file is gone
+		std::fs::remove_file(&path).unwrap();
+		let err = blocklist.reload().unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::NotFound);
+		assert!(err.to_string().starts_with(&path.display().to_string()));
+		assert!(blocklist
+			.check(&build_query(1, "example.org", TYPE_A))
+			.is_some());
+	}
+}
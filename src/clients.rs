@@ -0,0 +1,249 @@
+//! Statistics and access control per client.
+//!
+//! Every flow whisper forwards is counted against its source address, which is the device it came
+//! from when whisper is the gateway of a LAN. Bytes of TCP flows are added when the flow closes,
+//! UDP is counted per datagram. The clients that were active are logged every
+//! `--client-stats-interval` seconds.
+//!
+//! With `--allow-client`, only sources in one of the given networks may use the tunnel. Flows from
+//! other clients are refused: TCP connections are closed and UDP gets port unreachable.
+
+use std::{
+	collections::HashMap,
+	net::IpAddr,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::info;
+
+use crate::route::Cidr;
+
+#[derive(Debug, Clone, Args)]
+pub struct ClientOptions {
+	/// Network of clients that may use the tunnel (e.g. 192.168.1.0/24). Can be given multiple
+	/// times. Every client may use it if this isn't given.
+	#[arg(long)]
+	pub allow_client: Vec<Cidr>,
+	/// Seconds between logs of the statistics of each client, 0 to disable
+	#[arg(long, default_value_t = 300)]
+	pub client_stats_interval: u64,
+}
+
+impl Default for ClientOptions {
+	fn default() -> Self {
+		Self {
+			allow_client: Vec::new(),
+			client_stats_interval: 300,
+		}
+	}
+}
+
+/// Traffic of one client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientStats {
+	pub tcp_flows: u64,
+	pub udp_flows: u64,
+	/// Flows refused because the client isn't allowed.
+	pub refused: u64,
+	pub sent: u64,
+	pub received: u64,
+	pub last_seen: Instant,
+}
+
+impl ClientStats {
+	fn new() -> Self {
+		Self {
+			tcp_flows: 0,
+			udp_flows: 0,
+			refused: 0,
+			sent: 0,
+			received: 0,
+			last_seen: Instant::now(),
+		}
+	}
+}
+
+/// Clients that may use the tunnel and what they sent through it.
+#[derive(Debug)]
+pub struct Clients {
+	allow: Vec<Cidr>,
+	interval: Duration,
+	stats: Mutex<HashMap<IpAddr, ClientStats>>,
+}
+
+impl Default for Clients {
+	fn default() -> Self {
+		Self::new(&ClientOptions::default())
+	}
+}
+
+impl Clients {
+	pub fn new(opts: &ClientOptions) -> Self {
+		Self {
+			allow: opts.allow_client.clone(),
+			interval: Duration::from_secs(opts.client_stats_interval),
+			stats: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn update(&self, client: IpAddr, f: impl FnOnce(&mut ClientStats)) {
+		let mut stats = self.stats.lock().unwrap();
+		let stats = stats
+			.entry(client.to_canonical())
+			.or_insert_with(ClientStats::new);
+		stats.last_seen = Instant::now();
+		f(stats);
+	}
+
+	/// Whether `client` may open a flow, counting the flow as refused if not.
+	pub fn allowed(&self, client: IpAddr) -> bool {
+		let client = client.to_canonical();
+		if self.allow.is_empty() || self.allow.iter().any(|x| x.contains(&client)) {
+			return true;
+		}
+		self.update(client, |x| x.refused += 1);
+		false
+	}
+
+	pub fn tcp_flow(&self, client: IpAddr) {
+		self.update(client, |x| x.tcp_flows += 1);
+	}
+
+	pub fn udp_flow(&self, client: IpAddr) {
+		self.update(client, |x| x.udp_flows += 1);
+	}
+
+	pub fn sent(&self, client: IpAddr, bytes: u64) {
+		self.update(client, |x| x.sent += bytes);
+	}
+
+	pub fn received(&self, client: IpAddr, bytes: u64) {
+		self.update(client, |x| x.received += bytes);
+	}
+
+	/// Statistics of every client seen so far, most traffic first.
+	pub fn stats(&self) -> Vec<(IpAddr, ClientStats)> {
+		let mut stats: Vec<_> = self
+			.stats
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(client, stats)| (*client, *stats))
+			.collect();
+		stats.sort_by(|a, b| {
+			(b.1.sent + b.1.received)
+				.cmp(&(a.1.sent + a.1.received))
+				.then_with(|| a.0.cmp(&b.0))
+		});
+		stats
+	}
+
+	/// Time between logs of [`Clients::stats`], `None` if disabled.
+	pub fn interval(&self) -> Option<Duration> {
+		(!self.interval.is_zero()).then_some(self.interval)
+	}
+
+	/// Logs the statistics of the clients seen after `since`.
+	pub fn log_stats(&self, since: Instant) {
+		for (client, stats) in self.stats() {
+			if stats.last_seen < since {
+				continue;
+			}
+			info!(
+				"client {}: {} tcp and {} udp flows, {} refused, sent {} bytes, received {} bytes, last seen {}s ago",
+				client,
+				stats.tcp_flows,
+				stats.udp_flows,
+				stats.refused,
+				stats.sent,
+				stats.received,
+				stats.last_seen.elapsed().as_secs()
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn clients(allow: &[&str]) -> Clients {
+		Clients::new(&ClientOptions {
+			allow_client: allow.iter().map(|x| x.parse().unwrap()).collect(),
+			client_stats_interval: 0,
+		})
+	}
+
+	#[test]
+	fn allow_everyone() {
+		let clients = clients(&[]);
+		assert!(clients.allowed("10.0.0.1".parse().unwrap()));
+		assert!(clients.allowed("2001:db8::1".parse().unwrap()));
+		assert!(clients.stats().is_empty());
+		assert_eq!(clients.interval(), None);
+	}
+
+	#[test]
+	fn refused() {
+		let clients = clients(&["192.168.1.0/24"]);
+		assert!(clients.allowed("192.168.1.5".parse().unwrap()));
+		assert!(!clients.allowed("10.0.0.1".parse().unwrap()));
+		assert!(!clients.allowed("10.0.0.1".parse().unwrap()));
+
+		let stats = clients.stats();
+		assert_eq!(stats.len(), 1);
+		assert_eq!(stats[0].0, "10.0.0.1".parse::<IpAddr>().unwrap());
+		assert_eq!(stats[0].1.refused, 2);
+		assert_eq!(stats[0].1.tcp_flows, 0);
+	}
+
+	#[test]
+	fn v4_mapped() {
+		let clients = clients(&["192.168.1.0/24"]);
+		let mapped: IpAddr = "::ffff:192.168.1.5".parse().unwrap();
+		assert!(clients.allowed(mapped));
+		assert!(!clients.allowed("::ffff:10.0.0.1".parse().unwrap()));
+		clients.tcp_flow(mapped);
+		clients.tcp_flow("192.168.1.5".parse().unwrap());
+
+		let stats = clients.stats();
+		assert_eq!(stats.len(), 2);
+		let v4 = stats
+			.iter()
+			.find(|x| x.0 == "192.168.1.5".parse::<IpAddr>().unwrap())
+			.unwrap();
+		assert_eq!(v4.1.tcp_flows, 2);
+		let refused = stats
+			.iter()
+			.find(|x| x.0 == "10.0.0.1".parse::<IpAddr>().unwrap())
+			.unwrap();
+		assert_eq!(refused.1.refused, 1);
+	}
+
+	#[test]
+	fn stats_order() {
+		let clients = clients(&[]);
+		clients.sent("10.0.0.3".parse().unwrap(), 10);
+		clients.sent("10.0.0.2".parse().unwrap(), 100);
+		clients.received("10.0.0.2".parse().unwrap(), 50);
+		clients.received("10.0.0.1".parse().unwrap(), 10);
+		clients.udp_flow("10.0.0.4".parse().unwrap());
+
+		let order: Vec<_> = clients
+			.stats()
+			.into_iter()
+			.map(|(client, stats)| (client.to_string(), stats.sent + stats.received))
+			.collect();
+		assert_eq!(
+			order,
+			[
+				("10.0.0.2".to_string(), 150),
+				("10.0.0.1".to_string(), 10),
+				("10.0.0.3".to_string(), 10),
+				("10.0.0.4".to_string(), 0),
+			]
+		);
+	}
+}
@@ -0,0 +1,509 @@
+use std::{io, sync::Arc};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use clap::ValueEnum;
+use log::warn;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec, LengthDelimitedCodecError};
+
+use crate::{
+	compress::{Compressor, Decompressor, COMPRESSION_OVERHEAD},
+	noise::NoiseCipher,
+};
+
+/// Marker that starts every frame in [`Framing::Sync`] mode.
+const SYNC_MARKER: [u8; 4] = [0xF0, 0x9F, 0x97, 0xA3];
+/// Offset of the header check after the marker and length.
+const SYNC_CHECK_START: usize = SYNC_MARKER.len() + 4;
+/// Marker + length + header check.
+const SYNC_HEADER_LEN: usize = SYNC_CHECK_START + 2;
+const SYNC_CRC_LEN: usize = 4;
+
+/// Packet type (u8) and stream id (u32) that precede the payload of every Wisp packet.
+pub const WISP_HEADER_LEN: usize = 5;
+/// Largest Wisp packet payload accepted by default. wisp-mux never sends more than a UDP datagram
+/// or a TCP read buffer in one packet, so this leaves plenty of headroom while keeping a corrupt
+/// length prefix from allocating gigabytes.
+pub const DEFAULT_MAX_PAYLOAD_LENGTH: usize = 1 << 20;
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = WISP_HEADER_LEN + DEFAULT_MAX_PAYLOAD_LENGTH;
+
+/// Whether `frame` looks like a Wisp packet: a full header and a packet type known to the client.
+pub fn is_wisp_packet(frame: &[u8]) -> bool {
+	// CONNECT, DATA, CONTINUE, CLOSE and INFO
+	frame.len() >= WISP_HEADER_LEN && (0x01..=0x05).contains(&frame[0])
+}
+
+fn frame_too_large(len: usize, max_frame_length: usize) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidData,
+		format!(
+			"frame of {} bytes exceeds the maximum frame length of {} bytes",
+			len, max_frame_length
+		),
+	)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Framing {
+	/// Little endian u32 length prefix
+	#[default]
+	Length,
+	/// Sync marker, little endian u32 length, header check, payload and CRC32. Corrupt frames are
+	/// dropped and the stream resynchronizes on the next marker.
+	Sync,
+}
+
+/// Codec that carries Wisp frames over byte streams.
+///
+/// The maximum frame length applies to the Wisp packets. Frames on the wire may be larger by the
+/// overhead of compression and encryption.
+pub struct FrameCodec {
+	framer: Framer,
+	max_frame_length: usize,
+	cipher: Option<Arc<NoiseCipher>>,
+	compressor: Option<Compressor>,
+	decompressor: Option<Decompressor>,
+}
+
+enum Framer {
+	Length(LengthDelimitedCodec, usize),
+	Sync(SyncCodec),
+}
+
+impl FrameCodec {
+	pub fn new(framing: Framing, max_frame_length: usize) -> Self {
+		let framer = match framing {
+			Framing::Length => Framer::Length(
+				LengthDelimitedCodec::builder()
+					.little_endian()
+					.max_frame_length(max_frame_length)
+					.new_codec(),
+				max_frame_length,
+			),
+			Framing::Sync => Framer::Sync(SyncCodec::new(max_frame_length)),
+		};
+		Self {
+			framer,
+			max_frame_length,
+			cipher: None,
+			compressor: None,
+			decompressor: None,
+		}
+	}
+
+	/// Encrypt every frame from now on. Called once the Noise handshake is done.
+	pub fn set_cipher(&mut self, cipher: Arc<NoiseCipher>) {
+		self.cipher = Some(cipher);
+		self.update_wire_length();
+	}
+
+	/// Compress every frame written from now on. Called once compression is negotiated.
+	pub fn set_compressor(&mut self, compressor: Compressor) {
+		self.compressor = Some(compressor);
+		self.update_wire_length();
+	}
+
+	/// Decompress every frame read from now on. Called once compression is negotiated.
+	pub fn set_decompressor(&mut self, decompressor: Decompressor) {
+		self.decompressor = Some(decompressor);
+		self.update_wire_length();
+	}
+
+	/// Lets the framer carry a frame of the maximum length after compression and encryption.
+	fn update_wire_length(&mut self) {
+		let mut len = self.max_frame_length;
+		if self.compressor.is_some() || self.decompressor.is_some() {
+			len += COMPRESSION_OVERHEAD;
+		}
+		if self.cipher.is_some() {
+			len = NoiseCipher::encrypted_len(len);
+		}
+		self.framer.set_max_frame_length(len);
+	}
+}
+
+impl Decoder for FrameCodec {
+	type Item = BytesMut;
+	type Error = io::Error;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		let mut frame = loop {
+			let Some(frame) = self.framer.decode(src)? else {
+				return Ok(None);
+			};
+			let Some(cipher) = &self.cipher else {
+				break frame;
+			};
+			match (cipher.decrypt(&frame), &mut self.framer) {
+				(Ok(frame), _) => break frame,
+				// a forged or damaged frame on a noisy line only costs itself
+				(Err(err), Framer::Sync(codec)) => codec.drop_frame(&err.to_string()),
+				(Err(err), Framer::Length(..)) => return Err(err),
+			}
+		};
+		if let Some(decompressor) = &mut self.decompressor {
+			frame = decompressor.decompress(&frame)?;
+		}
+		Ok(Some(frame))
+	}
+}
+
+impl Encoder<Bytes> for FrameCodec {
+	type Error = io::Error;
+
+	fn encode(&mut self, mut item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+		if item.len() > self.max_frame_length {
+			return Err(frame_too_large(item.len(), self.max_frame_length));
+		}
+		if let Some(compressor) = &mut self.compressor {
+			let mut frame = BytesMut::new();
+			compressor.compress(&item, &mut frame)?;
+			item = frame.freeze();
+		}
+		if let Some(cipher) = &self.cipher {
+			let mut frame = BytesMut::new();
+			cipher.encrypt(&item, &mut frame)?;
+			item = frame.freeze();
+		}
+		self.framer.encode(item, dst)
+	}
+}
+
+impl Framer {
+	fn set_max_frame_length(&mut self, len: usize) {
+		match self {
+			Self::Length(codec, max_frame_length) => {
+				codec.set_max_frame_length(len);
+				*max_frame_length = len;
+			}
+			Self::Sync(codec) => codec.max_frame_length = len,
+		}
+	}
+}
+
+impl Decoder for Framer {
+	type Item = BytesMut;
+	type Error = io::Error;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		match self {
+			Self::Length(codec, max_frame_length) => {
+				let len = src
+					.get(..4)
+					.map(|x| u32::from_le_bytes(x.try_into().unwrap()) as usize);
+				codec.decode(src).map_err(|err| match (len, err.get_ref()) {
+					(Some(len), Some(inner)) if inner.is::<LengthDelimitedCodecError>() => {
+						frame_too_large(len, *max_frame_length)
+					}
+					_ => err,
+				})
+			}
+			Self::Sync(codec) => codec.decode(src),
+		}
+	}
+}
+
+impl Encoder<Bytes> for Framer {
+	type Error = io::Error;
+
+	fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+		match self {
+			Self::Length(codec, _) => codec.encode(item, dst),
+			Self::Sync(codec) => codec.encode(item, dst),
+		}
+	}
+}
+
+/// Framing for noisy serial lines.
+///
+/// Each frame is `marker | len: u32 | check: u16 | payload | crc32(len | check | payload)`, where
+/// `check` is the low half of the CRC32 of `len`, so a corrupt length is caught before the decoder
+/// waits for the payload. On a bad header or CRC the decoder skips one byte and searches for the
+/// next marker instead of failing the stream.
+pub struct SyncCodec {
+	max_frame_length: usize,
+	dropped: u64,
+}
+
+/// Check of the length field of a sync frame.
+fn header_check(len: &[u8]) -> [u8; 2] {
+	(crc32fast::hash(len) as u16).to_le_bytes()
+}
+
+impl SyncCodec {
+	pub fn new(max_frame_length: usize) -> Self {
+		Self {
+			max_frame_length,
+			dropped: 0,
+		}
+	}
+
+	fn resync(&mut self, src: &mut BytesMut, reason: &str) {
+		self.dropped += 1;
+		warn!(
+			"Dropping corrupt frame ({}), resynchronizing. {} frames dropped so far.",
+			reason, self.dropped
+		);
+		src.advance(1);
+	}
+
+	/// Drops a frame that was framed correctly but can't be used.
+	fn drop_frame(&mut self, reason: &str) {
+		self.dropped += 1;
+		warn!(
+			"Dropping frame ({}). {} frames dropped so far.",
+			reason, self.dropped
+		);
+	}
+}
+
+impl Decoder for SyncCodec {
+	type Item = BytesMut;
+	type Error = io::Error;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+		loop {
+			match src
+				.windows(SYNC_MARKER.len())
+				.position(|x| x == SYNC_MARKER)
+			{
+				Some(pos) => src.advance(pos),
+				None => {
+					// keep a possible partial marker at the end
+					let keep = src.len().min(SYNC_MARKER.len() - 1);
+					src.advance(src.len() - keep);
+					return Ok(None);
+				}
+			}
+
+			if src.len() < SYNC_HEADER_LEN {
+				return Ok(None);
+			}
+			let len_bytes = &src[SYNC_MARKER.len()..SYNC_CHECK_START];
+			if header_check(len_bytes) != src[SYNC_CHECK_START..SYNC_HEADER_LEN] {
+				self.resync(src, "header check mismatch");
+				continue;
+			}
+			let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+			if len > self.max_frame_length {
+				self.resync(src, "length too large");
+				continue;
+			}
+			if src.len() < SYNC_HEADER_LEN + len + SYNC_CRC_LEN {
+				src.reserve(SYNC_HEADER_LEN + len + SYNC_CRC_LEN - src.len());
+				return Ok(None);
+			}
+
+			let crc = u32::from_le_bytes(
+				src[SYNC_HEADER_LEN + len..SYNC_HEADER_LEN + len + SYNC_CRC_LEN]
+					.try_into()
+					.unwrap(),
+			);
+			if crc32fast::hash(&src[SYNC_MARKER.len()..SYNC_HEADER_LEN + len]) != crc {
+				self.resync(src, "CRC mismatch");
+				continue;
+			}
+
+			src.advance(SYNC_HEADER_LEN);
+			let frame = src.split_to(len);
+			src.advance(SYNC_CRC_LEN);
+			return Ok(Some(frame));
+		}
+	}
+}
+
+impl Encoder<Bytes> for SyncCodec {
+	type Error = io::Error;
+
+	fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+		if item.len() > self.max_frame_length {
+			return Err(frame_too_large(item.len(), self.max_frame_length));
+		}
+		let len = u32::try_from(item.len())
+			.map_err(|_| frame_too_large(item.len(), self.max_frame_length))?;
+
+		dst.reserve(SYNC_HEADER_LEN + item.len() + SYNC_CRC_LEN);
+		dst.put_slice(&SYNC_MARKER);
+		let start = dst.len();
+		dst.put_u32_le(len);
+		dst.put_slice(&header_check(&len.to_le_bytes()));
+		dst.put_slice(&item);
+		let crc = crc32fast::hash(&dst[start..]);
+		dst.put_u32_le(crc);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::RngCore;
+
+	use super::*;
+	use crate::compress::{Compression, CompressionOptions, CompressionStats};
+
+	fn encode(codec: &mut SyncCodec, frames: &[&[u8]]) -> BytesMut {
+		let mut buf = BytesMut::new();
+		for frame in frames {
+			codec
+				.encode(Bytes::copy_from_slice(frame), &mut buf)
+				.unwrap();
+		}
+		buf
+	}
+
+	fn decode_all(codec: &mut SyncCodec, buf: &mut BytesMut) -> Vec<BytesMut> {
+		let mut frames = Vec::new();
+		while let Some(frame) = codec.decode(buf).unwrap() {
+			frames.push(frame);
+		}
+		frames
+	}
+
+	#[test]
+	fn sync_round_trip() {
+		let mut codec = SyncCodec::new(1024);
+		let mut buf = encode(&mut codec, &[b"hello", b"", b"world"]);
+		assert_eq!(
+			decode_all(&mut codec, &mut buf),
+			[&b"hello"[..], b"", b"world"]
+		);
+		assert!(buf.is_empty());
+	}
+
+	#[test]
+	fn sync_partial_frame() {
+		let mut codec = SyncCodec::new(1024);
+		let full = encode(&mut codec, &[b"hello"]);
+		let mut buf = BytesMut::from(&full[..full.len() - 1]);
+		assert_eq!(codec.decode(&mut buf).unwrap(), None);
+		buf.put_u8(full[full.len() - 1]);
+		assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"hello"[..]);
+	}
+
+	#[test]
+	fn sync_skips_garbage() {
+		let mut codec = SyncCodec::new(1024);
+		let mut buf = BytesMut::from(&b"\x00garbage\xF0\x9F"[..]);
+		buf.extend_from_slice(&encode(&mut codec, &[b"hello"]));
+		assert_eq!(decode_all(&mut codec, &mut buf), [&b"hello"[..]]);
+		assert_eq!(codec.dropped, 0);
+	}
+
+	#[test]
+	fn sync_drops_corrupt_payload() {
+		let mut codec = SyncCodec::new(1024);
+		let mut buf = encode(&mut codec, &[b"hello", b"world"]);
+		buf[SYNC_HEADER_LEN] ^= 1;
+		assert_eq!(decode_all(&mut codec, &mut buf), [&b"world"[..]]);
+		assert_eq!(codec.dropped, 1);
+	}
+
+	#[test]
+	fn sync_rejects_corrupt_length_right_away() {
+		let mut codec = SyncCodec::new(1 << 20);
+		let mut buf = encode(&mut codec, &[b"hello", b"world"]);
+		// a length that is still below the maximum
+		buf[SYNC_MARKER.len() + 2] = 0x01;
+		assert_eq!(decode_all(&mut codec, &mut buf), [&b"world"[..]]);
+		assert_eq!(codec.dropped, 1);
+	}
+
+	#[test]
+	fn sync_frame_too_large() {
+		let mut codec = SyncCodec::new(4);
+		assert!(codec
+			.encode(Bytes::from_static(b"hello"), &mut BytesMut::new())
+			.is_err());
+	}
+
+	const MAX: usize = 200_000;
+
+	/// A sending and a receiving codec with the given layers.
+	fn codec_pair(framing: Framing, compress: bool, encrypt: bool) -> (FrameCodec, FrameCodec) {
+		let mut tx = FrameCodec::new(framing, MAX);
+		let mut rx = FrameCodec::new(framing, MAX);
+		if compress {
+			let opts = CompressionOptions {
+				compression: Compression::Zstd,
+				..Default::default()
+			};
+			let stats = Arc::new(CompressionStats::default());
+			tx.set_compressor(Compressor::new(&opts, &[], MAX, stats.clone()).unwrap());
+			rx.set_decompressor(Decompressor::new(&opts, &[], MAX, stats).unwrap());
+		}
+		if encrypt {
+			let (a, b) = NoiseCipher::pair();
+			tx.set_cipher(Arc::new(a));
+			rx.set_cipher(Arc::new(b));
+		}
+		(tx, rx)
+	}
+
+	#[test]
+	fn frame_round_trip_at_limit() {
+		// incompressible, so compression has to store it with its flag
+		let mut frame = vec![0; MAX];
+		rand::thread_rng().fill_bytes(&mut frame);
+		for framing in [Framing::Length, Framing::Sync] {
+			for (compress, encrypt) in [(false, false), (true, false), (false, true), (true, true)]
+			{
+				let (mut tx, mut rx) = codec_pair(framing, compress, encrypt);
+				let mut buf = BytesMut::new();
+				tx.encode(Bytes::copy_from_slice(&frame), &mut buf)
+					.unwrap_or_else(|err| {
+						panic!("{:?} {} {}: {}", framing, compress, encrypt, err)
+					});
+				let decoded = rx.decode(&mut buf).unwrap().unwrap();
+				assert_eq!(
+					decoded,
+					&frame[..],
+					"{:?} {} {}",
+					framing,
+					compress,
+					encrypt
+				);
+				assert!(buf.is_empty());
+
+				assert!(tx
+					.encode(Bytes::from(vec![0; MAX + 1]), &mut BytesMut::new())
+					.is_err());
+			}
+		}
+	}
+
+	#[test]
+	fn frame_too_large_on_the_wire() {
+		let (_, mut rx) = codec_pair(Framing::Length, false, true);
+		let mut buf = BytesMut::new();
+		buf.put_u32_le((NoiseCipher::encrypted_len(MAX) + 1) as u32);
+		buf.put_bytes(0, 16);
+		assert!(rx.decode(&mut buf).is_err());
+	}
+
+	#[test]
+	fn sync_drops_frame_that_does_not_authenticate() {
+		let (mut tx, mut rx) = codec_pair(Framing::Sync, false, true);
+		let mut buf = BytesMut::new();
+		tx.encode(Bytes::from_static(b"hello"), &mut buf).unwrap();
+		// a valid sync frame that isn't from the peer
+		SyncCodec::new(MAX)
+			.encode(Bytes::from(vec![1; 64]), &mut buf)
+			.unwrap();
+		tx.encode(Bytes::from_static(b"world"), &mut buf).unwrap();
+
+		assert_eq!(rx.decode(&mut buf).unwrap().unwrap(), &b"hello"[..]);
+		assert_eq!(rx.decode(&mut buf).unwrap().unwrap(), &b"world"[..]);
+		let Framer::Sync(codec) = &rx.framer else {
+			unreachable!()
+		};
+		assert_eq!(codec.dropped, 1);
+	}
+
+	#[test]
+	fn length_fails_on_frame_that_does_not_authenticate() {
+		let (_, mut rx) = codec_pair(Framing::Length, false, true);
+		let mut buf = BytesMut::new();
+		buf.put_u32_le(64);
+		buf.put_bytes(1, 64);
+		assert!(rx.decode(&mut buf).is_err());
+	}
+}
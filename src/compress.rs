@@ -0,0 +1,554 @@
+//! Per-frame compression for slow length-delimited transports.
+//!
+//! Both ends send a `WCMP | algorithm: u8 | dictionary: u32` frame before the Wisp mux starts and
+//! refuse to continue unless they match. After that every frame is `flag: u8 | data`, where the
+//! flag says whether the data is compressed or stored as is because compressing didn't help.
+//! Frames are compressed independently so frames dropped by
+//! [`Framing::Sync`](crate::codec::Framing::Sync) don't affect the ones after them.
+
+use std::{
+	fmt::Display,
+	io,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Weak,
+	},
+	time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use clap::{Args, ValueEnum};
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+use futures_util::{SinkExt, StreamExt};
+use log::info;
+use tokio::time::{interval, timeout};
+
+use crate::pty::{FrameRead, FrameWrite};
+
+const HANDSHAKE_MAGIC: &[u8; 4] = b"WCMP";
+const HANDSHAKE_LEN: usize = HANDSHAKE_MAGIC.len() + 5;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+const FLAG_STORED: u8 = 0;
+const FLAG_COMPRESSED: u8 = 1;
+/// Bytes compression adds to a frame at most: the flag in front of stored frames.
+pub const COMPRESSION_OVERHEAD: usize = 1;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+	#[default]
+	None,
+	/// Zstandard, optionally with a shared dictionary
+	Zstd,
+	/// Raw deflate
+	Deflate,
+}
+
+impl Compression {
+	fn id(self) -> u8 {
+		match self {
+			Self::None => 0,
+			Self::Zstd => 1,
+			Self::Deflate => 2,
+		}
+	}
+
+	fn from_id(id: u8) -> Option<Self> {
+		match id {
+			0 => Some(Self::None),
+			1 => Some(Self::Zstd),
+			2 => Some(Self::Deflate),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct CompressionOptions {
+	/// Compress frames on length-delimited transports. The other end must use the same settings.
+	#[arg(long, value_enum, default_value_t = Compression::None)]
+	pub compression: Compression,
+	/// Compression level (zstd: 1-22, deflate: 0-9)
+	#[arg(long)]
+	pub compression_level: Option<i32>,
+	/// Shared zstd dictionary, e.g. trained with `zstd --train` on captured Wisp frames
+	#[arg(long)]
+	pub compression_dict: Option<PathBuf>,
+}
+
+impl CompressionOptions {
+	pub fn enabled(&self) -> bool {
+		self.compression != Compression::None
+	}
+}
+
+/// Bytes before and after compression, per direction.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+	sent_raw: AtomicU64,
+	sent_compressed: AtomicU64,
+	received_raw: AtomicU64,
+	received_compressed: AtomicU64,
+}
+
+fn ratio(compressed: u64, raw: u64) -> f64 {
+	if raw == 0 {
+		100.0
+	} else {
+		compressed as f64 / raw as f64 * 100.0
+	}
+}
+
+impl CompressionStats {
+	pub fn sent(&self) -> (u64, u64) {
+		(
+			self.sent_raw.load(Ordering::Relaxed),
+			self.sent_compressed.load(Ordering::Relaxed),
+		)
+	}
+
+	pub fn received(&self) -> (u64, u64) {
+		(
+			self.received_raw.load(Ordering::Relaxed),
+			self.received_compressed.load(Ordering::Relaxed),
+		)
+	}
+}
+
+impl Display for CompressionStats {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let (sent_raw, sent_compressed) = self.sent();
+		let (received_raw, received_compressed) = self.received();
+		write!(
+			f,
+			"sent {} bytes as {} ({:.1}%), received {} bytes as {} ({:.1}%)",
+			sent_raw,
+			sent_compressed,
+			ratio(sent_compressed, sent_raw),
+			received_raw,
+			received_compressed,
+			ratio(received_compressed, received_raw)
+		)
+	}
+}
+
+fn invalid_data(msg: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+enum CompressorInner {
+	Zstd(zstd::bulk::Compressor<'static>),
+	Deflate(Compress),
+}
+
+/// Compresses frames written to a transport.
+pub struct Compressor {
+	inner: CompressorInner,
+	buf: Vec<u8>,
+	stats: Arc<CompressionStats>,
+}
+
+impl Compressor {
+	pub(crate) fn new(
+		opts: &CompressionOptions,
+		dict: &[u8],
+		max_frame_length: usize,
+		stats: Arc<CompressionStats>,
+	) -> Result<Self, io::Error> {
+		let inner = match opts.compression {
+			Compression::Zstd => CompressorInner::Zstd(zstd::bulk::Compressor::with_dictionary(
+				opts.compression_level
+					.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL),
+				dict,
+			)?),
+			Compression::Deflate => CompressorInner::Deflate(Compress::new(
+				flate2::Compression::new(opts.compression_level.unwrap_or(6).clamp(0, 9) as u32),
+				false,
+			)),
+			Compression::None => unreachable!("compression is disabled"),
+		};
+		Ok(Self {
+			inner,
+			buf: Vec::with_capacity(max_frame_length),
+			stats,
+		})
+	}
+
+	pub fn compress(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), io::Error> {
+		self.buf.clear();
+		let compressed = match &mut self.inner {
+			CompressorInner::Zstd(zstd) => zstd.compress_to_buffer(item, &mut self.buf).is_ok(),
+			CompressorInner::Deflate(deflate) => {
+				deflate.reset();
+				let status = deflate.compress_vec(item, &mut self.buf, FlushCompress::Finish);
+				matches!(status, Ok(Status::StreamEnd))
+			}
+		};
+
+		// incompressible or too large for the buffer, store it instead
+		let start = dst.len();
+		if compressed && self.buf.len() < item.len() {
+			dst.reserve(1 + self.buf.len());
+			dst.put_u8(FLAG_COMPRESSED);
+			dst.put_slice(&self.buf);
+		} else {
+			dst.reserve(1 + item.len());
+			dst.put_u8(FLAG_STORED);
+			dst.put_slice(item);
+		}
+		self.stats
+			.sent_raw
+			.fetch_add(item.len() as u64, Ordering::Relaxed);
+		self.stats
+			.sent_compressed
+			.fetch_add((dst.len() - start) as u64, Ordering::Relaxed);
+		Ok(())
+	}
+}
+
+enum DecompressorInner {
+	Zstd(zstd::bulk::Decompressor<'static>),
+	Deflate(Decompress),
+}
+
+/// Decompresses frames read from a transport, never producing more than the maximum frame
+/// length.
+pub struct Decompressor {
+	inner: DecompressorInner,
+	buf: Vec<u8>,
+	max_frame_length: usize,
+	stats: Arc<CompressionStats>,
+}
+
+impl Decompressor {
+	pub(crate) fn new(
+		opts: &CompressionOptions,
+		dict: &[u8],
+		max_frame_length: usize,
+		stats: Arc<CompressionStats>,
+	) -> Result<Self, io::Error> {
+		let inner = match opts.compression {
+			Compression::Zstd => {
+				DecompressorInner::Zstd(zstd::bulk::Decompressor::with_dictionary(dict)?)
+			}
+			Compression::Deflate => DecompressorInner::Deflate(Decompress::new(false)),
+			Compression::None => unreachable!("compression is disabled"),
+		};
+		Ok(Self {
+			inner,
+			buf: Vec::with_capacity(max_frame_length),
+			max_frame_length,
+			stats,
+		})
+	}
+
+	pub fn decompress(&mut self, frame: &[u8]) -> Result<BytesMut, io::Error> {
+		let (flag, data) = frame
+			.split_first()
+			.ok_or(invalid_data("empty compressed frame".to_string()))?;
+		let out = match *flag {
+			FLAG_STORED => BytesMut::from(data),
+			FLAG_COMPRESSED => {
+				self.buf.clear();
+				let complete = match &mut self.inner {
+					DecompressorInner::Zstd(zstd) => {
+						zstd.decompress_to_buffer(data, &mut self.buf).is_ok()
+					}
+					DecompressorInner::Deflate(deflate) => {
+						deflate.reset(false);
+						let status =
+							deflate.decompress_vec(data, &mut self.buf, FlushDecompress::Finish);
+						matches!(status, Ok(Status::StreamEnd))
+					}
+				};
+				if !complete {
+					return Err(invalid_data(format!(
+						"frame does not decompress to at most {} bytes",
+						self.max_frame_length
+					)));
+				}
+				BytesMut::from(&self.buf[..])
+			}
+			flag => return Err(invalid_data(format!("unknown compression flag {}", flag))),
+		};
+		self.stats
+			.received_raw
+			.fetch_add(out.len() as u64, Ordering::Relaxed);
+		self.stats
+			.received_compressed
+			.fetch_add(frame.len() as u64, Ordering::Relaxed);
+		Ok(out)
+	}
+}
+
+fn handshake_frame(compression: Compression, dict_id: u32) -> Bytes {
+	let mut frame = BytesMut::with_capacity(HANDSHAKE_LEN);
+	frame.put_slice(HANDSHAKE_MAGIC);
+	frame.put_u8(compression.id());
+	frame.put_u32_le(dict_id);
+	frame.freeze()
+}
+
+fn describe(compression: Option<Compression>, dict_id: u32) -> String {
+	match (compression, dict_id) {
+		(Some(compression), 0) => format!("{:?}", compression),
+		(Some(compression), dict_id) => {
+			format!("{:?} with dictionary {:08x}", compression, dict_id)
+		}
+		(None, _) => "an unknown algorithm".to_string(),
+	}
+}
+
+/// Agrees on compression with the other end of a freshly opened frame transport, then
+/// compresses every later frame in both directions.
+pub async fn negotiate<R: FrameRead, W: FrameWrite>(
+	rx: &mut R,
+	tx: &mut W,
+	opts: &CompressionOptions,
+	max_frame_length: usize,
+) -> Result<Arc<CompressionStats>, io::Error> {
+	let dict = match &opts.compression_dict {
+		Some(path) if opts.compression == Compression::Zstd => tokio::fs::read(path).await?,
+		Some(_) => {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"compression dictionaries are only supported with zstd",
+			))
+		}
+		None => Vec::new(),
+	};
+	let dict_id = if dict.is_empty() {
+		0
+	} else {
+		crc32fast::hash(&dict)
+	};
+
+	tx.sink()
+		.send(handshake_frame(opts.compression, dict_id))
+		.await?;
+	let answer = timeout(HANDSHAKE_TIMEOUT, rx.stream().next())
+		.await
+		.map_err(|_| {
+			io::Error::new(
+				io::ErrorKind::TimedOut,
+				"peer did not answer the compression handshake",
+			)
+		})?
+		.ok_or(io::Error::new(
+			io::ErrorKind::UnexpectedEof,
+			"connection closed during compression handshake",
+		))??;
+	if answer.len() != HANDSHAKE_LEN || !answer.starts_with(HANDSHAKE_MAGIC) {
+		return Err(invalid_data(
+			"peer did not answer the compression handshake".to_string(),
+		));
+	}
+	let peer = Compression::from_id(answer[HANDSHAKE_MAGIC.len()]);
+	let peer_dict_id = u32::from_le_bytes(answer[HANDSHAKE_MAGIC.len() + 1..].try_into().unwrap());
+	if peer != Some(opts.compression) || peer_dict_id != dict_id {
+		return Err(invalid_data(format!(
+			"compression mismatch: we use {}, peer uses {}",
+			describe(Some(opts.compression), dict_id),
+			describe(peer, peer_dict_id)
+		)));
+	}
+
+	let stats = Arc::new(CompressionStats::default());
+	rx.codec_mut().set_decompressor(Decompressor::new(
+		opts,
+		&dict,
+		max_frame_length,
+		stats.clone(),
+	)?);
+	tx.codec_mut().set_compressor(Compressor::new(
+		opts,
+		&dict,
+		max_frame_length,
+		stats.clone(),
+	)?);
+	info!(
+		"Negotiated {} compression",
+		describe(Some(opts.compression), dict_id)
+	);
+	Ok(stats)
+}
+
+/// Logs the compression ratio periodically until the transport is dropped.
+pub async fn log_stats(stats: Weak<CompressionStats>) {
+	let mut interval = interval(STATS_INTERVAL);
+	interval.tick().await;
+	loop {
+		interval.tick().await;
+		match stats.upgrade() {
+			Some(stats) => info!("Compression: {}", stats),
+			None => break,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::io::{duplex, split};
+
+	use super::*;
+	use crate::pty::{StreamRead, StreamWrite};
+
+	const MAX: usize = 4096;
+
+	fn opts(compression: Compression) -> CompressionOptions {
+		CompressionOptions {
+			compression,
+			..Default::default()
+		}
+	}
+
+	fn pair(compression: Compression, dict: &[u8], max: usize) -> (Compressor, Decompressor) {
+		let stats = Arc::new(CompressionStats::default());
+		let opts = opts(compression);
+		(
+			Compressor::new(&opts, dict, max, stats.clone()).unwrap(),
+			Decompressor::new(&opts, dict, max, stats).unwrap(),
+		)
+	}
+
+	#[test]
+	fn round_trip() {
+		let text = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(16);
+		for (compression, dict) in [
+			(Compression::Zstd, &b""[..]),
+			(Compression::Zstd, &text[..64]),
+			(Compression::Deflate, &b""[..]),
+		] {
+			let (mut compressor, mut decompressor) = pair(compression, dict, MAX);
+			for item in [&text[..], &text[..100], &[0; MAX], b"x"] {
+				let mut frame = BytesMut::new();
+				compressor.compress(item, &mut frame).unwrap();
+				assert_eq!(decompressor.decompress(&frame).unwrap(), item);
+			}
+			// frames don't depend on each other
+			let mut first = BytesMut::new();
+			compressor.compress(&text, &mut first).unwrap();
+			let mut second = BytesMut::new();
+			compressor.compress(&text, &mut second).unwrap();
+			assert_eq!(first, second);
+			assert_eq!(first[0], FLAG_COMPRESSED);
+			assert!(first.len() < text.len());
+		}
+	}
+
+	#[test]
+	fn stores_incompressible_frames() {
+		let random: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+		for compression in [Compression::Zstd, Compression::Deflate] {
+			let (mut compressor, mut decompressor) = pair(compression, b"", MAX);
+			for item in [&random[..], b""] {
+				let mut frame = BytesMut::new();
+				compressor.compress(item, &mut frame).unwrap();
+				assert_eq!(frame[0], FLAG_STORED);
+				assert_eq!(frame[1..], *item);
+				assert_eq!(decompressor.decompress(&frame).unwrap(), item);
+			}
+			let stats = &compressor.stats;
+			assert_eq!(stats.sent(), (1024, 1026));
+			assert_eq!(stats.received(), (1024, 1026));
+
+			assert!(decompressor.decompress(b"").is_err());
+			assert!(decompressor.decompress(&[7, 1, 2, 3]).is_err());
+			// garbage marked as compressed
+			assert!(decompressor
+				.decompress(&[FLAG_COMPRESSED, 1, 2, 3])
+				.is_err());
+		}
+	}
+
+	#[test]
+	fn rejects_bombs() {
+		let zeros = vec![0; 1 << 20];
+		for compression in [Compression::Zstd, Compression::Deflate] {
+			let (mut compressor, _) = pair(compression, b"", zeros.len());
+			let mut frame = BytesMut::new();
+			compressor.compress(&zeros, &mut frame).unwrap();
+			assert_eq!(frame[0], FLAG_COMPRESSED);
+			assert!(frame.len() < MAX);
+
+			let (_, mut decompressor) = pair(compression, b"", MAX);
+			let err = decompressor.decompress(&frame).unwrap_err();
+			assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+			assert!(decompressor.buf.capacity() < 2 * MAX);
+
+			// exactly at the limit is fine
+			let mut frame = BytesMut::new();
+			compressor.compress(&zeros[..MAX], &mut frame).unwrap();
+			assert_eq!(decompressor.decompress(&frame).unwrap(), zeros[..MAX]);
+		}
+	}
+
+	async fn negotiate_with(
+		ours: &CompressionOptions,
+		theirs: &CompressionOptions,
+	) -> (
+		Result<Arc<CompressionStats>, io::Error>,
+		Result<Arc<CompressionStats>, io::Error>,
+	) {
+		let (a, b) = duplex(1024);
+		let ((a_rx, a_tx), (b_rx, b_tx)) = (split(a), split(b));
+		let (mut a_rx, mut a_tx) = (StreamRead::new(a_rx, MAX), StreamWrite::new(a_tx, MAX));
+		let (mut b_rx, mut b_tx) = (StreamRead::new(b_rx, MAX), StreamWrite::new(b_tx, MAX));
+		tokio::join!(
+			negotiate(&mut a_rx, &mut a_tx, ours, MAX),
+			negotiate(&mut b_rx, &mut b_tx, theirs, MAX),
+		)
+	}
+
+	#[tokio::test]
+	async fn handshake() {
+		let zstd = opts(Compression::Zstd);
+		let (a, b) = negotiate_with(&zstd, &zstd).await;
+		assert!(a.is_ok() && b.is_ok());
+
+		let (a, b) = negotiate_with(&zstd, &opts(Compression::Deflate)).await;
+		let err = a.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+		assert_eq!(
+			err.to_string(),
+			"compression mismatch: we use Zstd, peer uses Deflate"
+		);
+		assert!(b.is_err());
+
+		// the same algorithm with different dictionaries
+		let dict = std::env::temp_dir().join(format!("whisper-dict-{}", std::process::id()));
+		std::fs::write(&dict, b"dictionary").unwrap();
+		let with_dict = CompressionOptions {
+			compression_dict: Some(dict.clone()),
+			..zstd.clone()
+		};
+		let (a, b) = negotiate_with(&with_dict, &zstd).await;
+		assert_eq!(
+			a.unwrap_err().to_string(),
+			format!(
+				"compression mismatch: we use Zstd with dictionary {:08x}, peer uses Zstd",
+				crc32fast::hash(b"dictionary")
+			)
+		);
+		assert!(b.is_err());
+
+		let deflate_with_dict = CompressionOptions {
+			compression: Compression::Deflate,
+			..with_dict
+		};
+		// refused before anything is sent
+		let (rx, tx) = split(duplex(1024).0);
+		let err = negotiate(
+			&mut StreamRead::new(rx, MAX),
+			&mut StreamWrite::new(tx, MAX),
+			&deflate_with_dict,
+			MAX,
+		)
+		.await
+		.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		std::fs::remove_file(&dict).unwrap();
+	}
+}
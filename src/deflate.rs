@@ -0,0 +1,723 @@
+//! permessage-deflate (RFC 7692) for WebSocket transports.
+//!
+//! fastwebsockets refuses frames with RSV1 set, so when the server accepts the extension the
+//! upgraded connection is driven by [`DeflateWebSocketRead`] and [`DeflateWebSocketWrite`]
+//! instead. When the server declines, the plain fastwebsockets path is used as before.
+
+use std::io;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use clap::Args;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use wisp_mux::{
+	ws::{Frame, LockedWebSocketWrite, OpCode, Payload, WebSocketRead, WebSocketWrite},
+	WispError,
+};
+
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+/// Same limit fastwebsockets applies to messages.
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+/// Trailer removed from every compressed message and added back before inflating it.
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const CLOSE_NORMAL: [u8; 2] = 1000u16.to_be_bytes();
+/// Largest payload of a control frame (RFC 6455 section 5.5).
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct WsDeflateOptions {
+	/// Offer permessage-deflate on WebSocket transports. Used only if the server accepts it.
+	#[arg(long)]
+	pub ws_deflate: bool,
+	/// Largest LZ77 window (in bits) whisper compresses with
+	#[arg(long, requires = "ws_deflate", value_parser = clap::value_parser!(u8).range(9..=15))]
+	pub ws_deflate_client_window_bits: Option<u8>,
+	/// Largest LZ77 window (in bits) the server may compress with
+	#[arg(long, requires = "ws_deflate", value_parser = clap::value_parser!(u8).range(9..=15))]
+	pub ws_deflate_server_window_bits: Option<u8>,
+	/// Reset the compression context after every message whisper sends
+	#[arg(long, requires = "ws_deflate")]
+	pub ws_deflate_client_no_context_takeover: bool,
+	/// Ask the server to reset its compression context after every message
+	#[arg(long, requires = "ws_deflate")]
+	pub ws_deflate_server_no_context_takeover: bool,
+}
+
+/// Parameters the server accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+	pub client_window_bits: u8,
+	pub server_window_bits: u8,
+	pub client_no_context_takeover: bool,
+	pub server_no_context_takeover: bool,
+}
+
+fn invalid_response(msg: String) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidData,
+		format!("invalid permessage-deflate response: {}", msg),
+	)
+}
+
+fn parse_window_bits(name: &str, value: Option<&str>) -> Result<u8, io::Error> {
+	value
+		.map(|x| x.trim_matches('"'))
+		.and_then(|x| x.parse::<u8>().ok())
+		.filter(|x| (8..=15).contains(x))
+		.ok_or(invalid_response(format!("bad {} value", name)))
+}
+
+impl WsDeflateOptions {
+	/// Value of the `Sec-WebSocket-Extensions` request header, if permessage-deflate is enabled.
+	pub fn offer(&self) -> Option<String> {
+		if !self.ws_deflate {
+			return None;
+		}
+		let mut offer = EXTENSION_NAME.to_string();
+		match self.ws_deflate_client_window_bits {
+			Some(bits) => offer.push_str(&format!("; client_max_window_bits={}", bits)),
+			None => offer.push_str("; client_max_window_bits"),
+		}
+		if let Some(bits) = self.ws_deflate_server_window_bits {
+			offer.push_str(&format!("; server_max_window_bits={}", bits));
+		}
+		if self.ws_deflate_client_no_context_takeover {
+			offer.push_str("; client_no_context_takeover");
+		}
+		if self.ws_deflate_server_no_context_takeover {
+			offer.push_str("; server_no_context_takeover");
+		}
+		Some(offer)
+	}
+
+	/// Parses the server's `Sec-WebSocket-Extensions` response header. Returns `None` if the
+	/// server didn't accept permessage-deflate.
+	pub fn accept(&self, header: Option<&str>) -> Result<Option<DeflateParams>, io::Error> {
+		let Some(header) = header else {
+			return Ok(None);
+		};
+		let mut accepted = None;
+		for extension in header.split(',') {
+			let mut params = extension.split(';').map(str::trim);
+			if params.next() != Some(EXTENSION_NAME) {
+				return Err(invalid_response(format!(
+					"server accepted an extension that was not offered: {}",
+					extension.trim()
+				)));
+			}
+			if !self.ws_deflate || accepted.is_some() {
+				return Err(invalid_response(
+					"server accepted permessage-deflate more than once or without an offer"
+						.to_string(),
+				));
+			}
+
+			let mut accept = DeflateParams {
+				client_window_bits: self.ws_deflate_client_window_bits.unwrap_or(15),
+				server_window_bits: 15,
+				client_no_context_takeover: self.ws_deflate_client_no_context_takeover,
+				server_no_context_takeover: false,
+			};
+			let mut server_window_bits = false;
+			for param in params {
+				let (name, value) = match param.split_once('=') {
+					Some((name, value)) => (name.trim(), Some(value.trim())),
+					None => (param, None),
+				};
+				match name {
+					"client_max_window_bits" => {
+						let bits = parse_window_bits(name, value)?;
+						if bits > accept.client_window_bits {
+							return Err(invalid_response(format!(
+								"client_max_window_bits={} is larger than offered",
+								bits
+							)));
+						}
+						accept.client_window_bits = bits;
+					}
+					"server_max_window_bits" => {
+						let bits = parse_window_bits(name, value)?;
+						if bits > self.ws_deflate_server_window_bits.unwrap_or(15) {
+							return Err(invalid_response(format!(
+								"server_max_window_bits={} is larger than offered",
+								bits
+							)));
+						}
+						accept.server_window_bits = bits;
+						server_window_bits = true;
+					}
+					"client_no_context_takeover" => accept.client_no_context_takeover = true,
+					"server_no_context_takeover" => accept.server_no_context_takeover = true,
+					_ => {
+						return Err(invalid_response(format!("unknown parameter {}", name)));
+					}
+				}
+			}
+			// a server that can't limit its window has to decline the offer instead
+			if self.ws_deflate_server_window_bits.is_some() && !server_window_bits {
+				return Err(invalid_response(
+					"server ignored server_max_window_bits".to_string(),
+				));
+			}
+			if self.ws_deflate_server_no_context_takeover && !accept.server_no_context_takeover {
+				return Err(invalid_response(
+					"server ignored server_no_context_takeover".to_string(),
+				));
+			}
+			accepted = Some(accept);
+		}
+		Ok(accepted)
+	}
+}
+
+fn ws_error(msg: impl Into<String>) -> WispError {
+	WispError::WsImplError(Box::new(io::Error::new(
+		io::ErrorKind::InvalidData,
+		msg.into(),
+	)))
+}
+
+fn io_error(err: io::Error) -> WispError {
+	if err.kind() == io::ErrorKind::UnexpectedEof {
+		WispError::WsImplSocketClosed
+	} else {
+		WispError::WsImplError(Box::new(err))
+	}
+}
+
+struct RawFrame {
+	fin: bool,
+	rsv1: bool,
+	opcode: u8,
+	payload: BytesMut,
+}
+
+/// Reads server frames, inflating compressed messages.
+pub struct DeflateWebSocketRead<R: AsyncRead + Unpin + Send> {
+	rx: R,
+	inflate: Decompress,
+	no_context_takeover: bool,
+	message: BytesMut,
+}
+
+impl<R: AsyncRead + Unpin + Send> DeflateWebSocketRead<R> {
+	pub fn new(rx: R, params: DeflateParams) -> Self {
+		Self {
+			rx,
+			inflate: Decompress::new(false),
+			no_context_takeover: params.server_no_context_takeover,
+			message: BytesMut::new(),
+		}
+	}
+
+	async fn read_raw_frame(&mut self) -> Result<RawFrame, WispError> {
+		let mut head = [0u8; 2];
+		self.rx.read_exact(&mut head).await.map_err(io_error)?;
+		if head[0] & 0b0011_0000 != 0 {
+			return Err(ws_error("reserved bits set"));
+		}
+
+		let len = match head[1] & 0x7f {
+			126 => self.rx.read_u16().await.map_err(io_error)? as usize,
+			127 => self.rx.read_u64().await.map_err(io_error)? as usize,
+			len => len as usize,
+		};
+		if head[0] & 0x8 != 0 && len > MAX_CONTROL_PAYLOAD {
+			return Err(ws_error("control frame too large"));
+		}
+		if self.message.len().saturating_add(len) > MAX_MESSAGE_SIZE {
+			return Err(ws_error("message too large"));
+		}
+		let mask = if head[1] & 0x80 != 0 {
+			let mut mask = [0u8; 4];
+			self.rx.read_exact(&mut mask).await.map_err(io_error)?;
+			Some(mask)
+		} else {
+			None
+		};
+
+		let mut payload = BytesMut::zeroed(len);
+		self.rx.read_exact(&mut payload).await.map_err(io_error)?;
+		if let Some(mask) = mask {
+			for (i, byte) in payload.iter_mut().enumerate() {
+				*byte ^= mask[i % 4];
+			}
+		}
+		Ok(RawFrame {
+			fin: head[0] & 0x80 != 0,
+			rsv1: head[0] & 0x40 != 0,
+			opcode: head[0] & 0x0f,
+			payload,
+		})
+	}
+
+	fn inflate_message(&mut self) -> Result<BytesMut, WispError> {
+		self.message.extend_from_slice(&DEFLATE_TRAILER);
+		let input = self.message.split();
+		let start = self.inflate.total_in();
+		let mut out = Vec::with_capacity(input.len() * 4);
+		loop {
+			if out.len() == out.capacity() {
+				if out.len() >= MAX_MESSAGE_SIZE {
+					return Err(ws_error("message too large"));
+				}
+				out.reserve(out.len().max(4096));
+			}
+			let consumed = (self.inflate.total_in() - start) as usize;
+			let out_len = out.len();
+			self.inflate
+				.decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+				.map_err(|x| WispError::WsImplError(Box::new(x)))?;
+			let progress =
+				(self.inflate.total_in() - start) as usize > consumed || out.len() > out_len;
+			let done = (self.inflate.total_in() - start) as usize == input.len();
+			// a full buffer may still hold back output, anything else is the end of the message
+			if done && (out.len() < out.capacity() || !progress) {
+				break;
+			}
+			if !progress {
+				return Err(ws_error("truncated compressed message"));
+			}
+		}
+		if self.no_context_takeover {
+			self.inflate.reset(false);
+		}
+		Ok(BytesMut::from(&out[..]))
+	}
+}
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send> WebSocketRead for DeflateWebSocketRead<R> {
+	async fn wisp_read_frame(
+		&mut self,
+		tx: &LockedWebSocketWrite,
+	) -> Result<Frame<'static>, WispError> {
+		self.message.clear();
+		let mut message = None;
+		loop {
+			let frame = self.read_raw_frame().await?;
+			if frame.opcode & 0x8 != 0 && (frame.rsv1 || !frame.fin) {
+				return Err(ws_error("invalid control frame"));
+			}
+			match frame.opcode {
+				0x0 if message.is_some() && !frame.rsv1 => {
+					self.message.extend_from_slice(&frame.payload)
+				}
+				0x1 | 0x2 if message.is_none() => {
+					let opcode = if frame.opcode == 0x1 {
+						OpCode::Text
+					} else {
+						OpCode::Binary
+					};
+					message = Some((opcode, frame.rsv1));
+					self.message.extend_from_slice(&frame.payload);
+				}
+				0x8 => return Ok(Frame::close(Payload::Bytes(frame.payload))),
+				0x9 => {
+					tx.write_frame(Frame {
+						finished: true,
+						opcode: OpCode::Pong,
+						payload: Payload::Bytes(frame.payload),
+					})
+					.await?;
+					continue;
+				}
+				0xa => {
+					return Ok(Frame {
+						finished: true,
+						opcode: OpCode::Pong,
+						payload: Payload::Bytes(frame.payload),
+					})
+				}
+				_ => return Err(ws_error("unexpected frame")),
+			}
+
+			if let (true, Some((opcode, compressed))) = (frame.fin, message) {
+				let payload = if compressed {
+					self.inflate_message()?
+				} else {
+					self.message.split()
+				};
+				return Ok(Frame {
+					finished: true,
+					opcode,
+					payload: Payload::Bytes(payload),
+				});
+			}
+		}
+	}
+}
+
+/// Writes masked client frames, compressing data messages.
+pub struct DeflateWebSocketWrite<W: AsyncWrite + Unpin + Send> {
+	tx: W,
+	deflate: Option<Compress>,
+	no_context_takeover: bool,
+}
+
+impl<W: AsyncWrite + Unpin + Send> DeflateWebSocketWrite<W> {
+	pub fn new(tx: W, params: DeflateParams) -> Self {
+		// zlib can't produce raw deflate streams with a 256 byte window, send uncompressed instead
+		let deflate = (params.client_window_bits >= 9).then(|| {
+			Compress::new_with_window_bits(Compression::default(), false, params.client_window_bits)
+		});
+		Self {
+			tx,
+			deflate,
+			no_context_takeover: params.client_no_context_takeover,
+		}
+	}
+
+	fn deflate_message(deflate: &mut Compress, payload: &[u8]) -> Result<Vec<u8>, WispError> {
+		let start = deflate.total_in();
+		let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+		loop {
+			let consumed = (deflate.total_in() - start) as usize;
+			if consumed == payload.len() && out.len() < out.capacity() {
+				break;
+			}
+			if out.len() == out.capacity() {
+				out.reserve(out.len().max(4096));
+			}
+			deflate
+				.compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+				.map_err(|x| WispError::WsImplError(Box::new(x)))?;
+		}
+		if out.ends_with(&DEFLATE_TRAILER) {
+			out.truncate(out.len() - DEFLATE_TRAILER.len());
+		} else if out.is_empty() {
+			// nothing was flushed, the message still has to end with an empty stored block
+			out.push(0x00);
+		}
+		Ok(out)
+	}
+
+	async fn write_raw_frame(
+		&mut self,
+		fin: bool,
+		rsv1: bool,
+		opcode: u8,
+		payload: &[u8],
+	) -> Result<(), WispError> {
+		if opcode & 0x8 != 0 && payload.len() > MAX_CONTROL_PAYLOAD {
+			return Err(ws_error("control frame too large"));
+		}
+		let mut buf = Vec::with_capacity(14 + payload.len());
+		buf.push((fin as u8) << 7 | (rsv1 as u8) << 6 | opcode);
+		match payload.len() {
+			len @ 0..=125 => buf.push(0x80 | len as u8),
+			len @ 126..=0xffff => {
+				buf.push(0x80 | 126);
+				buf.extend_from_slice(&(len as u16).to_be_bytes());
+			}
+			len => {
+				buf.push(0x80 | 127);
+				buf.extend_from_slice(&(len as u64).to_be_bytes());
+			}
+		}
+		let mask: [u8; 4] = rand::random();
+		buf.extend_from_slice(&mask);
+		buf.extend(payload.iter().enumerate().map(|(i, x)| x ^ mask[i % 4]));
+
+		self.tx.write_all(&buf).await.map_err(io_error)?;
+		self.tx.flush().await.map_err(io_error)
+	}
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send> WebSocketWrite for DeflateWebSocketWrite<W> {
+	async fn wisp_write_frame(&mut self, frame: Frame<'_>) -> Result<(), WispError> {
+		let opcode = match frame.opcode {
+			OpCode::Text => 0x1,
+			OpCode::Binary => 0x2,
+			OpCode::Close => 0x8,
+			OpCode::Ping => 0x9,
+			OpCode::Pong => 0xa,
+		};
+		match &mut self.deflate {
+			Some(deflate) if opcode & 0x8 == 0 => {
+				let payload = Self::deflate_message(deflate, &frame.payload)?;
+				if self.no_context_takeover {
+					deflate.reset();
+				}
+				self.write_raw_frame(frame.finished, true, opcode, &payload)
+					.await
+			}
+			_ => {
+				self.write_raw_frame(frame.finished, false, opcode, &frame.payload)
+					.await
+			}
+		}
+	}
+
+	async fn wisp_close(&mut self) -> Result<(), WispError> {
+		self.write_raw_frame(true, false, 0x8, &CLOSE_NORMAL).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn offered() -> WsDeflateOptions {
+		WsDeflateOptions {
+			ws_deflate: true,
+			..Default::default()
+		}
+	}
+
+	fn params(client_window_bits: u8, server_window_bits: u8) -> DeflateParams {
+		DeflateParams {
+			client_window_bits,
+			server_window_bits,
+			client_no_context_takeover: false,
+			server_no_context_takeover: false,
+		}
+	}
+
+	#[test]
+	fn offer() {
+		assert_eq!(WsDeflateOptions::default().offer(), None);
+		assert_eq!(
+			offered().offer().unwrap(),
+			"permessage-deflate; client_max_window_bits"
+		);
+		let opts = WsDeflateOptions {
+			ws_deflate_client_window_bits: Some(10),
+			ws_deflate_server_window_bits: Some(12),
+			ws_deflate_client_no_context_takeover: true,
+			ws_deflate_server_no_context_takeover: true,
+			..offered()
+		};
+		assert_eq!(
+			opts.offer().unwrap(),
+			"permessage-deflate; client_max_window_bits=10; server_max_window_bits=12; \
+			 client_no_context_takeover; server_no_context_takeover"
+		);
+	}
+
+	#[test]
+	fn accept() {
+		let opts = offered();
+		assert_eq!(opts.accept(None).unwrap(), None);
+		assert_eq!(
+			opts.accept(Some("permessage-deflate")).unwrap(),
+			Some(params(15, 15))
+		);
+		assert_eq!(
+			opts.accept(Some(
+				"permessage-deflate; client_max_window_bits=9; server_max_window_bits=\"10\"; \
+				 server_no_context_takeover"
+			))
+			.unwrap(),
+			Some(DeflateParams {
+				server_no_context_takeover: true,
+				..params(9, 10)
+			})
+		);
+	}
+
+	#[test]
+	fn accept_window_bits_up_to_offer() {
+		let opts = WsDeflateOptions {
+			ws_deflate_client_window_bits: Some(10),
+			ws_deflate_server_window_bits: Some(12),
+			..offered()
+		};
+		assert_eq!(
+			opts.accept(Some(
+				"permessage-deflate; client_max_window_bits=10; server_max_window_bits=12"
+			))
+			.unwrap(),
+			Some(params(10, 12))
+		);
+		for header in [
+			"permessage-deflate; client_max_window_bits=11; server_max_window_bits=12",
+			"permessage-deflate; server_max_window_bits=13",
+			// the server has to confirm the limit on its window
+			"permessage-deflate",
+		] {
+			assert!(opts.accept(Some(header)).is_err(), "{}", header);
+		}
+	}
+
+	#[test]
+	fn accept_rejects_bad_responses() {
+		assert!(WsDeflateOptions::default()
+			.accept(Some("permessage-deflate"))
+			.is_err());
+		let opts = WsDeflateOptions {
+			ws_deflate_server_no_context_takeover: true,
+			..offered()
+		};
+		assert!(opts.accept(Some("permessage-deflate")).is_err());
+		for header in [
+			"x-webkit-deflate-frame",
+			"permessage-deflate, permessage-deflate",
+			"permessage-deflate; server_max_window_bits=16",
+			"permessage-deflate; server_max_window_bits",
+			"permessage-deflate; client_max_window_bits=abc",
+			"permessage-deflate; unknown",
+		] {
+			assert!(offered().accept(Some(header)).is_err(), "{}", header);
+		}
+	}
+
+	#[tokio::test]
+	async fn control_frames_are_limited() {
+		// a ping claiming 200 bytes of payload
+		let mut rx = DeflateWebSocketRead::new(&[0x89, 126, 0, 200][..], params(15, 15));
+		assert!(rx.read_raw_frame().await.is_err());
+		let mut rx = DeflateWebSocketRead::new(&[0x89, 2, 1, 2][..], params(15, 15));
+		assert_eq!(&rx.read_raw_frame().await.unwrap().payload[..], [1, 2]);
+
+		let mut tx = DeflateWebSocketWrite::new(Vec::new(), params(15, 15));
+		assert!(tx
+			.write_raw_frame(true, false, 0x9, &[0; 126])
+			.await
+			.is_err());
+		tx.write_raw_frame(true, false, 0x9, &[0; 125])
+			.await
+			.unwrap();
+	}
+
+	/// Pipes messages from a [`DeflateWebSocketWrite`] into a [`DeflateWebSocketRead`].
+	struct Pipe {
+		tx: DeflateWebSocketWrite<Vec<u8>>,
+		rx_params: DeflateParams,
+		sent: Vec<u8>,
+		rx: Option<DeflateWebSocketRead<io::Cursor<Vec<u8>>>>,
+	}
+
+	impl Pipe {
+		fn new(tx: DeflateParams, rx: DeflateParams) -> Self {
+			Self {
+				tx: DeflateWebSocketWrite::new(Vec::new(), tx),
+				rx_params: rx,
+				sent: Vec::new(),
+				rx: None,
+			}
+		}
+
+		/// Sends `payload` and returns the frame it was written as.
+		async fn send(&mut self, payload: &[u8]) -> Vec<u8> {
+			self.tx
+				.wisp_write_frame(Frame::binary(Payload::Borrowed(payload)))
+				.await
+				.unwrap();
+			let frame = self.tx.tx.split_off(0);
+			self.sent.extend_from_slice(&frame);
+			frame
+		}
+
+		/// Reads the next message of everything sent so far.
+		async fn recv(&mut self) -> Result<Vec<u8>, WispError> {
+			let params = self.rx_params;
+			let rx = self.rx.get_or_insert_with(|| {
+				DeflateWebSocketRead::new(io::Cursor::new(Vec::new()), params)
+			});
+			let pos = rx.rx.position();
+			rx.rx.get_mut().extend_from_slice(&self.sent);
+			rx.rx.set_position(pos);
+			self.sent.clear();
+			let tx = LockedWebSocketWrite::new(Box::new(DeflateWebSocketWrite::new(
+				tokio::io::sink(),
+				params,
+			)));
+			let frame = rx.wisp_read_frame(&tx).await?;
+			assert_eq!(frame.opcode, OpCode::Binary);
+			Ok(frame.payload.to_vec())
+		}
+	}
+
+	#[tokio::test]
+	async fn round_trip() {
+		let text = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(100);
+		let random: Vec<u8> = (0..5000).map(|_| rand::random()).collect();
+		let mut pipe = Pipe::new(params(15, 15), params(15, 15));
+		for payload in [&text[..], &random, b"", b"x", &text[..37]] {
+			let frame = pipe.send(payload).await;
+			// RSV1 set on every data frame
+			assert_eq!(frame[0], 0xc2);
+			assert_eq!(
+				pipe.recv().await.unwrap(),
+				payload,
+				"{} bytes",
+				payload.len()
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn round_trip_at_exact_capacity() {
+		// messages whose inflated size is exactly the initial output buffer
+		let mut exact = 0;
+		let mut pipe = Pipe::new(params(15, 15), params(15, 15));
+		for len in 1..2000 {
+			let payload: Vec<u8> = (0..len).map(|i| (i % 7) as u8).collect();
+			let frame = pipe.send(&payload).await;
+			// header and mask of a masked frame
+			let header = if frame.len() - 6 < 126 { 6 } else { 8 };
+			if len == (frame.len() - header + DEFLATE_TRAILER.len()) * 4 {
+				exact += 1;
+			}
+			assert_eq!(pipe.recv().await.unwrap(), payload, "{} bytes", len);
+		}
+		assert!(exact > 0);
+	}
+
+	#[tokio::test]
+	async fn continuation_frames() {
+		let text = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(100);
+		let mut pipe = Pipe::new(params(15, 15), params(15, 15));
+		let frame = pipe.send(&text).await;
+		pipe.sent.clear();
+
+		// the same compressed message split over three frames, with a ping in between
+		let mut rx = DeflateWebSocketRead::new(&frame[..], params(15, 15));
+		let payload = rx.read_raw_frame().await.unwrap().payload;
+		let (a, rest) = payload.split_at(10);
+		let (b, c) = rest.split_at(rest.len() / 2);
+		let mut tx = DeflateWebSocketWrite::new(Vec::new(), params(15, 15));
+		tx.write_raw_frame(false, true, 0x2, a).await.unwrap();
+		tx.write_raw_frame(false, false, 0x0, b).await.unwrap();
+		tx.write_raw_frame(true, false, 0x9, b"ping").await.unwrap();
+		tx.write_raw_frame(true, false, 0x0, c).await.unwrap();
+		pipe.sent = tx.tx;
+		assert_eq!(pipe.recv().await.unwrap(), text);
+	}
+
+	#[tokio::test]
+	async fn context_takeover() {
+		let text = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(10);
+		let no_takeover = DeflateParams {
+			client_no_context_takeover: true,
+			server_no_context_takeover: true,
+			..params(15, 15)
+		};
+
+		// later messages refer back to earlier ones
+		let mut pipe = Pipe::new(params(15, 15), params(15, 15));
+		let first = pipe.send(&text).await;
+		let second = pipe.send(&text).await;
+		assert!(second.len() < first.len());
+		assert_eq!(pipe.recv().await.unwrap(), text);
+		assert_eq!(pipe.recv().await.unwrap(), text);
+
+		// every message stands alone
+		let mut pipe = Pipe::new(no_takeover, no_takeover);
+		let first = pipe.send(&text).await;
+		let second = pipe.send(&text).await;
+		assert_eq!(second.len(), first.len());
+		assert_eq!(pipe.recv().await.unwrap(), text);
+		assert_eq!(pipe.recv().await.unwrap(), text);
+
+		// a reader that resets can't follow a writer that doesn't
+		let mut pipe = Pipe::new(params(15, 15), no_takeover);
+		pipe.send(&text).await;
+		pipe.send(&text).await;
+		assert_eq!(pipe.recv().await.unwrap(), text);
+		assert!(pipe.recv().await.is_err());
+	}
+}
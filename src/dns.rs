@@ -0,0 +1,508 @@
+//! Hostnames for flows from the DNS answers that pass through whisper.
+//!
+//! Flows only carry an IP address, which says little in the logs. Whisper reads the A and AAAA
+//! records of every DNS answer it forwards on port 53 and remembers which name each address was
+//! looked up as, so the connect and disconnect logs can show it next to the address. The cache
+//! holds at most `--dns-cache-size` addresses and forgets them once their TTL has passed, dropping
+//! the ones that expire first when it is full. Its hit rate is logged every minute while flows are
+//! being looked up.
+
+use std::{
+	collections::{BTreeSet, HashMap},
+	fmt::Display,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex, Weak,
+	},
+	time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::info;
+use tokio::time::interval;
+
+/// Shortest time an address is remembered, since clients often keep using an address for a while
+/// after its TTL.
+const MIN_TTL: Duration = Duration::from_secs(60);
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+/// Compression pointers followed before a name is considered malformed.
+const MAX_POINTERS: usize = 64;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Clone, Args)]
+pub struct DnsOptions {
+	/// Number of addresses to remember the hostname of from DNS answers, 0 to disable
+	#[arg(long, default_value_t = 4096)]
+	pub dns_cache_size: usize,
+}
+
+impl Default for DnsOptions {
+	fn default() -> Self {
+		Self {
+			dns_cache_size: 4096,
+		}
+	}
+}
+
+/// Question of a DNS query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+	pub name: String,
+	pub kind: u16,
+	/// Offset of the end of the question in the message.
+	pub end: usize,
+}
+
+struct Reader<'a> {
+	msg: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+		let x = self.msg.get(self.pos..self.pos.checked_add(n)?)?;
+		self.pos += n;
+		Some(x)
+	}
+
+	fn u16(&mut self) -> Option<u16> {
+		let x = self.take(2)?;
+		Some(u16::from_be_bytes([x[0], x[1]]))
+	}
+
+	fn u32(&mut self) -> Option<u32> {
+		let x = self.take(4)?;
+		Some(u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+	}
+
+	/// Reads a possibly compressed name, lowercased and without the trailing dot.
+	fn name(&mut self) -> Option<String> {
+		let mut labels: Vec<String> = Vec::new();
+		let mut pos = self.pos;
+		let mut end = None;
+		let mut pointers = 0;
+		loop {
+			let len = *self.msg.get(pos)? as usize;
+			match len & 0xc0 {
+				0x00 if len == 0 => {
+					pos += 1;
+					break;
+				}
+				0x00 => {
+					let label = self.msg.get(pos + 1..pos + 1 + len)?;
+					labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+					pos += 1 + len;
+				}
+				0xc0 => {
+					pointers += 1;
+					if pointers > MAX_POINTERS {
+						return None;
+					}
+					let offset = (len & 0x3f) << 8 | *self.msg.get(pos + 1)? as usize;
+					end.get_or_insert(pos + 2);
+					pos = offset;
+				}
+				_ => return None,
+			}
+		}
+		self.pos = end.unwrap_or(pos);
+		Some(labels.join("."))
+	}
+
+	/// Reads a question and returns its name.
+	fn question(&mut self) -> Option<String> {
+		let name = self.name()?;
+		// type and class
+		self.take(4)?;
+		Some(name)
+	}
+}
+
+/// Question of a standard DNS query with a single question.
+pub fn query(msg: &[u8]) -> Option<Question> {
+	let mut r = Reader { msg, pos: 2 };
+	let flags = r.u16()?;
+	// QR clear and opcode QUERY
+	if flags & 0xf800 != 0 || r.u16()? != 1 {
+		return None;
+	}
+	r.pos = 12;
+	let name = r.name()?;
+	let kind = r.u16()?;
+	r.u16()?;
+	Some(Question {
+		name,
+		kind,
+		end: r.pos,
+	})
+}
+
+/// Standard query for the records of type `kind` of `name`, with recursion desired.
+pub fn build_query(id: u16, name: &str, kind: u16) -> Vec<u8> {
+	let mut msg = Vec::with_capacity(18 + name.len());
+	msg.extend_from_slice(&id.to_be_bytes());
+	// RD, then one question
+	msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+	for label in name.trim_end_matches('.').split('.') {
+		msg.push(label.len() as u8);
+		msg.extend_from_slice(label.as_bytes());
+	}
+	msg.push(0);
+	msg.extend_from_slice(&kind.to_be_bytes());
+	msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+	msg
+}
+
+/// Name asked for in a DNS answer and the addresses it resolved to, with their TTLs. Addresses
+/// reached through CNAMEs are attributed to the name that was asked for.
+pub fn addresses(msg: &[u8]) -> Option<(String, Vec<(IpAddr, u32)>)> {
+	let mut r = Reader { msg, pos: 0 };
+	r.take(2)?;
+	let flags = r.u16()?;
+	// only responses without an error
+	if flags & 0x8000 == 0 || flags & 0x000f != 0 {
+		return None;
+	}
+	let questions = r.u16()?;
+	let answers = r.u16()?;
+	r.take(4)?;
+	if questions == 0 {
+		return None;
+	}
+	let name = r.question()?;
+	for _ in 1..questions {
+		r.question()?;
+	}
+
+	let mut addrs = Vec::new();
+	for _ in 0..answers {
+		r.name()?;
+		let kind = r.u16()?;
+		let class = r.u16()?;
+		let ttl = r.u32()?;
+		let len = r.u16()? as usize;
+		let data = r.take(len)?;
+		if class != CLASS_IN {
+			continue;
+		}
+		match (kind, data.len()) {
+			(TYPE_A, 4) => {
+				let octets: [u8; 4] = data.try_into().ok()?;
+				addrs.push((IpAddr::V4(Ipv4Addr::from(octets)), ttl));
+			}
+			(TYPE_AAAA, 16) => {
+				let octets: [u8; 16] = data.try_into().ok()?;
+				addrs.push((IpAddr::V6(Ipv6Addr::from(octets)), ttl));
+			}
+			_ => {}
+		}
+	}
+	Some((name, addrs))
+}
+
+struct Entry {
+	name: String,
+	expires: Instant,
+}
+
+#[derive(Default)]
+struct Entries {
+	by_addr: HashMap<IpAddr, Entry>,
+	/// The same addresses ordered by when they expire.
+	by_expiry: BTreeSet<(Instant, IpAddr)>,
+}
+
+impl Entries {
+	fn remove(&mut self, addr: &IpAddr) {
+		if let Some(entry) = self.by_addr.remove(addr) {
+			self.by_expiry.remove(&(entry.expires, *addr));
+		}
+	}
+
+	/// Drops the entry that expires first.
+	fn pop_first(&mut self) -> Option<(Instant, IpAddr)> {
+		let (expires, addr) = self.by_expiry.pop_first()?;
+		self.by_addr.remove(&addr);
+		Some((expires, addr))
+	}
+}
+
+/// Bounded map from addresses to the name they were looked up as.
+pub struct DnsCache {
+	capacity: usize,
+	entries: Mutex<Entries>,
+	hits: AtomicU64,
+	misses: AtomicU64,
+}
+
+impl DnsCache {
+	pub fn new(opts: &DnsOptions) -> Self {
+		Self {
+			capacity: opts.dns_cache_size,
+			entries: Mutex::new(Entries::default()),
+			hits: AtomicU64::new(0),
+			misses: AtomicU64::new(0),
+		}
+	}
+
+	/// Remembers the addresses of a DNS answer.
+	pub fn snoop(&self, msg: &[u8]) {
+		if self.capacity == 0 {
+			return;
+		}
+		let Some((name, addrs)) = addresses(msg) else {
+			return;
+		};
+		let now = Instant::now();
+		let mut entries = self.entries.lock().unwrap();
+		for (addr, ttl) in addrs {
+			entries.remove(&addr);
+			while entries.by_addr.len() >= self.capacity {
+				// expired entries go first since they expire before everything else
+				entries.pop_first();
+			}
+			let expires = now + Duration::from_secs(ttl.into()).max(MIN_TTL);
+			entries.by_expiry.insert((expires, addr));
+			entries.by_addr.insert(
+				addr,
+				Entry {
+					name: name.clone(),
+					expires,
+				},
+			);
+		}
+	}
+
+	/// Name `addr` was last looked up as, if it hasn't expired.
+	pub fn lookup(&self, addr: &IpAddr) -> Option<String> {
+		if self.capacity == 0 {
+			return None;
+		}
+		let mut entries = self.entries.lock().unwrap();
+		let now = Instant::now();
+		while entries
+			.by_expiry
+			.first()
+			.is_some_and(|(expires, _)| *expires <= now)
+		{
+			entries.pop_first();
+		}
+		let name = entries.by_addr.get(addr).map(|x| x.name.clone());
+		drop(entries);
+		match name {
+			Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+			None => self.misses.fetch_add(1, Ordering::Relaxed),
+		};
+		name
+	}
+
+	pub fn stats(&self) -> DnsCacheStats {
+		DnsCacheStats {
+			hits: self.hits.load(Ordering::Relaxed),
+			misses: self.misses.load(Ordering::Relaxed),
+			size: self.entries.lock().unwrap().by_addr.len(),
+		}
+	}
+}
+
+/// Snapshot of how well [`DnsCache`] is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DnsCacheStats {
+	/// Lookups that found a name.
+	pub hits: u64,
+	/// Lookups that didn't.
+	pub misses: u64,
+	/// Addresses remembered, including ones that expired since the last lookup.
+	pub size: usize,
+}
+
+impl Display for DnsCacheStats {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let lookups = self.hits + self.misses;
+		let rate = if lookups == 0 {
+			0.0
+		} else {
+			self.hits as f64 * 100.0 / lookups as f64
+		};
+		write!(
+			f,
+			"{} addresses, {} hits, {} misses ({:.1}% hit rate)",
+			self.size, self.hits, self.misses, rate
+		)
+	}
+}
+
+/// Logs the cache statistics periodically while there are lookups, until the cache is dropped.
+pub async fn log_stats(cache: Weak<DnsCache>) {
+	let mut interval = interval(STATS_INTERVAL);
+	interval.tick().await;
+	let mut last = None;
+	loop {
+		interval.tick().await;
+		let Some(cache) = cache.upgrade() else {
+			break;
+		};
+		if cache.capacity == 0 {
+			break;
+		}
+		let stats = cache.stats();
+		if last
+			.is_none_or(|last: DnsCacheStats| last.hits + last.misses != stats.hits + stats.misses)
+		{
+			info!("DNS cache: {}", stats);
+		}
+		last = Some(stats);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TYPE_CNAME: u16 = 5;
+
+	/// Owner name, type, TTL and data of a resource record.
+	type Record<'a> = (Option<&'a [u8]>, u16, u32, &'a [u8]);
+
+	/// Answer to `build_query(id, name, TYPE_A)` with records of `(name, kind, ttl, data)`,
+	/// where a `None` name points back at the question.
+	fn answer(name: &str, records: &[Record]) -> Vec<u8> {
+		let mut msg = build_query(7, name, TYPE_A);
+		msg[2] = 0x81;
+		msg[3] = 0x80;
+		msg[7] = records.len() as u8;
+		for (name, kind, ttl, data) in records {
+			msg.extend_from_slice(name.unwrap_or(&[0xc0, 12]));
+			msg.extend_from_slice(&kind.to_be_bytes());
+			msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+			msg.extend_from_slice(&ttl.to_be_bytes());
+			msg.extend_from_slice(&(data.len() as u16).to_be_bytes());
+			msg.extend_from_slice(data);
+		}
+		msg
+	}
+
+	fn v4(x: u8) -> IpAddr {
+		IpAddr::V4(Ipv4Addr::new(10, 0, 0, x))
+	}
+
+	#[test]
+	fn query_round_trip() {
+		let msg = build_query(1, "Example.COM.", TYPE_AAAA);
+		assert_eq!(
+			query(&msg),
+			Some(Question {
+				name: "example.com".into(),
+				kind: TYPE_AAAA,
+				end: msg.len(),
+			})
+		);
+		// answers, other opcodes and truncated queries aren't queries
+		assert_eq!(query(&answer("example.com", &[])), None);
+		let mut inverse = msg.clone();
+		inverse[2] |= 0x08;
+		assert_eq!(query(&inverse), None);
+		for len in 0..msg.len() {
+			assert_eq!(query(&msg[..len]), None);
+		}
+	}
+
+	#[test]
+	fn answer_addresses() {
+		// www.example.com CNAME example.com, written as "www" and a pointer into the question
+		let cname: &[u8] = &[3, b'w', b'w', b'w', 0xc0, 12];
+		let msg = answer(
+			"www.example.com",
+			&[
+				(None, TYPE_CNAME, 300, cname),
+				(Some(&[0xc0, 12]), TYPE_A, 120, &[10, 0, 0, 1]),
+				(None, TYPE_AAAA, 60, &Ipv6Addr::LOCALHOST.octets()),
+				// wrong length for an A record
+				(None, TYPE_A, 60, &[1, 2, 3]),
+			],
+		);
+		assert_eq!(
+			addresses(&msg),
+			Some((
+				"www.example.com".into(),
+				vec![(v4(1), 120), (IpAddr::V6(Ipv6Addr::LOCALHOST), 60)]
+			))
+		);
+		for len in 0..msg.len() {
+			assert_eq!(addresses(&msg[..len]), None);
+		}
+
+		// queries and errors have no addresses
+		assert_eq!(addresses(&build_query(1, "example.com", TYPE_A)), None);
+		let mut nxdomain = answer("example.com", &[]);
+		nxdomain[3] |= 3;
+		assert_eq!(addresses(&nxdomain), None);
+	}
+
+	#[test]
+	fn pointer_loops() {
+		// a name that points at itself
+		let msg = answer(
+			"example.com",
+			&[(Some(&[0xc0, 29]), TYPE_A, 60, &[10, 0, 0, 1])],
+		);
+		assert_eq!(msg[29..31], [0xc0, 29]);
+		assert_eq!(addresses(&msg), None);
+
+		// two names that point at each other
+		let mut msg = answer(
+			"example.com",
+			&[(Some(&[0xc0, 31]), TYPE_A, 60, &[10, 0, 0, 1])],
+		);
+		msg.truncate(29);
+		msg[7] = 1;
+		msg.extend_from_slice(&[0xc0, 31, 0xc0, 29]);
+		assert_eq!(addresses(&msg), None);
+
+		// pointers past the end and reserved label types
+		let msg = answer(
+			"example.com",
+			&[(Some(&[0xc0, 0xff]), TYPE_A, 60, &[10, 0, 0, 1])],
+		);
+		assert_eq!(addresses(&msg), None);
+		let msg = answer(
+			"example.com",
+			&[(Some(&[0x40, 12]), TYPE_A, 60, &[10, 0, 0, 1])],
+		);
+		assert_eq!(addresses(&msg), None);
+	}
+
+	#[test]
+	fn cache() {
+		let cache = DnsCache::new(&DnsOptions { dns_cache_size: 2 });
+		cache.snoop(&answer("a.com", &[(None, TYPE_A, 300, &[10, 0, 0, 1])]));
+		cache.snoop(&answer("b.com", &[(None, TYPE_A, 100, &[10, 0, 0, 2])]));
+		// a longer TTL for b moves it behind a
+		cache.snoop(&answer("b.com", &[(None, TYPE_A, 900, &[10, 0, 0, 2])]));
+		cache.snoop(&answer("c.com", &[(None, TYPE_A, 600, &[10, 0, 0, 3])]));
+		assert_eq!(cache.lookup(&v4(1)), None);
+		assert_eq!(cache.lookup(&v4(2)).as_deref(), Some("b.com"));
+		assert_eq!(cache.lookup(&v4(3)).as_deref(), Some("c.com"));
+		assert_eq!(
+			cache.stats(),
+			DnsCacheStats {
+				hits: 2,
+				misses: 1,
+				size: 2,
+			}
+		);
+		assert_eq!(
+			cache.stats().to_string(),
+			"2 addresses, 2 hits, 1 misses (66.7% hit rate)"
+		);
+
+		let disabled = DnsCache::new(&DnsOptions { dns_cache_size: 0 });
+		disabled.snoop(&answer("a.com", &[(None, TYPE_A, 300, &[10, 0, 0, 1])]));
+		assert_eq!(disabled.lookup(&v4(1)), None);
+		assert_eq!(disabled.stats().size, 0);
+	}
+}
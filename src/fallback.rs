@@ -0,0 +1,280 @@
+//! TCP-only mode for Wisp servers without UDP support.
+//!
+//! Wisp v1 servers and v2 servers that don't offer the UDP extension can only carry TCP. Instead of
+//! dropping UDP, whisper sends DNS queries to the same resolver over TCP and answers every other
+//! UDP datagram with an ICMP port unreachable, so apps like QUIC clients fall back to TCP right away
+//! instead of waiting for a timeout.
+
+use std::{
+	error::Error,
+	net::{IpAddr, SocketAddr},
+	time::Duration,
+};
+
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	time::timeout,
+};
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use wisp_mux::StreamType;
+
+use crate::pool::ServerPool;
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sends a DNS query over a TCP stream to the resolver it was meant for and returns the answer.
+pub async fn dns_over_tcp(
+	pool: &ServerPool,
+	dest: SocketAddr,
+	query: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+	let (stream, _guard) = pool
+		.new_stream(StreamType::Tcp, dest.ip().to_string(), dest.port())
+		.await?;
+	let mut stream = stream.into_io().into_asyncrw().compat();
+	let len = u16::try_from(query.len())?;
+	timeout(DNS_TIMEOUT, async {
+		let mut msg = Vec::with_capacity(query.len() + 2);
+		msg.extend_from_slice(&len.to_be_bytes());
+		msg.extend_from_slice(query);
+		stream.write_all(&msg).await?;
+		stream.flush().await?;
+		let len = stream.read_u16().await?;
+		let mut answer = vec![0; len as usize];
+		stream.read_exact(&mut answer).await?;
+		Ok(answer)
+	})
+	.await?
+}
+
+fn checksum(data: &[u8]) -> u16 {
+	let mut sum = 0u32;
+	for chunk in data.chunks(2) {
+		sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+	}
+	while sum > 0xffff {
+		sum = (sum & 0xffff) + (sum >> 16);
+	}
+	!(sum as u16)
+}
+
+fn udp_header(src: SocketAddr, dest: SocketAddr, len: usize) -> [u8; 8] {
+	let mut header = [0; 8];
+	header[0..2].copy_from_slice(&src.port().to_be_bytes());
+	header[2..4].copy_from_slice(&dest.port().to_be_bytes());
+	header[4..6].copy_from_slice(&((len + 8) as u16).to_be_bytes());
+	header
+}
+
+fn ipv4_header(src: [u8; 4], dest: [u8; 4], protocol: u8, payload_len: usize) -> [u8; 20] {
+	let mut header = [0; 20];
+	header[0] = 0x45;
+	header[2..4].copy_from_slice(&((payload_len + 20) as u16).to_be_bytes());
+	header[8] = 64;
+	header[9] = protocol;
+	header[12..16].copy_from_slice(&src);
+	header[16..20].copy_from_slice(&dest);
+	let sum = checksum(&header);
+	header[10..12].copy_from_slice(&sum.to_be_bytes());
+	header
+}
+
+fn ipv6_header(src: [u8; 16], dest: [u8; 16], next_header: u8, payload_len: usize) -> [u8; 40] {
+	let mut header = [0; 40];
+	header[0] = 0x60;
+	header[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+	header[6] = next_header;
+	header[7] = 64;
+	header[8..24].copy_from_slice(&src);
+	header[24..40].copy_from_slice(&dest);
+	header
+}
+
+/// ICMP port unreachable for a UDP datagram from `src` to `dest`, as an IP packet to write to the
+/// TUN device.
+pub fn port_unreachable(src: SocketAddr, dest: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+	match (src.ip(), dest.ip()) {
+		(IpAddr::V4(src_ip), IpAddr::V4(dest_ip)) => {
+			// the whole error has to fit in the minimum reassembly size
+			let payload = &payload[..payload.len().min(576 - 20 - 8 - 20 - 8)];
+			let mut icmp = vec![3, 3, 0, 0, 0, 0, 0, 0];
+			icmp.extend_from_slice(&ipv4_header(
+				src_ip.octets(),
+				dest_ip.octets(),
+				17,
+				payload.len() + 8,
+			));
+			icmp.extend_from_slice(&udp_header(src, dest, payload.len()));
+			icmp.extend_from_slice(payload);
+			let sum = checksum(&icmp);
+			icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+			let mut packet = ipv4_header(dest_ip.octets(), src_ip.octets(), 1, icmp.len()).to_vec();
+			packet.extend_from_slice(&icmp);
+			Some(packet)
+		}
+		(IpAddr::V6(src_ip), IpAddr::V6(dest_ip)) => {
+			let payload = &payload[..payload.len().min(1280 - 40 - 8 - 40 - 8)];
+			let mut icmp = vec![1, 4, 0, 0, 0, 0, 0, 0];
+			icmp.extend_from_slice(&ipv6_header(
+				src_ip.octets(),
+				dest_ip.octets(),
+				17,
+				payload.len() + 8,
+			));
+			icmp.extend_from_slice(&udp_header(src, dest, payload.len()));
+			icmp.extend_from_slice(payload);
+
+			let header = ipv6_header(dest_ip.octets(), src_ip.octets(), 58, icmp.len());
+			// pseudo-header: addresses, length and next header
+			let mut pseudo = header[8..40].to_vec();
+			pseudo.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+			pseudo.extend_from_slice(&[0, 0, 0, 58]);
+			pseudo.extend_from_slice(&icmp);
+			let sum = checksum(&pseudo);
+			icmp[2..4].copy_from_slice(&sum.to_be_bytes());
+
+			let mut packet = header.to_vec();
+			packet.extend_from_slice(&icmp);
+			Some(packet)
+		}
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio_util::compat::Compat;
+	use wisp_mux::{MuxStreamAsyncRW, ServerMux};
+
+	use super::*;
+	use crate::pool::tests::single;
+
+	fn v4(ip: [u8; 4], port: u16) -> SocketAddr {
+		(ip, port).into()
+	}
+
+	fn v6(last: u16, port: u16) -> SocketAddr {
+		([0xfd00, 0, 0, 0, 0, 0, 0, last], port).into()
+	}
+
+	/// Checksum of `icmp` with the IPv6 pseudo-header of `packet`.
+	fn icmpv6_checksum(packet: &[u8], icmp: &[u8]) -> u16 {
+		let mut pseudo = packet[8..40].to_vec();
+		pseudo.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+		pseudo.extend_from_slice(&[0, 0, 0, 58]);
+		pseudo.extend_from_slice(icmp);
+		checksum(&pseudo)
+	}
+
+	#[test]
+	fn ipv4() {
+		let src = v4([10, 0, 0, 2], 5000);
+		let dest = v4([1, 1, 1, 1], 443);
+		let packet = port_unreachable(src, dest, b"quic").unwrap();
+		assert_eq!(packet.len(), 20 + 8 + 20 + 8 + 4);
+
+		// from the destination back to the sender
+		let (header, icmp) = packet.split_at(20);
+		assert_eq!(checksum(header), 0);
+		assert_eq!(header[9], 1);
+		assert_eq!(
+			u16::from_be_bytes([header[2], header[3]]) as usize,
+			packet.len()
+		);
+		assert_eq!(header[12..16], [1, 1, 1, 1]);
+		assert_eq!(header[16..20], [10, 0, 0, 2]);
+
+		// port unreachable, quoting the datagram
+		assert_eq!(checksum(icmp), 0);
+		assert_eq!(icmp[..2], [3, 3]);
+		let (quoted, udp) = icmp[8..].split_at(20);
+		assert_eq!(checksum(quoted), 0);
+		assert_eq!(quoted[9], 17);
+		assert_eq!(quoted[12..16], [10, 0, 0, 2]);
+		assert_eq!(quoted[16..20], [1, 1, 1, 1]);
+		assert_eq!(udp[..6], [0x13, 0x88, 0x01, 0xbb, 0, 12]);
+		assert_eq!(&udp[8..], b"quic");
+	}
+
+	#[test]
+	fn ipv6() {
+		let src = v6(2, 5000);
+		let dest = v6(1, 443);
+		let packet = port_unreachable(src, dest, b"quic").unwrap();
+		assert_eq!(packet.len(), 40 + 8 + 40 + 8 + 4);
+
+		let (header, icmp) = packet.split_at(40);
+		assert_eq!(header[0], 0x60);
+		assert_eq!(header[6], 58);
+		assert_eq!(
+			u16::from_be_bytes([header[4], header[5]]) as usize,
+			icmp.len()
+		);
+		assert_eq!(header[23], 1);
+		assert_eq!(header[39], 2);
+
+		assert_eq!(icmpv6_checksum(&packet, icmp), 0);
+		assert_eq!(icmp[..2], [1, 4]);
+		let (quoted, udp) = icmp[8..].split_at(40);
+		assert_eq!(quoted[6], 17);
+		assert_eq!(quoted[23], 2);
+		assert_eq!(quoted[39], 1);
+		assert_eq!(udp[..6], [0x13, 0x88, 0x01, 0xbb, 0, 12]);
+		assert_eq!(&udp[8..], b"quic");
+	}
+
+	#[test]
+	fn truncates_quoted_payload() {
+		let payload = [0xaa; 2000];
+
+		let packet = port_unreachable(v4([10, 0, 0, 2], 1), v4([1, 1, 1, 1], 2), &payload).unwrap();
+		assert_eq!(packet.len(), 576);
+		assert_eq!(checksum(&packet[..20]), 0);
+		assert_eq!(checksum(&packet[20..]), 0);
+		// the quoted headers keep the length of what is quoted
+		assert_eq!(u16::from_be_bytes([packet[30], packet[31]]), 576 - 28);
+		assert_eq!(u16::from_be_bytes([packet[52], packet[53]]), 576 - 48);
+
+		let packet = port_unreachable(v6(2, 1), v6(1, 2), &payload).unwrap();
+		assert_eq!(packet.len(), 1280);
+		assert_eq!(icmpv6_checksum(&packet, &packet[40..]), 0);
+		assert_eq!(u16::from_be_bytes([packet[52], packet[53]]), 1280 - 88);
+	}
+
+	#[test]
+	fn mixed_families() {
+		assert!(port_unreachable(v4([10, 0, 0, 2], 1), v6(1, 2), b"").is_none());
+		assert!(port_unreachable(v6(2, 1), v4([1, 1, 1, 1], 2), b"").is_none());
+	}
+
+	/// Answers one length-prefixed DNS query on the next stream with the query reversed. Returns
+	/// where the stream went, and the stream itself so it stays open until the answer is read.
+	async fn resolver(server: &ServerMux) -> (String, Compat<MuxStreamAsyncRW>) {
+		let (connect, stream) = server.server_new_stream().await.unwrap();
+		let mut stream = stream.into_io().into_asyncrw().compat();
+		let len = stream.read_u16().await.unwrap();
+		let mut query = vec![0; len as usize];
+		stream.read_exact(&mut query).await.unwrap();
+		query.reverse();
+		stream.write_u16(query.len() as u16).await.unwrap();
+		stream.write_all(&query).await.unwrap();
+		stream.flush().await.unwrap();
+		let dest = format!(
+			"{}:{}",
+			connect.destination_hostname, connect.destination_port
+		);
+		(dest, stream)
+	}
+
+	#[tokio::test]
+	async fn dns_over_tcp() {
+		let (pool, mux) = single().await;
+		let (answer, (dest, _stream)) = tokio::join!(
+			super::dns_over_tcp(&pool, v4([1, 1, 1, 1], 53), b"query"),
+			resolver(&mux),
+		);
+		assert_eq!(answer.unwrap(), b"yreuq");
+		assert_eq!(dest, "1.1.1.1:53");
+	}
+}
@@ -1,12 +1,12 @@
 use std::{
-	ffi::{c_char, c_int, c_ushort, CStr, CString},
+	ffi::{c_char, c_int, c_long, c_ushort, CStr, CString},
 	net::SocketAddr,
+	path::PathBuf,
 	ptr,
 	sync::OnceLock,
 };
 
 use cfg_if::cfg_if;
-use hyper::Uri;
 use log::{info, LevelFilter};
 use tokio::{
 	runtime::{Builder, Runtime},
@@ -16,23 +16,28 @@ use tokio::{
 	},
 };
 use tun2::{create_as_async, AsyncDevice, Configuration};
-use wisp_mux::ClientMux;
 
 use crate::{
+	access::AccessOptions,
+	flow::FlowContext,
+	pool::{PoolOptions, ServerPool},
+	route::Router,
+	socket::set_protect,
 	start_whisper,
-	util::{connect_to_wisp, WhisperError},
+	util::WhisperError,
 	WhisperEvent, WispServer,
 };
 
 struct WhisperInitState {
-	mux: ClientMux,
+	pool: ServerPool,
 	tun: AsyncDevice,
 	mtu: u16,
-	socketaddr: SocketAddr,
+	socketaddr: Option<SocketAddr>,
 }
 
 struct WhisperRunningState {
-	socketaddr: SocketAddr,
+	pool: ServerPool,
+	socketaddr: Option<SocketAddr>,
 	channel: UnboundedSender<WhisperEvent>,
 }
 
@@ -69,8 +74,79 @@ pub extern "C" fn whisper_init_logging(app_name: *const c_char) -> bool {
 	}
 }
 
+/// Sets a callback that gets the fd of every socket to the Wisp server before it connects, so the
+/// app can keep it out of the tunnel (e.g. with `VpnService.protect` on Android). It returns false
+/// if it couldn't, which fails the connection, and may be called from any thread. Pass null to
+/// remove it. Call this before `whisper_init`.
+#[no_mangle]
+pub extern "C" fn whisper_set_protect_callback(callback: Option<extern "C" fn(c_int) -> bool>) {
+	set_protect(callback.map(|callback| Box::new(move |fd| callback(fd)) as _));
+}
+
 #[no_mangle]
 pub extern "C" fn whisper_init(fd: c_int, ws: *const c_char, mtu: c_ushort) -> bool {
+	init(fd, ws, mtu, WispServer::default())
+}
+
+/// Like `whisper_init`, but connects only once the first flow arrives and disconnects after
+/// `idle_timeout` seconds without open streams (0 keeps the connection open). The server is
+/// resolved again on every connect, so `whisper_get_ws_ip` returns the address resolved here until
+/// the first connect and the address last connected to after that. If it can't be resolved yet,
+/// e.g. because the network is still down, this succeeds anyway and `whisper_get_ws_ip` returns
+/// null until the first connect.
+#[no_mangle]
+pub extern "C" fn whisper_init_on_demand(
+	fd: c_int,
+	ws: *const c_char,
+	mtu: c_ushort,
+	idle_timeout: c_long,
+) -> bool {
+	init(
+		fd,
+		ws,
+		mtu,
+		WispServer {
+			pool: PoolOptions {
+				lazy: true,
+				idle_timeout: idle_timeout.max(0) as u64,
+				..Default::default()
+			},
+			..Default::default()
+		},
+	)
+}
+
+/// Like `whisper_init`, for a Wisp server behind Cloudflare Access. Pass the client ID and secret
+/// of a service token, a file with a CF_Authorization token, or both; unused ones may be null.
+#[no_mangle]
+pub extern "C" fn whisper_init_cf(
+	fd: c_int,
+	ws: *const c_char,
+	mtu: c_ushort,
+	client_id: *const c_char,
+	client_secret: *const c_char,
+	token_file: *const c_char,
+) -> bool {
+	let string = |x: *const c_char| {
+		(!x.is_null()).then(|| unsafe { CStr::from_ptr(x) }.to_string_lossy().to_string())
+	};
+	init(
+		fd,
+		ws,
+		mtu,
+		WispServer {
+			access: AccessOptions {
+				cf: true,
+				cf_client_id: string(client_id),
+				cf_client_secret: string(client_secret),
+				cf_token_file: string(token_file).map(PathBuf::from),
+			},
+			..Default::default()
+		},
+	)
+}
+
+fn init(fd: c_int, ws: *const c_char, mtu: c_ushort, opts: WispServer) -> bool {
 	let ws = unsafe {
 		if ws.is_null() {
 			return false;
@@ -85,25 +161,31 @@ pub extern "C" fn whisper_init(fd: c_int, ws: *const c_char, mtu: c_ushort) -> b
 				return Err(WhisperError::AlreadyInitialized);
 			}
 
-			let (mux, socketaddr) = connect_to_wisp(
+			let lazy = opts.pool.lazy;
+			let (pool, socketaddrs) = ServerPool::connect(
 				&WispServer {
-					pty: None,
-					url: Some(Uri::try_from(ws).map_err(WhisperError::other)?),
+					url: vec![ws.parse()?],
+					..opts
 				},
 				false,
 			)
 			.await
 			.map_err(WhisperError::Other)?;
+			// a --lazy pool resolves the server again on the first connect
+			let socketaddr = socketaddrs.first().copied();
+			if socketaddr.is_none() && !lazy {
+				return Err(WhisperError::NoSocketAddr);
+			}
 
 			let mut cfg = Configuration::default();
 			cfg.raw_fd(fd);
 			let tun = create_as_async(&cfg).map_err(WhisperError::other)?;
 
 			whisper.0.replace(WhisperInitState {
-				mux,
+				pool,
 				tun,
 				mtu,
-				socketaddr: socketaddr.ok_or(WhisperError::NoSocketAddr)?,
+				socketaddr,
 			});
 			info!("Initialized Whisper.");
 			Ok(())
@@ -119,13 +201,20 @@ pub extern "C" fn whisper_get_ws_ip() -> *mut c_char {
 	if let Ok(rt) = build_runtime!() {
 		let ip = rt.block_on(async {
 			let whisper = WHISPER.lock().await;
-			if let Some(init) = &whisper.0 {
-				CString::new(init.socketaddr.ip().to_string()).map_err(WhisperError::other)
+			let (pool, socketaddr) = if let Some(init) = &whisper.0 {
+				(&init.pool, init.socketaddr)
 			} else if let Some(running) = &whisper.1 {
-				CString::new(running.socketaddr.ip().to_string()).map_err(WhisperError::other)
+				(&running.pool, running.socketaddr)
 			} else {
-				Err(WhisperError::NotInitialized)
-			}
+				return Err(WhisperError::NotInitialized);
+			};
+			let socketaddr = pool
+				.health()
+				.first()
+				.and_then(|x| x.addr)
+				.or(socketaddr)
+				.ok_or(WhisperError::NoSocketAddr)?;
+			CString::new(socketaddr.ip().to_string()).map_err(WhisperError::other)
 		});
 		match ip {
 			Ok(ptr) => ptr.into_raw(),
@@ -136,6 +225,55 @@ pub extern "C" fn whisper_get_ws_ip() -> *mut c_char {
 	}
 }
 
+/// Last WebSocket round-trip time in milliseconds, or -1 if it isn't known yet.
+#[no_mangle]
+pub extern "C" fn whisper_get_ws_rtt() -> c_long {
+	if let Ok(rt) = build_runtime!() {
+		let rtt = rt.block_on(async {
+			let whisper = WHISPER.lock().await;
+			if let Some(init) = &whisper.0 {
+				Ok(init.pool.health().first().and_then(|x| x.rtt))
+			} else if let Some(running) = &whisper.1 {
+				Ok(running.pool.health().first().and_then(|x| x.rtt))
+			} else {
+				Err(WhisperError::NotInitialized)
+			}
+		});
+		match rtt {
+			Ok(Some(rtt)) => rtt.as_millis() as c_long,
+			_ => -1,
+		}
+	} else {
+		-1
+	}
+}
+
+/// 1 if a Wisp server that is up supports UDP, 0 if whisper runs TCP-only, or -1 if none is
+/// connected.
+#[no_mangle]
+pub extern "C" fn whisper_get_udp_supported() -> c_int {
+	if let Ok(rt) = build_runtime!() {
+		let health = rt.block_on(async {
+			let whisper = WHISPER.lock().await;
+			if let Some(init) = &whisper.0 {
+				Ok(init.pool.health())
+			} else if let Some(running) = &whisper.1 {
+				Ok(running.pool.health())
+			} else {
+				Err(WhisperError::NotInitialized)
+			}
+		});
+		match health {
+			Ok(health) if health.iter().any(|x| x.up) => {
+				health.iter().any(|x| x.up && x.udp) as c_int
+			}
+			_ => -1,
+		}
+	} else {
+		-1
+	}
+}
+
 #[no_mangle]
 pub extern "C" fn whisper_free(s: *mut c_char) {
 	unsafe {
@@ -155,20 +293,21 @@ pub extern "C" fn whisper_start() -> bool {
 				return Err(WhisperError::AlreadyStarted);
 			}
 			let WhisperInitState {
-				mux,
+				pool,
 				tun,
 				mtu,
 				socketaddr,
 			} = whisper.0.take().ok_or(WhisperError::NotInitialized)?;
 			let (channel, rx) = unbounded_channel();
 			whisper.1.replace(WhisperRunningState {
+				pool: pool.clone(),
 				channel,
 				socketaddr,
 			});
 			// unlock so other stuff can be called
 			drop(whisper);
 			info!("Starting Whisper...");
-			let ret = start_whisper(mux, tun, mtu, rx)
+			let ret = start_whisper(Router::new(pool), FlowContext::default(), tun, mtu, rx)
 				.await
 				.map_err(WhisperError::Other);
 			info!("Whisper finished with ret: {:?}", ret);
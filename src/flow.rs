@@ -0,0 +1,84 @@
+//! State shared by every flow.
+//!
+//! The [`Router`](crate::route::Router) picks the outbound of a flow. Everything else a flow looks
+//! at or updates on the way lives here: how TCP flows are sniffed for a hostname, the hostnames seen
+//! in DNS answers, the blocklists for DNS queries, and the clients that may use the tunnel.
+
+use std::{io, sync::Arc};
+
+use clap::Args;
+
+use crate::{
+	block::{BlockOptions, Blocklist},
+	clients::{ClientOptions, Clients},
+	dns::{DnsCache, DnsOptions},
+	sniff::SniffOptions,
+};
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct FlowOptions {
+	#[clap(flatten)]
+	pub sniff: SniffOptions,
+	#[clap(flatten)]
+	pub dns: DnsOptions,
+	#[clap(flatten)]
+	pub block: BlockOptions,
+	#[clap(flatten)]
+	pub clients: ClientOptions,
+}
+
+struct FlowContextInner {
+	sniff: SniffOptions,
+	names: Arc<DnsCache>,
+	blocklist: Blocklist,
+	clients: Clients,
+}
+
+/// State shared by the flows of [`start_whisper`](crate::start_whisper) and
+/// [`start_transparent`](crate::transparent::start_transparent).
+#[derive(Clone)]
+pub struct FlowContext(Arc<FlowContextInner>);
+
+impl FlowContext {
+	/// Loads the blocklists given in `opts`.
+	pub fn new(opts: &FlowOptions) -> Result<Self, io::Error> {
+		Ok(Self(Arc::new(FlowContextInner {
+			sniff: opts.sniff.clone(),
+			names: Arc::new(DnsCache::new(&opts.dns)),
+			blocklist: Blocklist::load(&opts.block)?,
+			clients: Clients::new(&opts.clients),
+		})))
+	}
+
+	/// How TCP flows are sniffed for a hostname.
+	pub fn sniff(&self) -> &SniffOptions {
+		&self.0.sniff
+	}
+
+	/// Hostnames of addresses seen in DNS answers.
+	pub fn names(&self) -> &Arc<DnsCache> {
+		&self.0.names
+	}
+
+	/// Blocklists checked for DNS queries.
+	pub fn blocklist(&self) -> &Blocklist {
+		&self.0.blocklist
+	}
+
+	/// Clients that may use the tunnel and their statistics.
+	pub fn clients(&self) -> &Clients {
+		&self.0.clients
+	}
+}
+
+impl Default for FlowContext {
+	/// No sniffing, blocklists or client restrictions.
+	fn default() -> Self {
+		Self(Arc::new(FlowContextInner {
+			sniff: SniffOptions::default(),
+			names: Arc::new(DnsCache::new(&DnsOptions::default())),
+			blocklist: Blocklist::default(),
+			clients: Clients::default(),
+		}))
+	}
+}
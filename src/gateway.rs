@@ -0,0 +1,200 @@
+//! Whisper as the gateway of a LAN.
+//!
+//! `whisper --tun whisper0 gateway --lan eth1` lets other devices use the box whisper runs on as
+//! their IPv4 gateway. Whisper enables IP forwarding and adds a policy routing rule that looks up
+//! packets arriving on `--lan` (from `--subnet` only, if given) in `--table`, whose default route
+//! is the TUN device. Whisper terminates every flow itself, so no NAT rules are needed. Traffic to
+//! the box itself still goes through the `local` table first, and the subnets of `--lan`, `--subnet`
+//! and every `--direct` network are thrown back to the `main` table, so they stay reachable
+//! directly. Everything else, including other private networks, goes through whisper.
+//!
+//! Whisper only routes IPv4, so IPv6 from the LAN is refused instead of being forwarded past it:
+//! the same rule for IPv6 looks up a table whose default route is `prohibit`, which makes clients
+//! fall back to IPv4 right away. IPv6 subnets of the LAN and `--direct` are thrown as well.
+//! Everything is restored when whisper exits.
+
+use std::{fs, io, path::Path};
+
+use log::{info, warn};
+
+use crate::{
+	route::Cidr,
+	transparent::{command, output, words},
+	GatewayOptions,
+};
+
+/// Value written to a sysctl, with the value it had before.
+struct Sysctl {
+	path: String,
+	old: String,
+}
+
+impl Sysctl {
+	fn set(path: String, value: &str) -> Result<Self, io::Error> {
+		let old = fs::read_to_string(&path)
+			.map_err(|x| io::Error::new(x.kind(), format!("{}: {}", path, x)))?;
+		fs::write(&path, value)
+			.map_err(|x| io::Error::new(x.kind(), format!("{}: {}", path, x)))?;
+		Ok(Self {
+			path,
+			old: old.trim().to_string(),
+		})
+	}
+}
+
+/// Forwarding and policy routing for a LAN, restored when dropped.
+pub struct Gateway {
+	sysctls: Vec<Sysctl>,
+	/// Commands that undo the routing, in the order they have to run.
+	undo: Vec<Vec<String>>,
+}
+
+/// Networks of the addresses in `ip -o addr show` output.
+fn subnets(addrs: &str) -> Vec<Cidr> {
+	let mut nets = Vec::new();
+	for line in addrs.lines() {
+		let mut words = line.split_whitespace();
+		while let Some(word) = words.next() {
+			if word == "inet" || word == "inet6" {
+				if let Some(net) = words.next().and_then(|x| x.parse::<Cidr>().ok()) {
+					nets.push(net.network());
+				}
+			}
+		}
+	}
+	nets
+}
+
+impl Gateway {
+	/// Routes the traffic of the LAN through the TUN device `tun`, which has to be up.
+	pub fn install(opts: &GatewayOptions, tun: &str) -> Result<Self, io::Error> {
+		let from = match &opts.subnet {
+			Some(subnet) => format!(" from {}", subnet),
+			None => String::new(),
+		};
+		// command, whether it's IPv4, rule and default route of each address family
+		let mut families = vec![(
+			"ip",
+			true,
+			format!("iif {}{} lookup {}", opts.lan, from, opts.table),
+			format!("default dev {}", tun),
+		)];
+		if Path::new("/proc/sys/net/ipv6").exists() {
+			families.push((
+				"ip -6",
+				false,
+				format!("iif {} lookup {}", opts.lan, opts.table),
+				"prohibit default".to_string(),
+			));
+		}
+
+		let mut direct: Vec<Cidr> = Vec::new();
+		for (ip, _, _, _) in &families {
+			let addrs = output(&words(&format!("{} -o addr show dev {}", ip, opts.lan)))?;
+			for net in subnets(&addrs) {
+				if !direct.contains(&net) {
+					direct.push(net);
+				}
+			}
+		}
+		for net in opts.subnet.iter().chain(&opts.direct).map(Cidr::network) {
+			if !direct.contains(&net) {
+				direct.push(net);
+			}
+		}
+
+		// leftovers of an earlier run
+		for (ip, _, rule, _) in &families {
+			let _ = command(&words(&format!("{} rule del {}", ip, rule)));
+			let _ = command(&words(&format!("{} route flush table {}", ip, opts.table)));
+		}
+
+		let mut gateway = Self {
+			sysctls: Vec::new(),
+			undo: Vec::new(),
+		};
+		gateway.sysctls.push(Sysctl::set(
+			"/proc/sys/net/ipv4/ip_forward".to_string(),
+			"1",
+		)?);
+		// replies come in on the TUN device, which the main table doesn't route to
+		gateway.sysctls.push(Sysctl::set(
+			format!("/proc/sys/net/ipv4/conf/{}/rp_filter", tun),
+			"2",
+		)?);
+
+		for (ip, v4, rule, default) in &families {
+			gateway
+				.undo
+				.push(words(&format!("{} route flush table {}", ip, opts.table)));
+			for net in direct.iter().filter(|x| x.is_ipv4() == *v4) {
+				command(&words(&format!(
+					"{} route add throw {} table {}",
+					ip, net, opts.table
+				)))?;
+			}
+			command(&words(&format!(
+				"{} route add {} table {}",
+				ip, default, opts.table
+			)))?;
+			command(&words(&format!("{} rule add {}", ip, rule)))?;
+			// the rules have to go before the tables are flushed
+			gateway
+				.undo
+				.insert(0, words(&format!("{} rule del {}", ip, rule)));
+		}
+
+		let direct = direct
+			.iter()
+			.map(|x| x.to_string())
+			.collect::<Vec<_>>()
+			.join(", ");
+		info!(
+			"Routing traffic from {}{} through {}, except to {}",
+			opts.lan, from, tun, direct
+		);
+		if families.len() > 1 {
+			info!("Refusing IPv6 traffic from {}", opts.lan);
+		}
+		Ok(gateway)
+	}
+}
+
+impl Drop for Gateway {
+	fn drop(&mut self) {
+		for args in &self.undo {
+			if let Err(err) = command(args) {
+				warn!("Failed to remove gateway routing: {}", err);
+			}
+		}
+		for sysctl in self.sysctls.iter().rev() {
+			if let Err(err) = fs::write(&sysctl.path, &sysctl.old) {
+				warn!("Failed to restore {}: {}", sysctl.path, err);
+			}
+		}
+		info!("Removed gateway routing");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lan_subnets() {
+		// `ip -o addr show dev eth1` with IPv4 and IPv6 addresses
+		let addrs = [
+			r"3: eth1    inet 192.168.1.1/24 brd 192.168.1.255 scope global eth1\       valid_lft forever preferred_lft forever",
+			r"3: eth1    inet 10.1.2.3/8 scope global secondary eth1\       valid_lft forever preferred_lft forever",
+			r"3: eth1    inet6 2001:db8::1/64 scope global \       valid_lft forever preferred_lft forever",
+			r"3: eth1    inet6 fe80::1234/64 scope link \       valid_lft forever preferred_lft forever",
+		]
+		.join("\n");
+		assert_eq!(
+			subnets(&addrs),
+			["192.168.1.0/24", "10.0.0.0/8", "2001:db8::/64", "fe80::/64"]
+				.map(|x| x.parse::<Cidr>().unwrap())
+		);
+		assert_eq!(subnets(""), Vec::new());
+	}
+}
@@ -0,0 +1,540 @@
+//! Pre-connect helpers.
+//!
+//! Some servers are only reachable through a local helper such as `cloudflared access tcp` or
+//! `ssh -L`, which listens on a local port and carries the connection to the server. With
+//! `--helper`, whisper runs the command, waits until its port accepts connections and then dials
+//! that port instead of the server. The TLS and WebSocket handshakes are unchanged, so the URL
+//! scheme should match what the helper forwards to.
+//!
+//! The command template is split on whitespace (no shell quoting) and these placeholders are
+//! replaced in every argument:
+//!
+//! - `{listen}`: local address the helper should listen on, `127.0.0.1:{port}`
+//! - `{port}`: local port the helper should listen on
+//! - `{host}`: host of the Wisp server
+//! - `{url}`: URL of the Wisp server
+//!
+//! Without `--helper-port` a new free port is picked for every start, so a port that was taken in
+//! the meantime only costs a restart. Such a port only counts as ready once its listener belongs to
+//! the helper or a process it started, so another program that took the port isn't mistaken for
+//! the helper. That is checked in `/proc`; elsewhere `--helper-port` is required. A port given with
+//! `--helper-port`, or one whose owner can't be checked because `/proc/net` isn't readable (e.g. on
+//! Android 10 and later), is ready once it accepts connections.
+//!
+//! The helper's output goes to the logs. It is restarted with exponential backoff when it exits or
+//! isn't ready within `--helper-ready-timeout` seconds, and killed when the server is dropped. After
+//! [`MAX_FAILED_STARTS`] starts in a row that never got ready, or if the command doesn't exist, the
+//! helper is given up on and connecting through it fails.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::collections::{HashMap, HashSet};
+use std::{
+	io,
+	net::{Ipv4Addr, SocketAddr, TcpListener},
+	process::Stdio,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use clap::Args;
+use log::{error, info, warn};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncRead, BufReader},
+	net::TcpStream,
+	process::{Child, Command},
+	select,
+	sync::watch,
+	time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{util::WhisperError, WispServer, WispUrl};
+
+/// How often the helper's port is probed while it starts.
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+/// A helper that was up for this long starts over with the shortest backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+/// Starts in a row that never got ready after which the helper is given up on.
+pub const MAX_FAILED_STARTS: u32 = 5;
+
+#[derive(Debug, Clone, Args)]
+pub struct HelperOptions {
+	/// Command that opens a local tunnel to the Wisp server, e.g. "cloudflared access tcp --hostname {host} --listener {listen}"
+	#[arg(long, conflicts_with = "via")]
+	pub helper: Option<String>,
+	/// Local port for the helper to listen on. Picked automatically by default.
+	#[arg(long, requires = "helper")]
+	pub helper_port: Option<u16>,
+	/// Seconds to wait for the helper's port to accept connections
+	#[arg(long, default_value_t = 15)]
+	pub helper_ready_timeout: u64,
+	/// Longest delay in seconds between restarts of the helper
+	#[arg(long, default_value_t = 30)]
+	pub helper_max_backoff: u64,
+}
+
+impl Default for HelperOptions {
+	fn default() -> Self {
+		Self {
+			helper: None,
+			helper_port: None,
+			helper_ready_timeout: 15,
+			helper_max_backoff: 30,
+		}
+	}
+}
+
+/// Values for the placeholders of the command template.
+#[derive(Debug, Clone)]
+struct Template {
+	command: String,
+	host: String,
+	url: String,
+}
+
+impl Template {
+	fn render(&self, port: u16) -> Vec<String> {
+		self.command
+			.split_whitespace()
+			.map(|arg| {
+				arg.replace("{listen}", &format!("127.0.0.1:{}", port))
+					.replace("{port}", &port.to_string())
+					.replace("{host}", &self.host)
+					.replace("{url}", &self.url)
+			})
+			.collect()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+	Starting,
+	/// Address of the helper's listener
+	Ready(SocketAddr),
+	/// Given up on, with the last error
+	Failed(String),
+}
+
+#[derive(Debug)]
+struct HelperInner {
+	name: String,
+	opts: HelperOptions,
+	state: watch::Receiver<State>,
+	shutdown: CancellationToken,
+}
+
+impl Drop for HelperInner {
+	fn drop(&mut self) {
+		self.shutdown.cancel();
+	}
+}
+
+/// Running helper. The helper is stopped once every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct Helper(Arc<HelperInner>);
+
+impl Helper {
+	/// Starts the helper for the server in `opts` and keeps it running.
+	pub fn start(opts: &WispServer) -> Result<Self, WhisperError> {
+		let command = opts.helper.helper.clone().ok_or(WhisperError::NoHelper)?;
+		if !cfg!(any(target_os = "linux", target_os = "android"))
+			&& opts.helper.helper_port.is_none()
+		{
+			return Err(WhisperError::HelperPortRequired);
+		}
+		let (host, url) = match opts.url.first() {
+			Some(WispUrl::Uri(url)) => (
+				url.host().ok_or(WhisperError::UriHasNoHost)?.to_string(),
+				url.to_string(),
+			),
+			Some(url @ WispUrl::Tcp { host, .. }) => (host.clone(), url.to_string()),
+			_ => return Err(WhisperError::HelperNotSupported),
+		};
+		let template = Template { command, host, url };
+		let name = template
+			.command
+			.split_whitespace()
+			.next()
+			.unwrap_or("helper")
+			.to_string();
+
+		let (state_tx, state) = watch::channel(State::Starting);
+		let shutdown = CancellationToken::new();
+		tokio::spawn(supervise(
+			name.clone(),
+			opts.helper.clone(),
+			template,
+			state_tx,
+			shutdown.clone(),
+		));
+		Ok(Self(Arc::new(HelperInner {
+			name,
+			opts: opts.helper.clone(),
+			state,
+			shutdown,
+		})))
+	}
+
+	/// Waits until the helper accepts connections and returns its address.
+	pub async fn ready(&self) -> Result<SocketAddr, WhisperError> {
+		let mut state = self.0.state.clone();
+		let wait = state.wait_for(|x| *x != State::Starting);
+		let state = match timeout(Duration::from_secs(self.0.opts.helper_ready_timeout), wait).await
+		{
+			Ok(Ok(state)) => state.clone(),
+			_ => State::Starting,
+		};
+		match state {
+			State::Ready(addr) => Ok(addr),
+			State::Failed(reason) => Err(WhisperError::HelperFailed(self.0.name.clone(), reason)),
+			State::Starting => Err(WhisperError::HelperNotReady(
+				self.0.name.clone(),
+				self.0.opts.helper_ready_timeout,
+			)),
+		}
+	}
+
+	/// Whether the helper was given up on.
+	pub fn failed(&self) -> bool {
+		matches!(*self.0.state.borrow(), State::Failed(_))
+	}
+}
+
+fn free_port() -> Result<u16, io::Error> {
+	Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+		.local_addr()?
+		.port())
+}
+
+fn log_lines(name: String, output: impl AsyncRead + Unpin + Send + 'static) {
+	tokio::spawn(async move {
+		let mut lines = BufReader::new(output).lines();
+		while let Ok(Some(line)) = lines.next_line().await {
+			info!("[{}] {}", name, line);
+		}
+	});
+}
+
+fn spawn(name: &str, args: &[String]) -> Result<Child, io::Error> {
+	let (program, args) = args
+		.split_first()
+		.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+	let mut child = Command::new(program)
+		.args(args)
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn()?;
+	if let Some(stdout) = child.stdout.take() {
+		log_lines(name.to_string(), stdout);
+	}
+	if let Some(stderr) = child.stderr.take() {
+		log_lines(name.to_string(), stderr);
+	}
+	Ok(child)
+}
+
+/// Inodes of the sockets listening on `port` in a `/proc/net/tcp` or `/proc/net/tcp6` table.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn listening_inodes(table: &str, port: u16) -> HashSet<u64> {
+	table
+		.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let fields: Vec<_> = line.split_whitespace().collect();
+			let (_, local_port) = fields.get(1)?.rsplit_once(':')?;
+			// 0A is TCP_LISTEN
+			(u16::from_str_radix(local_port, 16).ok()? == port && *fields.get(3)? == "0A")
+				.then(|| fields.get(9)?.parse().ok())
+				.flatten()
+		})
+		.collect()
+}
+
+/// `pid` and every process it started, directly or not.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn process_tree(pid: u32) -> Vec<u32> {
+	let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+	for entry in std::fs::read_dir("/proc").into_iter().flatten().flatten() {
+		let Some(child) = entry.file_name().to_str().and_then(|x| x.parse().ok()) else {
+			continue;
+		};
+		// the command name in parentheses may contain spaces, the parent follows the state
+		let Some(parent) = std::fs::read_to_string(entry.path().join("stat"))
+			.ok()
+			.and_then(|x| {
+				x.rsplit_once(')')?
+					.1
+					.split_whitespace()
+					.nth(1)?
+					.parse()
+					.ok()
+			})
+		else {
+			continue;
+		};
+		children.entry(parent).or_default().push(child);
+	}
+	let mut tree = vec![pid];
+	let mut i = 0;
+	while let Some(pid) = tree.get(i) {
+		tree.extend(children.get(pid).into_iter().flatten());
+		i += 1;
+	}
+	tree
+}
+
+/// Whether the listener on `port` belongs to `pid` or a process it started, or `None` if the
+/// socket tables in `/proc` can't be read.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn owns_listener(pid: u32, port: u16) -> Option<bool> {
+	let tables: Vec<_> = ["/proc/net/tcp", "/proc/net/tcp6"]
+		.iter()
+		.filter_map(|x| std::fs::read_to_string(x).ok())
+		.collect();
+	if tables.is_empty() {
+		return None;
+	}
+	let inodes: HashSet<u64> = tables
+		.iter()
+		.flat_map(|x| listening_inodes(x, port))
+		.collect();
+	if inodes.is_empty() {
+		return Some(false);
+	}
+	let owned = process_tree(pid).into_iter().any(|pid| {
+		std::fs::read_dir(format!("/proc/{}/fd", pid))
+			.into_iter()
+			.flatten()
+			.flatten()
+			.filter_map(|x| std::fs::read_link(x.path()).ok())
+			.filter_map(|x| {
+				x.to_str()?
+					.strip_prefix("socket:[")?
+					.strip_suffix(']')?
+					.parse()
+					.ok()
+			})
+			.any(|x: u64| inodes.contains(&x))
+	});
+	Some(owned)
+}
+
+/// Without `/proc` the port has to be given with `--helper-port`, see [`Helper::start`].
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn owns_listener(_pid: u32, _port: u16) -> Option<bool> {
+	None
+}
+
+/// Probes the helper's port until the helper accepts connections on it. With `check_owner`, the
+/// listener has to belong to the helper where that can be checked. Fails if the helper exits
+/// first.
+async fn probe(
+	name: &str,
+	child: &mut Child,
+	addr: SocketAddr,
+	check_owner: bool,
+) -> Result<(), String> {
+	let mut warned = false;
+	loop {
+		if let Ok(Some(status)) = child.try_wait() {
+			return Err(format!("exited before it was ready ({})", status));
+		}
+		if TcpStream::connect(addr).await.is_ok() {
+			let owned = match child.id() {
+				Some(pid) if check_owner => owns_listener(pid, addr.port()),
+				Some(_) => None,
+				None => Some(false),
+			};
+			match owned {
+				None | Some(true) => return Ok(()),
+				Some(false) if !warned => {
+					warn!(
+						"Port {} accepts connections, but not from helper {}. Waiting for the helper.",
+						addr.port(),
+						name
+					);
+					warned = true;
+				}
+				Some(false) => {}
+			}
+		}
+		sleep(PROBE_INTERVAL).await;
+	}
+}
+
+async fn supervise(
+	name: String,
+	opts: HelperOptions,
+	template: Template,
+	state: watch::Sender<State>,
+	shutdown: CancellationToken,
+) {
+	let max_backoff = Duration::from_secs(opts.helper_max_backoff.max(1));
+	let ready_timeout = Duration::from_secs(opts.helper_ready_timeout);
+	let mut backoff = Duration::from_secs(1);
+	let mut failed_starts = 0;
+	loop {
+		let started = Instant::now();
+		let mut ready = false;
+		let mut missing = false;
+		let result = match opts.helper_port.map_or_else(free_port, Ok) {
+			Ok(port) => {
+				let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+				let args = template.render(port);
+				info!("Starting helper: {}", args.join(" "));
+				let check_owner = opts.helper_port.is_none();
+				match spawn(&name, &args) {
+					Ok(mut child) => {
+						let probe =
+							timeout(ready_timeout, probe(&name, &mut child, addr, check_owner));
+						select! {
+							x = probe => match x.unwrap_or_else(|_| Err(format!(
+								"didn't accept connections within {}s",
+								opts.helper_ready_timeout
+							))) {
+								Ok(()) => {
+									info!("Helper {} is ready on {}", name, addr);
+									ready = true;
+									state.send_replace(State::Ready(addr));
+									select! {
+										x = child.wait() => Err(match x {
+											Ok(status) => format!("exited ({})", status),
+											Err(err) => err.to_string(),
+										}),
+										_ = shutdown.cancelled() => Ok(child),
+									}
+								}
+								Err(err) => Err(err),
+							},
+							_ = shutdown.cancelled() => Ok(child),
+						}
+					}
+					Err(err) => {
+						missing = err.kind() == io::ErrorKind::NotFound;
+						Err(format!("failed to start: {}", err))
+					}
+				}
+			}
+			Err(err) => Err(format!("no free port: {}", err)),
+		};
+		state.send_replace(State::Starting);
+
+		match result {
+			Ok(mut child) => {
+				let _ = child.kill().await;
+				info!("Stopped helper {}", name);
+				return;
+			}
+			Err(err) => {
+				failed_starts = if ready { 0 } else { failed_starts + 1 };
+				if missing || failed_starts >= MAX_FAILED_STARTS {
+					error!("Helper {} {}, giving up", name, err);
+					state.send_replace(State::Failed(err));
+					return;
+				}
+				if started.elapsed() >= STABLE_AFTER {
+					backoff = Duration::from_secs(1);
+				}
+				warn!(
+					"Helper {} {}, restarting in {}s",
+					name,
+					err,
+					backoff.as_secs()
+				);
+			}
+		}
+		select! {
+			_ = sleep(backoff) => {}
+			_ = shutdown.cancelled() => return,
+		}
+		backoff = (backoff * 2).min(max_backoff);
+	}
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn listening_inodes_of_port() {
+		let table = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0 100 0 0 10 0
+   1: 0100007F:1F90 0100007F:D2A4 01 00000000:00000000 00:00000000 00000000  1000        0 4343 1 0 20 4 30 10 -1
+   2: 00000000:0050 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 4444 1 0 100 0 0 10 0
+";
+		assert_eq!(listening_inodes(table, 8080), HashSet::from([4242]));
+		assert_eq!(listening_inodes(table, 80), HashSet::from([4444]));
+		assert!(listening_inodes(table, 443).is_empty());
+	}
+
+	#[test]
+	fn listener_owner() {
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		let port = listener.local_addr().unwrap().port();
+		assert_eq!(owns_listener(std::process::id(), port), Some(true));
+
+		let mut other = std::process::Command::new("sleep")
+			.arg("10")
+			.spawn()
+			.unwrap();
+		assert_eq!(owns_listener(other.id(), port), Some(false));
+		other.kill().unwrap();
+		other.wait().unwrap();
+
+		drop(listener);
+		assert_eq!(owns_listener(std::process::id(), port), Some(false));
+	}
+
+	fn helper(command: &str, port: Option<u16>) -> Helper {
+		Helper::start(&WispServer {
+			url: vec!["tcp://wisp.example:6001".parse().unwrap()],
+			helper: HelperOptions {
+				helper: Some(command.to_string()),
+				helper_port: port,
+				helper_ready_timeout: 2,
+				helper_max_backoff: 1,
+			},
+			..Default::default()
+		})
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn given_port_is_ready_once_it_accepts() {
+		// the listener isn't the helper's, but the port was given explicitly
+		let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+		let addr = listener.local_addr().unwrap();
+		let helper = helper("sleep 10", Some(addr.port()));
+		assert_eq!(helper.ready().await.unwrap(), addr);
+	}
+
+	#[tokio::test]
+	async fn gives_up_on_missing_command() {
+		let helper = helper("/nonexistent/helper {listen}", None);
+		assert!(matches!(
+			helper.ready().await,
+			Err(WhisperError::HelperFailed(name, _)) if name == "/nonexistent/helper"
+		));
+		assert!(helper.failed());
+	}
+
+	#[tokio::test]
+	async fn gives_up_after_failed_starts() {
+		let helper = helper("false {listen}", None);
+		assert!(!helper.failed());
+		timeout(Duration::from_secs(15), async {
+			while !helper.failed() {
+				sleep(PROBE_INTERVAL).await;
+			}
+		})
+		.await
+		.unwrap();
+		assert!(matches!(
+			helper.ready().await,
+			Err(WhisperError::HelperFailed(_, reason)) if reason.starts_with("exited before it was ready")
+		));
+	}
+}
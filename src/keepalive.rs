@@ -0,0 +1,352 @@
+//! WebSocket keepalive.
+//!
+//! Half-dead WebSocket connections (NAT timeouts, mobile networks switching) can go unnoticed for
+//! minutes because nothing is sent while the tunnel is idle. Whisper pings the server on an
+//! interval and declares the connection dead if the pong doesn't arrive in time. The time between
+//! ping and pong is kept as the connection's round-trip time.
+
+use std::{
+	io,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use clap::Args;
+use log::debug;
+use tokio::{
+	sync::watch,
+	time::{sleep, timeout},
+};
+use wisp_mux::{
+	ws::{Frame, LockedWebSocketWrite, OpCode, Payload, WebSocketRead},
+	WispError,
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct KeepaliveOptions {
+	/// Seconds between WebSocket pings. 0 disables pings.
+	#[arg(long, default_value_t = 30)]
+	pub ws_ping_interval: u64,
+	/// Seconds to wait for the pong before the connection is considered dead
+	#[arg(long, default_value_t = 10)]
+	pub ws_pong_timeout: u64,
+}
+
+impl Default for KeepaliveOptions {
+	fn default() -> Self {
+		Self {
+			ws_ping_interval: 30,
+			ws_pong_timeout: 10,
+		}
+	}
+}
+
+impl KeepaliveOptions {
+	pub fn enabled(&self) -> bool {
+		self.ws_ping_interval != 0
+	}
+}
+
+/// Last measured round-trip time of the connection.
+#[derive(Debug, Default)]
+pub struct RttMetric(AtomicU64);
+
+impl RttMetric {
+	/// `None` until the first pong arrives.
+	pub fn get(&self) -> Option<Duration> {
+		match self.0.load(Ordering::Relaxed) {
+			0 => None,
+			micros => Some(Duration::from_micros(micros)),
+		}
+	}
+
+	fn set(&self, rtt: Duration) {
+		self.0
+			.store((rtt.as_micros() as u64).max(1), Ordering::Relaxed);
+	}
+}
+
+/// State shared between [`KeepaliveRead`] and [`ping_loop`].
+pub struct Keepalive {
+	opts: KeepaliveOptions,
+	rtt: Arc<RttMetric>,
+	/// Ping currently waiting for its pong.
+	pending: Mutex<Option<(u64, Instant)>>,
+	/// Id of the last ping that was answered.
+	answered: watch::Sender<u64>,
+	/// Write half of the mux, picked up from the first read.
+	tx: watch::Sender<Option<LockedWebSocketWrite>>,
+}
+
+impl Keepalive {
+	pub fn new(opts: KeepaliveOptions, rtt: Arc<RttMetric>) -> Arc<Self> {
+		Arc::new(Self {
+			opts,
+			rtt,
+			pending: Mutex::new(None),
+			answered: watch::Sender::new(0),
+			tx: watch::Sender::new(None),
+		})
+	}
+
+	fn pong(&self, payload: &[u8]) {
+		let Ok(id) = payload.try_into().map(u64::from_be_bytes) else {
+			return;
+		};
+		let mut pending = self.pending.lock().unwrap();
+		if let Some((_, sent)) = pending.take_if(|(pending, _)| *pending == id) {
+			let rtt = sent.elapsed();
+			debug!("WebSocket round-trip time: {:?}", rtt);
+			self.rtt.set(rtt);
+			self.answered.send_replace(id);
+		}
+	}
+}
+
+/// Consumes pong frames so they don't reach the mux, which only accepts binary frames.
+pub struct KeepaliveRead<R: WebSocketRead + Send> {
+	rx: R,
+	keepalive: Arc<Keepalive>,
+}
+
+impl<R: WebSocketRead + Send> KeepaliveRead<R> {
+	pub fn new(rx: R, keepalive: Arc<Keepalive>) -> Self {
+		Self { rx, keepalive }
+	}
+}
+
+#[async_trait]
+impl<R: WebSocketRead + Send> WebSocketRead for KeepaliveRead<R> {
+	async fn wisp_read_frame(
+		&mut self,
+		tx: &LockedWebSocketWrite,
+	) -> Result<Frame<'static>, WispError> {
+		if self.keepalive.tx.borrow().is_none() {
+			self.keepalive.tx.send_replace(Some(tx.clone()));
+		}
+		loop {
+			let frame = self.rx.wisp_read_frame(tx).await?;
+			if frame.opcode == OpCode::Pong {
+				self.keepalive.pong(&frame.payload);
+				continue;
+			}
+			return Ok(frame);
+		}
+	}
+}
+
+fn pong_timeout_error(secs: u64) -> WispError {
+	WispError::WsImplError(Box::new(io::Error::new(
+		io::ErrorKind::TimedOut,
+		format!("no pong from the server within {}s", secs),
+	)))
+}
+
+/// Pings the server until a pong times out. Runs next to the mux future; if it returns, the
+/// connection is dead.
+pub async fn ping_loop(keepalive: Arc<Keepalive>) -> Result<(), WispError> {
+	let opts = &keepalive.opts;
+	if !opts.enabled() {
+		return std::future::pending().await;
+	}
+	let interval = Duration::from_secs(opts.ws_ping_interval);
+	let pong_timeout = Duration::from_secs(opts.ws_pong_timeout);
+
+	let mut tx = keepalive.tx.subscribe();
+	let tx = tx
+		.wait_for(Option::is_some)
+		.await
+		.map_err(|_| WispError::WsImplSocketClosed)?
+		.clone()
+		.expect("write half is set");
+	let mut answered = keepalive.answered.subscribe();
+
+	for id in 1.. {
+		sleep(interval).await;
+		keepalive
+			.pending
+			.lock()
+			.unwrap()
+			.replace((id, Instant::now()));
+		// a write to a dead connection can block as well once the socket buffer fills up
+		timeout(pong_timeout, async {
+			tx.write_frame(Frame {
+				finished: true,
+				opcode: OpCode::Ping,
+				payload: Payload::Bytes(BytesMut::from(&id.to_be_bytes()[..])),
+			})
+			.await?;
+			answered
+				.wait_for(|x| *x == id)
+				.await
+				.map_err(|_| WispError::WsImplSocketClosed)?;
+			Ok::<(), WispError>(())
+		})
+		.await
+		.map_err(|_| pong_timeout_error(opts.ws_pong_timeout))??;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+	use wisp_mux::ws::WebSocketWrite;
+
+	use super::*;
+
+	struct ChannelRead(UnboundedReceiver<Frame<'static>>);
+
+	#[async_trait]
+	impl WebSocketRead for ChannelRead {
+		async fn wisp_read_frame(
+			&mut self,
+			_: &LockedWebSocketWrite,
+		) -> Result<Frame<'static>, WispError> {
+			self.0.recv().await.ok_or(WispError::WsImplSocketClosed)
+		}
+	}
+
+	struct ChannelWrite(UnboundedSender<(OpCode, Vec<u8>)>);
+
+	#[async_trait]
+	impl WebSocketWrite for ChannelWrite {
+		async fn wisp_write_frame(&mut self, frame: Frame<'_>) -> Result<(), WispError> {
+			self.0
+				.send((frame.opcode, frame.payload.to_vec()))
+				.map_err(|_| WispError::WsImplSocketClosed)
+		}
+
+		async fn wisp_close(&mut self) -> Result<(), WispError> {
+			Ok(())
+		}
+	}
+
+	fn frame(opcode: OpCode, payload: &[u8]) -> Frame<'static> {
+		Frame {
+			finished: true,
+			opcode,
+			payload: Payload::Bytes(BytesMut::from(payload)),
+		}
+	}
+
+	fn keepalive(ws_ping_interval: u64) -> Arc<Keepalive> {
+		Keepalive::new(
+			KeepaliveOptions {
+				ws_ping_interval,
+				ws_pong_timeout: 10,
+			},
+			Arc::default(),
+		)
+	}
+
+	#[test]
+	fn pongs_match_pings() {
+		let keepalive = keepalive(30);
+		keepalive
+			.pending
+			.lock()
+			.unwrap()
+			.replace((2, Instant::now()));
+
+		// pongs for other pings or with other payloads are ignored
+		keepalive.pong(&1u64.to_be_bytes());
+		keepalive.pong(b"2");
+		assert_eq!(keepalive.rtt.get(), None);
+		assert_eq!(*keepalive.answered.borrow(), 0);
+
+		keepalive.pong(&2u64.to_be_bytes());
+		assert!(keepalive.rtt.get().is_some());
+		assert_eq!(*keepalive.answered.borrow(), 2);
+		assert!(keepalive.pending.lock().unwrap().is_none());
+
+		// a repeated pong changes nothing
+		keepalive.rtt.set(Duration::from_secs(5));
+		keepalive.pong(&2u64.to_be_bytes());
+		assert_eq!(keepalive.rtt.get(), Some(Duration::from_secs(5)));
+	}
+
+	#[test]
+	fn rtt() {
+		let rtt = RttMetric::default();
+		assert_eq!(rtt.get(), None);
+		rtt.set(Duration::from_millis(25));
+		assert_eq!(rtt.get(), Some(Duration::from_millis(25)));
+		// too fast to measure still counts as measured
+		rtt.set(Duration::ZERO);
+		assert_eq!(rtt.get(), Some(Duration::from_micros(1)));
+	}
+
+	#[tokio::test]
+	async fn read_consumes_pongs() {
+		let keepalive = keepalive(30);
+		let (server, rx) = unbounded_channel();
+		let (tx, _) = unbounded_channel();
+		let tx = LockedWebSocketWrite::new(Box::new(ChannelWrite(tx)));
+		let mut read = KeepaliveRead::new(ChannelRead(rx), keepalive.clone());
+
+		server
+			.send(frame(OpCode::Pong, &1u64.to_be_bytes()))
+			.unwrap();
+		server.send(frame(OpCode::Binary, b"wisp")).unwrap();
+		let frame = read.wisp_read_frame(&tx).await.unwrap();
+		assert_eq!(frame.opcode, OpCode::Binary);
+		assert_eq!(&frame.payload[..], b"wisp");
+		assert!(keepalive.tx.borrow().is_some());
+	}
+
+	/// Runs [`ping_loop`] against a server that answers the first `answers` pings, returning how
+	/// long the loop ran and how it ended.
+	async fn ping(answers: usize) -> (Duration, Result<(), WispError>) {
+		let keepalive = keepalive(30);
+		let (server, rx) = unbounded_channel();
+		let (tx, mut pings) = unbounded_channel();
+		let tx = LockedWebSocketWrite::new(Box::new(ChannelWrite(tx)));
+		let mut read = KeepaliveRead::new(ChannelRead(rx), keepalive.clone());
+		// the mux reading frames
+		tokio::spawn(async move { while read.wisp_read_frame(&tx).await.is_ok() {} });
+		tokio::spawn(async move {
+			let mut answered = 0;
+			while let Some((opcode, payload)) = pings.recv().await {
+				assert_eq!(opcode, OpCode::Ping);
+				if answered < answers {
+					answered += 1;
+					server.send(frame(OpCode::Pong, &payload)).unwrap();
+				}
+			}
+		});
+
+		let start = tokio::time::Instant::now();
+		let result = ping_loop(keepalive.clone()).await;
+		assert_eq!(keepalive.rtt.get().is_some(), answers > 0);
+		(start.elapsed(), result)
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn pong_timeout() {
+		let (elapsed, result) = ping(0).await;
+		assert_eq!(elapsed, Duration::from_secs(40));
+		match result.unwrap_err() {
+			WispError::WsImplError(err) => {
+				assert_eq!(err.to_string(), "no pong from the server within 10s")
+			}
+			err => panic!("{:?}", err),
+		}
+
+		// the connection stays up while pongs arrive
+		let (elapsed, result) = ping(3).await;
+		assert_eq!(elapsed, Duration::from_secs(4 * 30 + 10));
+		assert!(result.is_err());
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn disabled() {
+		let result = timeout(Duration::from_secs(3600), ping_loop(keepalive(0))).await;
+		assert!(result.is_err());
+	}
+}
@@ -1,6 +1,29 @@
 #![feature(once_cell_try, let_chains)]
+pub mod access;
+pub mod block;
+pub mod clients;
+pub mod codec;
+pub mod compress;
+pub mod deflate;
+pub mod dns;
+pub mod fallback;
 mod ffi;
-mod pty;
+pub mod flow;
+#[cfg(target_os = "linux")]
+pub mod gateway;
+pub mod helper;
+pub mod keepalive;
+pub mod mux;
+#[cfg(target_os = "linux")]
+pub mod netns;
+pub mod noise;
+pub mod pool;
+pub mod pty;
+pub mod route;
+pub mod sniff;
+pub mod socket;
+#[cfg(target_os = "linux")]
+pub mod transparent;
 pub mod util;
 
 #[cfg(all(feature = "native-tls", feature = "rustls"))]
@@ -10,30 +33,48 @@ use dashmap::DashMap;
 use futures_util::{
 	future::select_all, stream::SplitSink, Future, Sink, SinkExt, Stream, StreamExt,
 };
-use log::{error, info};
+use log::{debug, error, info};
 use lwip::NetStack;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
 
 use std::{
 	error::Error,
-	net::{Ipv4Addr, SocketAddr},
+	fmt::Display,
+	io,
+	net::{IpAddr, Ipv4Addr, SocketAddr},
 	path::PathBuf,
 	pin::Pin,
+	str::FromStr,
 	sync::Arc,
 	task::Poll,
 	time::Duration,
 };
 
-use clap::{Args, Parser};
+use access::AccessOptions;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use codec::{Framing, DEFAULT_MAX_FRAME_LENGTH};
+use compress::CompressionOptions;
+use deflate::WsDeflateOptions;
+use fallback::{dns_over_tcp, port_unreachable};
+use flow::{FlowContext, FlowOptions};
+use helper::{Helper, HelperOptions};
 use hyper::Uri;
+use keepalive::KeepaliveOptions;
+use noise::NoiseOptions;
+use pool::PoolOptions;
+use route::{Cidr, RouteOptions, Router};
+use sniff::sniff;
+use socket::SocketOptions;
 use tokio::{
-	io::copy_bidirectional,
-	sync::mpsc::UnboundedReceiver,
+	io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt},
+	select,
+	sync::mpsc::{unbounded_channel, UnboundedReceiver},
 	task::JoinError,
 	time::{Instant, Sleep},
 };
 use tun2::AsyncDevice;
-use wisp_mux::{ClientMux, MuxStreamIo, StreamType};
+use util::WhisperError;
+use wisp_mux::{MuxStreamIo, StreamType};
 
 /// Wisp client that exposes the Wisp connection over a TUN device.
 #[derive(Debug, Parser)]
@@ -41,9 +82,15 @@ use wisp_mux::{ClientMux, MuxStreamIo, StreamType};
 pub struct Cli {
 	#[clap(flatten)]
 	pub wisp: WispServer,
-	/// Name of created TUN device
+	#[clap(flatten)]
+	pub route: RouteOptions,
+	#[clap(flatten)]
+	pub flow: FlowOptions,
+	#[command(subcommand)]
+	pub mode: Option<Mode>,
+	/// Name of created TUN device. Required unless running `exec` or `transparent`.
 	#[arg(short, long)]
-	pub tun: String,
+	pub tun: Option<String>,
 	/// MTU of created TUN device
 	#[arg(short, long, default_value_t = u16::MAX)]
 	pub mtu: u16,
@@ -56,23 +103,302 @@ pub struct Cli {
 	// Destination of created TUN device (defaults to 0.0.0.0)
 	#[arg(short, long, default_value = "0.0.0.0")]
 	pub dest: Ipv4Addr,
-	// Use cloudflared access. URL must be specified. You must be logged into cloudflared.
-	#[arg(short, long)]
-	pub cf: bool,
 	// Use wisp v2.
 	#[arg(long)]
 	pub wisp_v2: bool,
 }
 
-#[derive(Debug, Args)]
-#[group(required = true, multiple = false)]
+#[derive(Debug, Subcommand)]
+pub enum Mode {
+	/// Run a command in a new network namespace whose only route is whisper (Linux only)
+	Exec(ExecOptions),
+	/// Accept flows diverted by iptables REDIRECT or TPROXY instead of creating a TUN device (Linux only)
+	Transparent(TransparentOptions),
+	/// Route the traffic of other devices on a LAN through the TUN device (Linux only)
+	Gateway(GatewayOptions),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ExecOptions {
+	/// DNS server the command uses, reached through whisper
+	#[arg(long, default_value = "1.1.1.1")]
+	pub dns: IpAddr,
+	/// Command to run and its arguments
+	#[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+	pub command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TransparentMethod {
+	/// TCP sent to whisper by iptables REDIRECT
+	Redirect,
+	/// TCP and UDP sent to whisper by iptables TPROXY
+	Tproxy,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TransparentOptions {
+	/// How flows are diverted to whisper
+	#[arg(long, value_enum, default_value_t = TransparentMethod::Redirect)]
+	pub method: TransparentMethod,
+	/// Address to accept diverted flows on. Its family picks between iptables and ip6tables.
+	#[arg(long, default_value = "0.0.0.0:12345")]
+	pub listen: SocketAddr,
+	/// Install the firewall rules for the method on start and remove them on exit
+	#[arg(long)]
+	pub firewall: bool,
+	/// Firewall mark of packets diverted by TPROXY
+	#[arg(long, default_value_t = 1, value_parser = socket::parse_mark)]
+	pub tproxy_mark: u32,
+	/// Routing table that delivers packets diverted by TPROXY locally
+	#[arg(long, default_value_t = 100)]
+	pub tproxy_table: u32,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GatewayOptions {
+	/// LAN interface whose traffic goes through whisper
+	#[arg(long)]
+	pub lan: String,
+	/// Only route traffic from this subnet of the LAN (e.g. 192.168.1.0/24)
+	#[arg(long)]
+	pub subnet: Option<Cidr>,
+	/// Send traffic to this network past whisper, like the subnets of the LAN itself. Can be given multiple times.
+	#[arg(long)]
+	pub direct: Vec<Cidr>,
+	/// Routing table for traffic from the LAN
+	#[arg(long, default_value_t = 200)]
+	pub table: u32,
+}
+
+#[derive(Debug, Clone, Args)]
+#[group(skip)]
+#[command(group = ArgGroup::new("transport").required(true).multiple(false))]
 pub struct WispServer {
 	/// Path to PTY device
-	#[arg(short, long)]
+	#[arg(short, long, group = "transport")]
 	pub pty: Option<PathBuf>,
-	/// Wisp server URL
-	#[arg(short, long)]
-	pub url: Option<Uri>,
+	#[clap(flatten)]
+	pub serial: SerialOptions,
+	/// Speak length-delimited Wisp over our own stdin/stdout
+	#[arg(long, group = "transport")]
+	pub stdio: bool,
+	/// Spawn a command (through `sh -c`) and speak length-delimited Wisp over its stdin/stdout
+	#[arg(long, group = "transport")]
+	pub command: Option<String>,
+	/// Wisp server URL (ws://, wss://, ws+unix:///path.sock[:/path], unix:///path.sock or tcp://host:port). Can be given multiple times.
+	#[arg(short, long, group = "transport", value_parser = |x: &str| x.parse::<WispUrl>().map_err(|x| x.to_string()))]
+	pub url: Vec<WispUrl>,
+	/// Reach the server through a TCP stream over this Wisp server. Give it multiple times for longer chains, outermost first.
+	#[arg(long, requires = "url", value_parser = |x: &str| x.parse::<WispUrl>().map_err(|x| x.to_string()))]
+	pub via: Vec<WispUrl>,
+	/// Largest Wisp frame accepted on length-delimited transports (PTY, stdio, command, unix://, tcp://), not counting compression and encryption overhead
+	#[arg(long, default_value_t = DEFAULT_MAX_FRAME_LENGTH)]
+	pub max_frame_length: usize,
+	#[clap(flatten)]
+	pub noise: NoiseOptions,
+	#[clap(flatten)]
+	pub compression: CompressionOptions,
+	#[clap(flatten)]
+	pub ws_deflate: WsDeflateOptions,
+	#[clap(flatten)]
+	pub keepalive: KeepaliveOptions,
+	#[clap(flatten)]
+	pub socket: SocketOptions,
+	#[clap(flatten)]
+	pub pool: PoolOptions,
+	#[clap(flatten)]
+	pub access: AccessOptions,
+	#[clap(flatten)]
+	pub helper: HelperOptions,
+	/// Helper that is already running for this server. [`ServerPool`](pool::ServerPool) starts
+	/// one per server from [`HelperOptions`] if this is empty.
+	#[arg(skip)]
+	pub helper_handle: Option<Helper>,
+}
+
+impl Default for WispServer {
+	fn default() -> Self {
+		Self {
+			pty: None,
+			serial: SerialOptions::default(),
+			stdio: false,
+			command: None,
+			url: Vec::new(),
+			via: Vec::new(),
+			max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+			noise: NoiseOptions::default(),
+			compression: CompressionOptions::default(),
+			ws_deflate: WsDeflateOptions::default(),
+			keepalive: KeepaliveOptions::default(),
+			socket: SocketOptions::default(),
+			pool: PoolOptions::default(),
+			access: AccessOptions::default(),
+			helper: HelperOptions::default(),
+			helper_handle: None,
+		}
+	}
+}
+
+impl WispServer {
+	/// Options for each server when `--url` is given more than once.
+	pub fn split_servers(&self) -> Vec<WispServer> {
+		if self.url.len() <= 1 {
+			return vec![self.clone()];
+		}
+		self.url
+			.iter()
+			.map(|url| WispServer {
+				url: vec![url.clone()],
+				..self.clone()
+			})
+			.collect()
+	}
+
+	/// Short name of the transport for logs.
+	pub fn describe(&self) -> String {
+		if let Some(pty) = &self.pty {
+			format!("pty:{}", pty.display())
+		} else if self.stdio {
+			"stdio".to_string()
+		} else if let Some(command) = &self.command {
+			format!("command:{}", command)
+		} else if self.via.is_empty() {
+			join(&self.url)
+		} else {
+			format!("{} via {}", join(&self.url), join(&self.via))
+		}
+	}
+}
+
+/// Line settings applied to the PTY device. Unset options are left as they are after `cfmakeraw`.
+#[derive(Debug, Clone, Args)]
+pub struct SerialOptions {
+	/// Baud rate of the serial line
+	#[arg(long)]
+	pub baud: Option<u32>,
+	/// Data bits per character
+	#[arg(long, value_parser = clap::value_parser!(u8).range(5..=8))]
+	pub data_bits: Option<u8>,
+	/// Parity of the serial line
+	#[arg(long, value_enum)]
+	pub parity: Option<Parity>,
+	/// Stop bits of the serial line
+	#[arg(long, value_parser = clap::value_parser!(u8).range(1..=2))]
+	pub stop_bits: Option<u8>,
+	/// Flow control of the serial line
+	#[arg(long, value_enum)]
+	pub flow_control: Option<FlowControl>,
+	/// Framing of Wisp frames on the PTY
+	#[arg(long, value_enum, default_value_t = Framing::Length)]
+	pub framing: Framing,
+	/// Times to try reopening the PTY after it hangs up, 0 to disable
+	#[arg(long, default_value_t = 30)]
+	pub reopen_attempts: u32,
+	/// Seconds to wait between PTY reopen attempts
+	#[arg(long, default_value_t = 1)]
+	pub reopen_delay: u64,
+}
+
+impl Default for SerialOptions {
+	fn default() -> Self {
+		Self {
+			baud: None,
+			data_bits: None,
+			parity: None,
+			stop_bits: None,
+			flow_control: None,
+			framing: Framing::Length,
+			reopen_attempts: 30,
+			reopen_delay: 1,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Parity {
+	None,
+	Odd,
+	Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FlowControl {
+	None,
+	/// Hardware flow control
+	RtsCts,
+	/// Software flow control
+	XonXoff,
+}
+
+/// Wisp server address. The transport is selected by the URL scheme.
+#[derive(Debug, Clone)]
+pub enum WispUrl {
+	/// WebSocket URL, `ws://` or `wss://`. `--cf` also takes `http://` and `https://` here.
+	Uri(Uri),
+	/// WebSocket handshake over a Unix socket, `ws+unix:///path/to.sock[:/http/path]`. A socket
+	/// path containing `:/` needs the HTTP path to be given.
+	UnixWebSocket { socket: PathBuf, path: String },
+	/// Length-delimited Wisp frames over a Unix socket, `unix:///path/to.sock`.
+	Unix(PathBuf),
+	/// Length-delimited Wisp frames over TCP, `tcp://host:port`.
+	Tcp { host: String, port: u16 },
+}
+
+impl FromStr for WispUrl {
+	type Err = WhisperError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(rest) = s.strip_prefix("ws+unix://") {
+			// the HTTP path starts with '/', so a ':' elsewhere is part of the socket path
+			let (socket, path) = match rest.rsplit_once(":/") {
+				Some((socket, path)) => (socket, format!("/{}", path)),
+				None => (rest, "/".to_string()),
+			};
+			if socket.is_empty() {
+				return Err(WhisperError::UriHasNoPath);
+			}
+			Ok(Self::UnixWebSocket {
+				socket: socket.into(),
+				path,
+			})
+		} else if let Some(socket) = s.strip_prefix("unix://") {
+			if socket.is_empty() {
+				return Err(WhisperError::UriHasNoPath);
+			}
+			Ok(Self::Unix(socket.into()))
+		} else {
+			let uri = Uri::try_from(s).map_err(WhisperError::other)?;
+			if uri.scheme_str() == Some("tcp") {
+				Ok(Self::Tcp {
+					host: uri.host().ok_or(WhisperError::UriHasNoHost)?.to_string(),
+					port: uri.port_u16().ok_or(WhisperError::UriHasNoPort)?,
+				})
+			} else {
+				Ok(Self::Uri(uri))
+			}
+		}
+	}
+}
+
+fn join(urls: &[WispUrl]) -> String {
+	urls.iter()
+		.map(|x| x.to_string())
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+impl Display for WispUrl {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Uri(uri) => write!(f, "{}", uri),
+			Self::UnixWebSocket { socket, path } => {
+				write!(f, "ws+unix://{}:{}", socket.display(), path)
+			}
+			Self::Unix(socket) => write!(f, "unix://{}", socket.display()),
+			Self::Tcp { host, port } => write!(f, "tcp://{}:{}", host, port),
+		}
+	}
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -145,28 +471,250 @@ impl<S: for<'a> Sink<&'a [u8], Error = std::io::Error>> Sink<Vec<u8>> for Timeou
 	}
 }
 
+/// Destination of a flow for logs, with its hostname if known.
+fn flow(dest: SocketAddr, name: Option<String>) -> String {
+	match name {
+		Some(name) => format!("{:?} ({})", dest, name),
+		None => format!("{:?}", dest),
+	}
+}
+
 type TimeoutMuxStreamSink = SplitSink<TimeoutStreamSink<MuxStreamIo>, Vec<u8>>;
 
+/// Forwards a TCP flow to `dest` over the outbound `router` picks for it.
+pub(crate) async fn forward_tcp<S: AsyncRead + AsyncWrite + Unpin>(
+	router: Router,
+	context: FlowContext,
+	mut stream: S,
+	src: SocketAddr,
+	dest: SocketAddr,
+) {
+	if !context.clients().allowed(src.ip()) {
+		debug!("refused tcp from {:?}: client not allowed", src);
+		return;
+	}
+	let (sniffed, name) = if context.sniff().sniff {
+		sniff(&mut stream, context.sniff()).await
+	} else {
+		(Vec::new(), None)
+	};
+	let flow = flow(
+		dest,
+		name.clone().or_else(|| context.names().lookup(&dest.ip())),
+	);
+	let outbound = router.route(&dest);
+	let (mut wisp_stream, _guard) = match outbound
+		.pool
+		.new_stream(
+			StreamType::Tcp,
+			name.unwrap_or_else(|| dest.ip().to_string()),
+			dest.port(),
+		)
+		.await
+	{
+		Ok((stream, guard)) => (stream.into_io().into_asyncrw().compat(), guard),
+		Err(err) => {
+			error!("error while connecting tcp to {}: {:?}", flow, err);
+			return;
+		}
+	};
+	info!("connected tcp: {} via {}", flow, outbound.name);
+	context.clients().tcp_flow(src.ip());
+	if let Err(err) = wisp_stream.write_all(&sniffed).await {
+		error!("error while forwarding tcp to {}: {:?}", flow, err);
+		return;
+	}
+	match copy_bidirectional(&mut stream, &mut wisp_stream).await {
+		Ok((sent, received)) => {
+			let sent = sent + sniffed.len() as u64;
+			context.clients().sent(src.ip(), sent);
+			context.clients().received(src.ip(), received);
+			info!(
+				"disconnected tcp: {}, sent {} bytes, received {} bytes",
+				flow, sent, received
+			)
+		}
+		Err(err) => {
+			error!("error while forwarding tcp to {}: {:?}", flow, err);
+			info!("disconnected tcp: {}", flow);
+		}
+	}
+}
+
+/// Sends a datagram back to the client of a UDP flow.
+pub(crate) type UdpReply = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+/// Open UDP flows by source and destination.
+type UdpFlows = Arc<DashMap<(SocketAddr, SocketAddr), TimeoutMuxStreamSink>>;
+
+/// Forwards the datagrams of one UDP listener and keeps track of its open flows.
+pub(crate) struct UdpForwarder {
+	router: Router,
+	context: FlowContext,
+	flows: UdpFlows,
+}
+
+impl UdpForwarder {
+	pub(crate) fn new(router: Router, context: FlowContext) -> Self {
+		Self {
+			router,
+			context,
+			flows: Arc::new(DashMap::new()),
+		}
+	}
+
+	/// Forwards a datagram from `src` to `dest` over the outbound the router picks for it. `reply`
+	/// opens the way back to the client, and `refuse` gets datagrams the Wisp server can't carry.
+	pub(crate) async fn forward(
+		&self,
+		pkt: Vec<u8>,
+		src: SocketAddr,
+		dest: SocketAddr,
+		reply: impl FnOnce() -> Result<UdpReply, io::Error>,
+		refuse: impl FnOnce(&[u8]),
+	) {
+		if !self.context.clients().allowed(src.ip()) {
+			debug!("refused udp from {:?}: client not allowed", src);
+			refuse(&pkt);
+			return;
+		}
+		if dest.port() == 53 {
+			if let Some(answer) = self.context.blocklist().check(&pkt) {
+				match reply() {
+					Ok(reply) => reply(&answer),
+					Err(err) => error!("error while answering dns from {:?}: {:?}", src, err),
+				}
+				return;
+			}
+		}
+		if let Some(mut stream) = self.flows.get_mut(&(src, dest)) {
+			self.context.clients().sent(src.ip(), pkt.len() as u64);
+			if let Err(err) = stream.send(pkt).await {
+				error!("error while sending udp packet to {}: {:?}", dest, err);
+				drop(stream);
+				self.flows.remove(&(src, dest));
+			}
+			return;
+		}
+		let outbound = self.router.route(&dest);
+		match outbound
+			.pool
+			.new_stream(StreamType::Udp, dest.ip().to_string(), dest.port())
+			.await
+		{
+			Ok((wisp_stream, guard)) => {
+				let reply = match reply() {
+					Ok(reply) => reply,
+					Err(err) => {
+						error!("error while connecting udp from {:?}: {:?}", src, err);
+						return;
+					}
+				};
+				let flow = flow(dest, self.context.names().lookup(&dest.ip()));
+				info!("connected udp: {} via {}", flow, outbound.name);
+				self.context.clients().udp_flow(src.ip());
+				self.context.clients().sent(src.ip(), pkt.len() as u64);
+
+				let (wisp_w, mut wisp_r) = TimeoutStreamSink::new(wisp_stream.into_io()).split();
+				self.flows.insert((src, dest), wisp_w);
+
+				let stream_map = self.flows.clone();
+				let stream_context = self.context.clone();
+				tokio::spawn(async move {
+					while let Some(Ok(pkt)) = wisp_r.next().await {
+						if dest.port() == 53 {
+							stream_context.names().snoop(&pkt);
+						}
+						stream_context
+							.clients()
+							.received(src.ip(), pkt.len() as u64);
+						reply(&pkt);
+					}
+					info!("disconnected udp: {}", flow);
+					stream_map.remove(&(src, dest));
+					drop(guard);
+				});
+			}
+			Err(WhisperError::UdpNotSupported) if dest.port() == 53 => {
+				let reply = match reply() {
+					Ok(reply) => reply,
+					Err(err) => {
+						error!("error while answering dns from {:?}: {:?}", src, err);
+						return;
+					}
+				};
+				self.context.clients().sent(src.ip(), pkt.len() as u64);
+				let pool = outbound.pool.clone();
+				let stream_context = self.context.clone();
+				tokio::spawn(async move {
+					match dns_over_tcp(&pool, dest, &pkt).await {
+						Ok(answer) => {
+							stream_context.names().snoop(&answer);
+							stream_context
+								.clients()
+								.received(src.ip(), answer.len() as u64);
+							reply(&answer);
+						}
+						Err(err) => {
+							error!("error while sending dns to {:?} over tcp: {:?}", dest, err)
+						}
+					}
+				});
+			}
+			Err(WhisperError::UdpNotSupported) => {
+				debug!("refused udp: {:?}, server has no UDP support", dest);
+				refuse(&pkt);
+			}
+			Err(err) => error!("error while connecting udp to {:?}: {:?}", dest, err),
+		}
+	}
+}
+
 pub async fn start_whisper(
-	mux: ClientMux,
+	router: Router,
+	context: FlowContext,
 	tun: AsyncDevice,
 	mtu: u16,
-	mut channel: UnboundedReceiver<WhisperEvent>,
+	channel: UnboundedReceiver<WhisperEvent>,
 ) -> Result<(), Box<dyn Error>> {
+	start_whisper_on(router, context, tun.into_framed(), mtu, channel).await
+}
+
+/// [`start_whisper`] on any stream and sink of IP packets, such as a TUN device created elsewhere.
+pub async fn start_whisper_on<T>(
+	router: Router,
+	context: FlowContext,
+	tun: T,
+	mtu: u16,
+	mut channel: UnboundedReceiver<WhisperEvent>,
+) -> Result<(), Box<dyn Error>>
+where
+	T: Stream<Item = Result<Vec<u8>, io::Error>>
+		+ Sink<Vec<u8>, Error = io::Error>
+		+ Send
+		+ 'static,
+{
 	let (stack, mut tcp_listener, udp_socket) = NetStack::with_buffer_size(mtu.into(), 64)?;
-	let (mut tun_tx, mut tun_rx) = tun.into_framed().split();
+	let (mut tun_tx, mut tun_rx) = tun.split();
 	let (mut stack_tx, mut stack_rx) = stack.split();
 	let (udp_write, mut udp_read) = udp_socket.split();
 	let udp_write = Arc::new(udp_write);
-
-	let mux = Arc::new(mux);
+	// packets whisper makes up itself, like ICMP errors in TCP-only mode
+	let (icmp_tx, mut icmp_rx) = unbounded_channel();
+	tokio::spawn(dns::log_stats(Arc::downgrade(context.names())));
 
 	let read_handle: Pin<Box<dyn Future<Output = Result<(), JoinError>>>> =
 		Box::pin(tokio::spawn(async move {
-			while let Some(pkt) = stack_rx.next().await {
-				if let Ok(pkt) = pkt {
-					tun_tx.send(pkt).await.unwrap();
-				}
+			loop {
+				let pkt = select! {
+					pkt = stack_rx.next() => match pkt {
+						Some(Ok(pkt)) => pkt,
+						Some(Err(_)) => continue,
+						None => break,
+					},
+					Some(pkt) = icmp_rx.recv() => pkt,
+				};
+				tun_tx.send(pkt).await.unwrap();
 			}
 		}));
 
@@ -179,63 +727,37 @@ pub async fn start_whisper(
 			}
 		}));
 
-	let tcp_mux = mux.clone();
+	let tcp_router = router.clone();
+	let tcp_context = context.clone();
 	let tcp_handle: Pin<Box<dyn Future<Output = Result<(), JoinError>>>> =
 		Box::pin(tokio::spawn(async move {
-			while let Some((mut stream, _src, dest)) = tcp_listener.next().await {
-				let stream_mux = tcp_mux.clone();
-				tokio::spawn(async move {
-					let mut wisp_stream = stream_mux
-						.client_new_stream(StreamType::Tcp, dest.ip().to_string(), dest.port())
-						.await
-						.unwrap()
-						.into_io()
-						.into_asyncrw()
-						.compat();
-					drop(stream_mux);
-					info!("connected tcp: {:?}", dest);
-					if let Err(err) = copy_bidirectional(&mut stream, &mut wisp_stream).await {
-						error!("error while forwarding tcp to {:?}: {:?}", dest, err);
-					}
-					info!("disconnected tcp: {:?}", dest);
-				});
+			while let Some((stream, src, dest)) = tcp_listener.next().await {
+				tokio::spawn(forward_tcp(
+					tcp_router.clone(),
+					tcp_context.clone(),
+					stream,
+					src,
+					dest,
+				));
 			}
 		}));
 
-	let udp_mux = mux.clone();
+	let udp = UdpForwarder::new(router.clone(), context);
 	let udp_handle: Pin<Box<dyn Future<Output = Result<(), JoinError>>>> =
 		Box::pin(tokio::spawn(async move {
-			let udp_map: Arc<DashMap<(SocketAddr, SocketAddr), TimeoutMuxStreamSink>> =
-				Arc::new(DashMap::new());
-
 			while let Some((pkt, src, dest)) = udp_read.next().await {
-				if let Some(mut stream) = udp_map.get_mut(&(src, dest)) {
-					if let Err(err) = stream.send(pkt).await {
-						error!("error while sending udp packet to {}: {:?}", dest, err);
-						drop(stream);
-						udp_map.remove(&(src, dest));
-					}
-				} else if let Ok(wisp_stream) = udp_mux
-					.client_new_stream(StreamType::Udp, dest.ip().to_string(), dest.port())
-					.await
-				{
-					info!("connected udp: {:?}", dest);
-
+				let reply = || {
 					let udp_channel = udp_write.clone();
-
-					let (wisp_w, mut wisp_r) =
-						TimeoutStreamSink::new(wisp_stream.into_io()).split();
-					udp_map.insert((src, dest), wisp_w);
-
-					let stream_map = udp_map.clone();
-					tokio::spawn(async move {
-						while let Some(Ok(pkt)) = wisp_r.next().await {
-							udp_channel.send_to(&pkt, &dest, &src).unwrap();
-						}
-						info!("disconnected udp: {:?}", dest);
-						stream_map.remove(&(src, dest));
-					});
-				}
+					Ok(Arc::new(move |pkt: &[u8]| {
+						udp_channel.send_to(pkt, &dest, &src).unwrap();
+					}) as UdpReply)
+				};
+				let refuse = |pkt: &[u8]| {
+					if let Some(icmp) = port_unreachable(src, dest, pkt) {
+						let _ = icmp_tx.send(icmp);
+					}
+				};
+				udp.forward(pkt, src, dest, reply, refuse).await;
 			}
 		}));
 
@@ -244,6 +766,12 @@ pub async fn start_whisper(
 			channel.recv().await;
 		}));
 
+	let closed_router = router.clone();
+	let closed_handle: Pin<Box<dyn Future<Output = Result<(), JoinError>>>> =
+		Box::pin(tokio::spawn(async move {
+			closed_router.closed().await;
+		}));
+
 	info!("Whisper ready!");
 
 	select_all(&mut [
@@ -252,10 +780,113 @@ pub async fn start_whisper(
 		tcp_handle,
 		udp_handle,
 		channel_handle,
+		closed_handle,
 	])
 	.await
 	.0?;
 
 	info!("Broke from whisper loop.");
+	if let Some(err) = router.error() {
+		return Err(Box::new(err));
+	}
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn round_trip(s: &str) -> WispUrl {
+		let url: WispUrl = s.parse().unwrap();
+		assert_eq!(url.to_string(), s);
+		url
+	}
+
+	#[test]
+	fn split_servers() {
+		let opts = WispServer {
+			url: vec![
+				"ws://a.example.com/".parse().unwrap(),
+				"ws://b.example.com/".parse().unwrap(),
+			],
+			via: vec!["ws://hop.example.com/".parse().unwrap()],
+			..Default::default()
+		};
+		let servers = opts.split_servers();
+		assert_eq!(
+			servers.iter().map(|x| x.describe()).collect::<Vec<_>>(),
+			[
+				"ws://a.example.com/ via ws://hop.example.com/",
+				"ws://b.example.com/ via ws://hop.example.com/"
+			]
+		);
+
+		// a single server or other transports stay as they are
+		let opts = WispServer {
+			url: vec!["ws://a.example.com/".parse().unwrap()],
+			..Default::default()
+		};
+		assert_eq!(opts.split_servers().len(), 1);
+		let opts = WispServer {
+			stdio: true,
+			..Default::default()
+		};
+		let servers = opts.split_servers();
+		assert_eq!(servers.len(), 1);
+		assert_eq!(servers[0].describe(), "stdio");
+	}
+
+	#[test]
+	fn unix_websocket_url() {
+		match round_trip("ws+unix:///run/wisp.sock:/wisp/") {
+			WispUrl::UnixWebSocket { socket, path } => {
+				assert_eq!(socket, PathBuf::from("/run/wisp.sock"));
+				assert_eq!(path, "/wisp/");
+			}
+			x => panic!("{:?}", x),
+		}
+		match "ws+unix:///run/wisp.sock".parse().unwrap() {
+			WispUrl::UnixWebSocket { socket, path } => {
+				assert_eq!(socket, PathBuf::from("/run/wisp.sock"));
+				assert_eq!(path, "/");
+			}
+			x => panic!("{:?}", x),
+		}
+	}
+
+	#[test]
+	fn unix_websocket_url_with_colon_in_socket() {
+		match round_trip("ws+unix:///run/a:b.sock:/") {
+			WispUrl::UnixWebSocket { socket, path } => {
+				assert_eq!(socket, PathBuf::from("/run/a:b.sock"));
+				assert_eq!(path, "/");
+			}
+			x => panic!("{:?}", x),
+		}
+		match "ws+unix:///run/a:b.sock".parse().unwrap() {
+			WispUrl::UnixWebSocket { socket, .. } => {
+				assert_eq!(socket, PathBuf::from("/run/a:b.sock"))
+			}
+			x => panic!("{:?}", x),
+		}
+	}
+
+	#[test]
+	fn other_urls() {
+		assert!(matches!(
+			round_trip("unix:///run/wisp.sock"),
+			WispUrl::Unix(_)
+		));
+		assert!(matches!(
+			round_trip("tcp://example.com:6001"),
+			WispUrl::Tcp { port: 6001, .. }
+		));
+		assert!(matches!(
+			round_trip("wss://example.com/wisp/"),
+			WispUrl::Uri(_)
+		));
+		assert!("ws+unix://".parse::<WispUrl>().is_err());
+		assert!("unix://".parse::<WispUrl>().is_err());
+		assert!("tcp://example.com".parse::<WispUrl>().is_err());
+	}
+}
@@ -0,0 +1,225 @@
+use std::{
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, RwLock,
+	},
+	time::Duration,
+};
+
+use tokio::sync::watch;
+use wisp_mux::{
+	extensions::udp::UdpProtocolExtension, ClientMux, MuxStream, StreamType, WispError,
+};
+
+use crate::{keepalive::RttMetric, util::WhisperError};
+
+struct MuxHandleInner {
+	mux: RwLock<Arc<ClientMux>>,
+	closed: watch::Sender<Option<String>>,
+	rtt: Arc<RttMetric>,
+	streams: Arc<AtomicUsize>,
+}
+
+/// Counts a stream towards the load of the [`MuxHandle`] it was opened on until it is dropped.
+pub struct StreamGuard(Arc<AtomicUsize>);
+
+impl Drop for StreamGuard {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// Handle to the Wisp multiplexor used by [`start_whisper`](crate::start_whisper).
+///
+/// The transport may swap the multiplexor underneath (e.g. after reopening a PTY). New flows always
+/// go to the current one. Once the transport gives up, the handle is closed and whisper stops.
+#[derive(Clone)]
+pub struct MuxHandle(Arc<MuxHandleInner>);
+
+impl MuxHandle {
+	pub fn new(mux: ClientMux, rtt: Arc<RttMetric>) -> Self {
+		Self(Arc::new(MuxHandleInner {
+			mux: RwLock::new(Arc::new(mux)),
+			closed: watch::Sender::new(None),
+			rtt,
+			streams: Arc::new(AtomicUsize::new(0)),
+		}))
+	}
+
+	pub fn get(&self) -> Arc<ClientMux> {
+		self.0.mux.read().unwrap().clone()
+	}
+
+	pub fn replace(&self, mux: ClientMux) {
+		*self.0.mux.write().unwrap() = Arc::new(mux);
+	}
+
+	/// Whether both are handles to the same multiplexor.
+	pub fn ptr_eq(&self, other: &MuxHandle) -> bool {
+		Arc::ptr_eq(&self.0, &other.0)
+	}
+
+	/// Opens a stream. Keep the guard for as long as the flow lives.
+	///
+	/// The stream counts towards [`streams`](Self::streams) while it is being opened already, so
+	/// streams opened at the same time are spread over the connections.
+	pub async fn new_stream(
+		&self,
+		stream_type: StreamType,
+		host: String,
+		port: u16,
+	) -> Result<(MuxStream, StreamGuard), WispError> {
+		self.0.streams.fetch_add(1, Ordering::Relaxed);
+		let guard = StreamGuard(self.0.streams.clone());
+		let mux = self.get();
+		let stream = mux.client_new_stream(stream_type, host, port).await?;
+		Ok((stream, guard))
+	}
+
+	/// Whether the server accepts UDP streams. Wisp v1 connections never do.
+	pub fn udp(&self) -> bool {
+		self.get()
+			.supported_extension_ids
+			.contains(&UdpProtocolExtension::ID)
+	}
+
+	/// Streams currently open on this handle.
+	pub fn streams(&self) -> usize {
+		self.0.streams.load(Ordering::Relaxed)
+	}
+
+	/// Mark the multiplexor as permanently dead.
+	pub fn close(&self, reason: String) {
+		self.0.closed.send_replace(Some(reason));
+	}
+
+	/// Error the multiplexor was closed with, if it was.
+	pub fn error(&self) -> Option<WhisperError> {
+		self.0
+			.closed
+			.borrow()
+			.as_ref()
+			.map(|x| WhisperError::MuxClosed(x.clone()))
+	}
+
+	/// Round-trip time measured by the WebSocket keepalive, if there was a pong yet.
+	pub fn rtt(&self) -> Option<Duration> {
+		self.0.rtt.get()
+	}
+
+	/// Wait until the multiplexor is permanently dead.
+	pub async fn closed(&self) {
+		let mut rx = self.0.closed.subscribe();
+		let _ = rx.wait_for(|x| x.is_some()).await;
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use std::pin::pin;
+
+	use futures_util::poll;
+	use tokio::io::{duplex, split};
+	use wisp_mux::ServerMux;
+
+	use super::*;
+	use crate::{
+		codec::DEFAULT_MAX_FRAME_LENGTH,
+		pty::{StreamRead, StreamWrite},
+		util::MuxFuture,
+	};
+
+	/// In-memory Wisp v1 connection: the client mux with its future, and the server it talks to.
+	pub(crate) async fn pair() -> (ClientMux, MuxFuture, ServerMux) {
+		let (client, server) = duplex(1 << 16);
+		let (client_rx, client_tx) = split(client);
+		let (server_rx, server_tx) = split(server);
+		let (client, server) = tokio::join!(
+			ClientMux::create(
+				StreamRead::new(client_rx, DEFAULT_MAX_FRAME_LENGTH),
+				StreamWrite::new(client_tx, DEFAULT_MAX_FRAME_LENGTH),
+				None,
+			),
+			ServerMux::create(
+				StreamRead::new(server_rx, DEFAULT_MAX_FRAME_LENGTH),
+				StreamWrite::new(server_tx, DEFAULT_MAX_FRAME_LENGTH),
+				128,
+				None,
+			),
+		);
+		let (server, server_fut) = server.unwrap().with_no_required_extensions();
+		tokio::spawn(server_fut);
+		let (client, client_fut) = client.unwrap().with_no_required_extensions();
+		(client, Box::pin(client_fut), server)
+	}
+
+	/// [`MuxHandle`] over [`pair`], with the client future running.
+	pub(crate) async fn handle() -> (MuxHandle, ServerMux) {
+		let (mux, fut, server) = pair().await;
+		tokio::spawn(fut);
+		(MuxHandle::new(mux, Arc::default()), server)
+	}
+
+	#[tokio::test]
+	async fn replace() {
+		let (handle, _server) = handle().await;
+		let old = handle.get();
+		let (mux, fut, server) = pair().await;
+		tokio::spawn(fut);
+		handle.replace(mux);
+		assert!(!Arc::ptr_eq(&old, &handle.get()));
+		assert!(handle.ptr_eq(&handle.clone()));
+
+		// new streams go to the new mux
+		let (_stream, guard) = handle
+			.new_stream(StreamType::Tcp, "example.com".into(), 80)
+			.await
+			.unwrap();
+		let (connect, _) = server.server_new_stream().await.unwrap();
+		assert_eq!(connect.destination_hostname, "example.com");
+		assert_eq!(handle.streams(), 1);
+		drop(guard);
+		assert_eq!(handle.streams(), 0);
+	}
+
+	#[tokio::test]
+	async fn streams_count_while_opening() {
+		let (handle, _server) = handle().await;
+		let mut opening = pin!(handle.new_stream(StreamType::Tcp, "example.com".into(), 80));
+		assert!(poll!(&mut opening).is_pending());
+		assert_eq!(handle.streams(), 1);
+		let (_stream, guard) = opening.await.unwrap();
+		assert_eq!(handle.streams(), 1);
+		drop(guard);
+		assert_eq!(handle.streams(), 0);
+
+		// failing to open gives the reservation back
+		handle.get().close().await.unwrap();
+		while handle.get().close().await.is_ok() {
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		assert!(handle
+			.new_stream(StreamType::Tcp, "example.com".into(), 80)
+			.await
+			.is_err());
+		assert_eq!(handle.streams(), 0);
+	}
+
+	#[tokio::test]
+	async fn close() {
+		let (handle, _server) = handle().await;
+		assert!(handle.error().is_none());
+		let closed = tokio::spawn({
+			let handle = handle.clone();
+			async move { handle.closed().await }
+		});
+		tokio::task::yield_now().await;
+		assert!(!closed.is_finished());
+
+		handle.close("gone".into());
+		closed.await.unwrap();
+		assert!(matches!(handle.error(), Some(WhisperError::MuxClosed(x)) if x == "gone"));
+		// waiting again returns right away
+		handle.closed().await;
+	}
+}
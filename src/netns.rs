@@ -0,0 +1,379 @@
+//! Running a command in its own network namespace.
+//!
+//! `whisper exec -- <command>` forks before the runtime starts. The child moves into new network
+//! and mount namespaces (inside a new user namespace when whisper isn't root), brings up `lo`,
+//! creates the TUN device with the default route, points `/etc/resolv.conf` at `--dns` and hands
+//! the TUN device back to whisper over a socket, or the error it failed with so whisper can log it.
+//! Once whisper is connected the child runs the command. The namespace only has `lo` and the TUN
+//! device, so the command can't reach anything except through whisper, and the host's routes are
+//! never touched. Everything is torn down by the kernel when the command exits.
+
+use std::{
+	convert::Infallible,
+	ffi::CString,
+	fs::{self, OpenOptions},
+	io::{self, IoSlice, IoSliceMut, Write},
+	mem,
+	os::{
+		fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+		unix::fs::OpenOptionsExt,
+	},
+	pin::Pin,
+	task::{ready, Context, Poll},
+};
+
+use log::{error, info};
+use nix::{
+	cmsg_space,
+	fcntl::{fcntl, FcntlArg, OFlag},
+	libc,
+	mount::{mount, umount2, MntFlags, MsFlags},
+	sched::{unshare, CloneFlags},
+	sys::{
+		prctl::set_pdeathsig,
+		signal::Signal,
+		socket::{
+			recvmsg, sendmsg, socket, socketpair, AddressFamily, ControlMessage,
+			ControlMessageOwned, MsgFlags, SockFlag, SockType,
+		},
+		wait::{waitpid, WaitStatus},
+	},
+	unistd::{execvp, fork, getgid, getuid, read, write, ForkResult, Pid},
+};
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::Framed;
+use tun2::{Configuration, TunPacketCodec};
+
+use crate::{Cli, ExecOptions};
+
+/// Name of the TUN device in the namespace without `--tun`.
+const DEFAULT_TUN: &str = "tun0";
+
+/// TUN device handed over from the namespace, read and written one packet at a time.
+pub struct TunFd(AsyncFd<OwnedFd>);
+
+impl AsyncRead for TunFd {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		loop {
+			let mut guard = ready!(self.0.poll_read_ready(cx))?;
+			let unfilled = buf.initialize_unfilled();
+			match guard.try_io(|fd| Ok(read(fd.as_raw_fd(), unfilled)?)) {
+				Ok(Ok(n)) => {
+					buf.advance(n);
+					return Poll::Ready(Ok(()));
+				}
+				Ok(Err(err)) => return Poll::Ready(Err(err)),
+				Err(_) => continue,
+			}
+		}
+	}
+}
+
+impl AsyncWrite for TunFd {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		loop {
+			let mut guard = ready!(self.0.poll_write_ready(cx))?;
+			match guard.try_io(|fd| Ok(write(fd.get_ref(), buf)?)) {
+				Ok(x) => return Poll::Ready(x),
+				Err(_) => continue,
+			}
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+/// Command running in its own network namespace, waiting for [`Namespace::start`].
+pub struct Namespace {
+	pid: Pid,
+	/// Socket to the child, closing it before `start` makes the child exit.
+	control: OwnedFd,
+	tun: Option<OwnedFd>,
+}
+
+impl Namespace {
+	/// Forks the child that sets up the namespace and returns once it handed over the TUN device.
+	///
+	/// This has to be called before any threads are started, in particular before the Tokio
+	/// runtime is built.
+	pub fn spawn(opts: &Cli, exec: &ExecOptions) -> Result<Self, io::Error> {
+		let (control, child_control) = socketpair(
+			AddressFamily::Unix,
+			SockType::Stream,
+			None,
+			SockFlag::SOCK_CLOEXEC,
+		)?;
+		// SAFETY: the process is still single-threaded
+		match unsafe { fork() }? {
+			ForkResult::Child => {
+				drop(control);
+				if let Err(err) = setup(opts, exec, &child_control) {
+					// whisper logs it, there's no TUN device for it to wait for
+					let _ = write(child_control.as_fd(), err.to_string().as_bytes());
+					std::process::exit(127);
+				}
+				let Err(err) = run(exec, child_control);
+				eprintln!("whisper exec: {}", err);
+				std::process::exit(127);
+			}
+			ForkResult::Parent { child } => {
+				drop(child_control);
+				let tun = match receive_fd(&control) {
+					Ok(tun) => tun,
+					Err(err) => {
+						let _ = waitpid(child, None);
+						error!("Failed to set up the network namespace: {}", err);
+						return Err(io::Error::new(
+							err.kind(),
+							"failed to set up the network namespace",
+						));
+					}
+				};
+				fcntl(tun.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+				info!(
+					"Created TUN device {:?} in a new network namespace",
+					opts.tun.as_deref().unwrap_or(DEFAULT_TUN)
+				);
+				Ok(Self {
+					pid: child,
+					control,
+					tun: Some(tun),
+				})
+			}
+		}
+	}
+
+	/// Packets of the TUN device in the namespace, for
+	/// [`start_whisper_on`](crate::start_whisper_on). Needs the Tokio runtime.
+	pub fn packets(&mut self, mtu: u16) -> Result<Framed<TunFd, TunPacketCodec>, io::Error> {
+		let tun = self.tun.take().ok_or(io::ErrorKind::NotFound)?;
+		Ok(Framed::with_capacity(
+			TunFd(AsyncFd::new(tun)?),
+			TunPacketCodec::new(mtu.into()),
+			mtu.into(),
+		))
+	}
+
+	/// Lets the child run the command.
+	pub fn start(&self) -> Result<(), io::Error> {
+		write(self.control.as_fd(), &[1])?;
+		Ok(())
+	}
+
+	/// Waits for the command to exit and returns its exit code.
+	pub async fn wait(self) -> Result<i32, io::Error> {
+		let pid = self.pid;
+		let status = tokio::task::spawn_blocking(move || waitpid(pid, None)).await??;
+		Ok(match status {
+			WaitStatus::Exited(_, code) => code,
+			WaitStatus::Signaled(_, signal, _) => 128 + signal as i32,
+			_ => 1,
+		})
+	}
+}
+
+/// Receives the TUN device, or the error the child failed to set up the namespace with.
+fn receive_fd(control: &OwnedFd) -> Result<OwnedFd, io::Error> {
+	let mut buf = [0; 512];
+	let mut iov = [IoSliceMut::new(&mut buf)];
+	let mut cmsg = cmsg_space!([std::os::fd::RawFd; 1]);
+	let msg = recvmsg::<()>(
+		control.as_raw_fd(),
+		&mut iov,
+		Some(&mut cmsg),
+		MsgFlags::MSG_CMSG_CLOEXEC,
+	)?;
+	for cmsg in msg.cmsgs() {
+		if let ControlMessageOwned::ScmRights(fds) = cmsg {
+			if let Some(fd) = fds.first() {
+				// SAFETY: the fd was just received and isn't owned by anything else
+				return Ok(unsafe { OwnedFd::from_raw_fd(*fd) });
+			}
+		}
+	}
+	let mut text = msg.iovs().next().unwrap_or_default().to_vec();
+	if !text.is_empty() {
+		// the rest of the error until the child exits
+		let mut buf = [0; 512];
+		loop {
+			match read(control.as_raw_fd(), &mut buf) {
+				Ok(0) => break,
+				Ok(n) => text.extend_from_slice(&buf[..n]),
+				Err(nix::errno::Errno::EINTR) => continue,
+				Err(err) => return Err(err.into()),
+			}
+		}
+	}
+	match String::from_utf8_lossy(&text).trim() {
+		"" => Err(io::Error::other("the child exited without a TUN device")),
+		text => Err(io::Error::other(text.to_string())),
+	}
+}
+
+/// Socket for the interface and route ioctls.
+fn ioctl_socket() -> Result<OwnedFd, io::Error> {
+	Ok(socket(
+		AddressFamily::Inet,
+		SockType::Datagram,
+		SockFlag::SOCK_CLOEXEC,
+		None,
+	)?)
+}
+
+fn loopback_up() -> Result<(), io::Error> {
+	let sock = ioctl_socket()?;
+	// SAFETY: ifreq is plain data and only used with the interface flag ioctls
+	unsafe {
+		let mut req: libc::ifreq = mem::zeroed();
+		req.ifr_name[0] = b'l' as libc::c_char;
+		req.ifr_name[1] = b'o' as libc::c_char;
+		if libc::ioctl(sock.as_raw_fd(), libc::SIOCGIFFLAGS, &mut req) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+		if libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS, &req) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+	Ok(())
+}
+
+/// Adds the IPv4 default route through the TUN device.
+fn default_route(tun: &str) -> Result<(), io::Error> {
+	let sock = ioctl_socket()?;
+	let tun = CString::new(tun)?;
+	// SAFETY: rtentry is plain data and the device name outlives the ioctl
+	unsafe {
+		let mut route: libc::rtentry = mem::zeroed();
+		// 0.0.0.0/0
+		route.rt_dst.sa_family = libc::AF_INET as libc::sa_family_t;
+		route.rt_genmask.sa_family = libc::AF_INET as libc::sa_family_t;
+		route.rt_flags = libc::RTF_UP;
+		route.rt_dev = tun.as_ptr() as *mut libc::c_char;
+		if libc::ioctl(sock.as_raw_fd(), libc::SIOCADDRT, &route) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+	Ok(())
+}
+
+fn resolv_conf(exec: &ExecOptions) -> Result<(), io::Error> {
+	// the mounts of the namespace must not propagate back to the host
+	mount(
+		None::<&str>,
+		"/",
+		None::<&str>,
+		MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+		None::<&str>,
+	)?;
+	// written to a tmpfs of our own, nobody else can put anything in its place
+	let dir = std::env::temp_dir();
+	mount(
+		Some("tmpfs"),
+		&dir,
+		Some("tmpfs"),
+		MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+		Some("mode=0755,size=16k"),
+	)?;
+	let path = dir.join("resolv.conf");
+	let result = OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o644)
+		.open(&path)
+		.and_then(|mut file| file.write_all(format!("nameserver {}\n", exec.dns).as_bytes()))
+		.and_then(|()| {
+			mount(
+				Some(&path),
+				"/etc/resolv.conf",
+				None::<&str>,
+				MsFlags::MS_BIND,
+				None::<&str>,
+			)
+			.map_err(io::Error::from)
+		});
+	// the bind mount keeps the file, the temp dir of the host shows through again
+	umount2(&dir, MntFlags::MNT_DETACH)?;
+	result.map_err(|x| io::Error::new(x.kind(), format!("/etc/resolv.conf: {}", x)))
+}
+
+/// Sets up the namespace in the child and hands the TUN device to whisper.
+fn setup(opts: &Cli, exec: &ExecOptions, control: &OwnedFd) -> Result<(), io::Error> {
+	set_pdeathsig(Signal::SIGTERM)?;
+	let (uid, gid) = (getuid(), getgid());
+	if uid.is_root() {
+		unshare(CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWNS)?;
+	} else {
+		unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWNS)
+			.map_err(|x| {
+				io::Error::new(
+					io::Error::from(x).kind(),
+					format!(
+						"failed to create a user namespace ({}), run as root instead",
+						x
+					),
+				)
+			})?;
+		// keep our own IDs, the command then runs without any capabilities
+		fs::write("/proc/self/setgroups", "deny")?;
+		fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))?;
+		fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))?;
+	}
+	loopback_up()?;
+
+	let name = opts.tun.as_deref().unwrap_or(DEFAULT_TUN);
+	let mut cfg = Configuration::default();
+	cfg.address(opts.ip)
+		.netmask(opts.mask)
+		.destination(opts.dest)
+		.mtu(opts.mtu)
+		.tun_name(name)
+		.up();
+	let tun = tun2::create(&cfg).map_err(io::Error::other)?;
+	default_route(name)?;
+	resolv_conf(exec)?;
+
+	sendmsg::<()>(
+		control.as_raw_fd(),
+		&[IoSlice::new(&[0])],
+		&[ControlMessage::ScmRights(&[tun.as_raw_fd()])],
+		MsgFlags::empty(),
+		None,
+	)?;
+	Ok(())
+}
+
+/// Runs the command in the child once whisper is connected.
+fn run(exec: &ExecOptions, control: OwnedFd) -> Result<Infallible, io::Error> {
+	// whisper closes the socket instead if it fails to connect
+	let mut buf = [0];
+	if read(control.as_raw_fd(), &mut buf)? == 0 {
+		std::process::exit(1);
+	}
+	drop(control);
+
+	let args = exec
+		.command
+		.iter()
+		.map(|x| CString::new(x.as_str()))
+		.collect::<Result<Vec<_>, _>>()?;
+	let Err(err) = execvp(&args[0], &args);
+	Err(io::Error::new(
+		io::Error::from(err).kind(),
+		format!("{}: {}", exec.command[0], err),
+	))
+}
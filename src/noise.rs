@@ -0,0 +1,482 @@
+//! Noise protocol encryption for the length-delimited transports.
+//!
+//! Whisper is always the initiator of a `Noise_XX_25519_ChaChaPoly_BLAKE2s` handshake, carried in
+//! three raw frames before the Wisp mux starts. After that every frame is
+//! `nonce: u64 | message...`, where the Wisp packet is split into Noise messages of at most
+//! 65535 bytes, each encrypted with the next nonce. Nonces are explicit so that frames dropped by
+//! [`Framing::Sync`](crate::codec::Framing::Sync) don't desynchronize the ends, and both ends
+//! rekey every [`REKEY_INTERVAL`] messages. A frame may be at most one key epoch ahead of the last
+//! one received, and the receiving keys only move on once a frame of the next epoch
+//! authenticates, so a forged nonce can't make the receiver skip keys.
+
+use std::{
+	fmt::Display,
+	fs::OpenOptions,
+	io::{self, Write},
+	os::unix::fs::OpenOptionsExt,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use clap::Args;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use log::{info, warn};
+use snow::{
+	params::{CipherChoice, DHChoice, NoiseParams},
+	resolvers::{CryptoResolver, DefaultResolver},
+	types::Cipher,
+	Builder, StatelessTransportState,
+};
+use tokio::time::timeout;
+
+use crate::pty::{FrameRead, FrameWrite};
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_PROLOGUE: &[u8] = b"whisper noise v1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const MAX_MESSAGE_LEN: usize = 65535;
+const MAX_CHUNK_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages sent under one key. Both ends derive the key epoch from the nonce, so rekeying needs
+/// no extra messages.
+pub const REKEY_INTERVAL: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct NoiseOptions {
+	/// Private key file that enables Noise encryption on length-delimited transports (create one with whisper-keygen)
+	#[arg(long)]
+	pub noise_key: Option<PathBuf>,
+	/// Public key the Noise peer must present. Can be given multiple times.
+	#[arg(long, requires = "noise_key")]
+	pub noise_peer: Vec<PublicKey>,
+}
+
+impl NoiseOptions {
+	pub fn enabled(&self) -> bool {
+		self.noise_key.is_some()
+	}
+}
+
+/// Curve25519 public key, base64 encoded like WireGuard keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(pub [u8; KEY_LEN]);
+
+impl FromStr for PublicKey {
+	type Err = io::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(Self(decode_key(s)?))
+	}
+}
+
+impl Display for PublicKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", BASE64_STANDARD.encode(self.0))
+	}
+}
+
+fn decode_key(s: &str) -> Result<[u8; KEY_LEN], io::Error> {
+	BASE64_STANDARD
+		.decode(s.trim())
+		.ok()
+		.and_then(|x| x.try_into().ok())
+		.ok_or(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"key is not 32 bytes of base64",
+		))
+}
+
+fn noise_error(err: snow::Error) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("Noise error: {}", err))
+}
+
+fn params() -> NoiseParams {
+	NOISE_PARAMS.parse().expect("valid Noise parameters")
+}
+
+pub fn generate_private_key() -> Result<[u8; KEY_LEN], io::Error> {
+	let keypair = Builder::new(params())
+		.generate_keypair()
+		.map_err(noise_error)?;
+	Ok(keypair.private.try_into().expect("32 byte private key"))
+}
+
+pub fn public_key(private_key: &[u8; KEY_LEN]) -> Result<PublicKey, io::Error> {
+	let mut dh = DefaultResolver
+		.resolve_dh(&DHChoice::Curve25519)
+		.ok_or(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"Curve25519 is not available",
+		))?;
+	dh.set(private_key);
+	Ok(PublicKey(
+		dh.pubkey().try_into().expect("32 byte public key"),
+	))
+}
+
+pub fn load_private_key(path: &Path) -> Result<[u8; KEY_LEN], io::Error> {
+	decode_key(&std::fs::read_to_string(path)?)
+}
+
+/// Writes a new private key readable only by the current user. Existing files are not overwritten.
+pub fn write_private_key(path: &Path, private_key: &[u8; KEY_LEN]) -> Result<(), io::Error> {
+	let mut file = OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(path)?;
+	writeln!(file, "{}", BASE64_STANDARD.encode(private_key))
+}
+
+/// Runs the Noise handshake over a freshly opened frame transport, then encrypts every later
+/// frame in both directions.
+pub async fn secure<R: FrameRead, W: FrameWrite>(
+	rx: &mut R,
+	tx: &mut W,
+	opts: &NoiseOptions,
+) -> Result<PublicKey, io::Error> {
+	let (cipher, peer) = timeout(HANDSHAKE_TIMEOUT, handshake(rx.stream(), tx.sink(), opts))
+		.await
+		.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Noise handshake timed out"))??;
+	let cipher = Arc::new(cipher);
+	rx.codec_mut().set_cipher(cipher.clone());
+	tx.codec_mut().set_cipher(cipher);
+	Ok(peer)
+}
+
+async fn handshake(
+	rx: &mut (dyn Stream<Item = Result<BytesMut, io::Error>> + Unpin + Send),
+	tx: &mut (dyn Sink<Bytes, Error = io::Error> + Unpin + Send),
+	opts: &NoiseOptions,
+) -> Result<(NoiseCipher, PublicKey), io::Error> {
+	let path = opts.noise_key.as_ref().ok_or(io::Error::new(
+		io::ErrorKind::InvalidInput,
+		"no Noise private key",
+	))?;
+	let private_key = load_private_key(path)?;
+	let mut handshake = Builder::new(params())
+		.local_private_key(&private_key)
+		.prologue(NOISE_PROLOGUE)
+		.build_initiator()
+		.map_err(noise_error)?;
+	let mut buf = vec![0; MAX_MESSAGE_LEN];
+
+	// -> e
+	let len = handshake
+		.write_message(&[], &mut buf)
+		.map_err(noise_error)?;
+	tx.send(Bytes::copy_from_slice(&buf[..len])).await?;
+
+	// <- e, ee, s, es
+	let msg = rx.next().await.ok_or(io::Error::new(
+		io::ErrorKind::UnexpectedEof,
+		"connection closed during Noise handshake",
+	))??;
+	handshake
+		.read_message(&msg, &mut buf)
+		.map_err(noise_error)?;
+	let peer = PublicKey(
+		handshake
+			.get_remote_static()
+			.and_then(|x| x.try_into().ok())
+			.ok_or(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"Noise peer sent no static key",
+			))?,
+	);
+	if opts.noise_peer.is_empty() {
+		warn!(
+			"Noise peer key {} is not pinned. Pass it to --noise-peer to pin it.",
+			peer
+		);
+	} else if !opts.noise_peer.contains(&peer) {
+		return Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			format!("Noise peer key {} is not pinned", peer),
+		));
+	}
+
+	// -> s, se
+	let len = handshake
+		.write_message(&[], &mut buf)
+		.map_err(noise_error)?;
+	tx.send(Bytes::copy_from_slice(&buf[..len])).await?;
+
+	let (_, recv_key) = handshake.dangerously_get_raw_split();
+	let transport = handshake
+		.into_stateless_transport_mode()
+		.map_err(noise_error)?;
+	info!("Noise handshake complete with peer {}", peer);
+	Ok((NoiseCipher::new(transport, &recv_key)?, peer))
+}
+
+/// Transport keys of a finished handshake, shared by the read and write half of a transport.
+pub struct NoiseCipher(Mutex<CipherState>);
+
+struct CipherState {
+	/// Sending keys. Received frames are decrypted with `recv` and `recv_next` instead, which
+	/// can be tried without changing any state.
+	transport: StatelessTransportState,
+	send_nonce: u64,
+	send_epoch: u64,
+	/// Receiving key of `recv_epoch`.
+	recv: Box<dyn Cipher>,
+	/// Receiving key of the epoch after `recv_epoch`.
+	recv_next: Box<dyn Cipher>,
+	recv_nonce: u64,
+	recv_epoch: u64,
+}
+
+fn nonce_exhausted() -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, "Noise nonce exhausted")
+}
+
+fn cipher(key: &[u8]) -> Result<Box<dyn Cipher>, io::Error> {
+	let mut cipher = DefaultResolver
+		.resolve_cipher(&CipherChoice::ChaChaPoly)
+		.ok_or(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"ChaChaPoly is not available",
+		))?;
+	cipher.set(key);
+	Ok(cipher)
+}
+
+impl NoiseCipher {
+	/// Length of a frame of `len` bytes once encrypted: the nonce and a tag per message.
+	pub fn encrypted_len(len: usize) -> usize {
+		NONCE_LEN + len + len.div_ceil(MAX_CHUNK_LEN).max(1) * TAG_LEN
+	}
+
+	/// Both ends of a finished handshake between two fresh keys.
+	#[cfg(test)]
+	pub(crate) fn pair() -> (Self, Self) {
+		let (a, b) = (
+			generate_private_key().unwrap(),
+			generate_private_key().unwrap(),
+		);
+		let builder = |key| {
+			Builder::new(params())
+				.local_private_key(key)
+				.prologue(NOISE_PROLOGUE)
+		};
+		let mut initiator = builder(&a).build_initiator().unwrap();
+		let mut responder = builder(&b).build_responder().unwrap();
+		let (mut msg, mut buf) = (vec![0; MAX_MESSAGE_LEN], vec![0; MAX_MESSAGE_LEN]);
+		for _ in 0..3 {
+			let (from, to) = if initiator.is_my_turn() {
+				(&mut initiator, &mut responder)
+			} else {
+				(&mut responder, &mut initiator)
+			};
+			let len = from.write_message(&[], &mut msg).unwrap();
+			to.read_message(&msg[..len], &mut buf).unwrap();
+		}
+		let (initiator_key, responder_key) = initiator.dangerously_get_raw_split();
+		(
+			Self::new(
+				initiator.into_stateless_transport_mode().unwrap(),
+				&responder_key,
+			)
+			.unwrap(),
+			Self::new(
+				responder.into_stateless_transport_mode().unwrap(),
+				&initiator_key,
+			)
+			.unwrap(),
+		)
+	}
+
+	/// Takes the transport keys of a finished handshake and the key the peer sends with.
+	fn new(transport: StatelessTransportState, recv_key: &[u8]) -> Result<Self, io::Error> {
+		let mut recv_next = cipher(recv_key)?;
+		recv_next.rekey();
+		Ok(Self(Mutex::new(CipherState {
+			transport,
+			send_nonce: 0,
+			send_epoch: 0,
+			recv: cipher(recv_key)?,
+			recv_next,
+			recv_nonce: 0,
+			recv_epoch: 0,
+		})))
+	}
+
+	pub fn encrypt(&self, item: &[u8], dst: &mut BytesMut) -> Result<(), io::Error> {
+		let mut state = self.0.lock().unwrap();
+		dst.reserve(Self::encrypted_len(item.len()));
+		dst.put_u64_le(state.send_nonce);
+
+		// an empty packet still needs one message to authenticate it
+		for chunk in item
+			.chunks(MAX_CHUNK_LEN)
+			.chain(item.is_empty().then_some(item))
+		{
+			let nonce = state.send_nonce;
+			while state.send_epoch < nonce / REKEY_INTERVAL {
+				state.transport.rekey_outgoing();
+				state.send_epoch += 1;
+			}
+			let start = dst.len();
+			dst.resize(start + chunk.len() + TAG_LEN, 0);
+			let len = state
+				.transport
+				.write_message(nonce, chunk, &mut dst[start..])
+				.map_err(noise_error)?;
+			dst.truncate(start + len);
+			state.send_nonce = nonce.checked_add(1).ok_or_else(nonce_exhausted)?;
+		}
+		Ok(())
+	}
+
+	/// Decrypts and authenticates a frame. Nothing changes if it doesn't authenticate, so the
+	/// caller may drop the frame and carry on.
+	pub fn decrypt(&self, frame: &[u8]) -> Result<BytesMut, io::Error> {
+		if frame.len() < NONCE_LEN + TAG_LEN {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!("Noise frame of {} bytes is too short", frame.len()),
+			));
+		}
+		let mut state = self.0.lock().unwrap();
+		let nonce = u64::from_le_bytes(frame[..NONCE_LEN].try_into().unwrap());
+		if nonce < state.recv_nonce {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!(
+					"replayed Noise frame: nonce {} is older than {}",
+					nonce, state.recv_nonce
+				),
+			));
+		}
+		let messages = (frame.len() - NONCE_LEN).div_ceil(MAX_MESSAGE_LEN) as u64;
+		// u64::MAX is reserved for rekeying
+		let last = nonce
+			.checked_add(messages - 1)
+			.filter(|x| *x != u64::MAX)
+			.ok_or_else(nonce_exhausted)?;
+		if last / REKEY_INTERVAL > state.recv_epoch + 1 {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				format!(
+					"Noise frame with nonce {} is more than one key epoch ahead of {}",
+					last, state.recv_nonce
+				),
+			));
+		}
+
+		let mut out = BytesMut::with_capacity(frame.len());
+		for (chunk, nonce) in frame[NONCE_LEN..].chunks(MAX_MESSAGE_LEN).zip(nonce..) {
+			if chunk.len() < TAG_LEN {
+				return Err(noise_error(snow::Error::Decrypt));
+			}
+			let cipher = if nonce / REKEY_INTERVAL > state.recv_epoch {
+				&state.recv_next
+			} else {
+				&state.recv
+			};
+			let start = out.len();
+			out.resize(start + chunk.len() - TAG_LEN, 0);
+			cipher
+				.decrypt(nonce, &[], chunk, &mut out[start..])
+				.map_err(noise_error)?;
+		}
+
+		if last / REKEY_INTERVAL > state.recv_epoch {
+			let state = &mut *state;
+			std::mem::swap(&mut state.recv, &mut state.recv_next);
+			state.recv_next.rekey();
+			state.recv_next.rekey();
+			state.recv_epoch += 1;
+		}
+		state.recv_nonce = last + 1;
+		Ok(out)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn encrypt(cipher: &NoiseCipher, item: &[u8]) -> BytesMut {
+		let mut frame = BytesMut::new();
+		cipher.encrypt(item, &mut frame).unwrap();
+		frame
+	}
+
+	/// A frame with `nonce` that no key authenticates.
+	fn forged(nonce: u64) -> BytesMut {
+		let mut frame = BytesMut::new();
+		frame.put_u64_le(nonce);
+		frame.put_bytes(0, 32);
+		frame
+	}
+
+	fn skip_to(cipher: &NoiseCipher, nonce: u64) {
+		cipher.0.lock().unwrap().send_nonce = nonce;
+	}
+
+	#[test]
+	fn round_trip() {
+		let (a, b) = NoiseCipher::pair();
+		for item in [&b""[..], b"hello", &vec![7; 3 * MAX_MESSAGE_LEN]] {
+			assert_eq!(b.decrypt(&encrypt(&a, item)).unwrap(), item);
+			assert_eq!(a.decrypt(&encrypt(&b, item)).unwrap(), item);
+		}
+	}
+
+	#[test]
+	fn rekeys_across_epochs() {
+		let (a, b) = NoiseCipher::pair();
+		for epoch in 1..4 {
+			skip_to(&a, epoch * REKEY_INTERVAL - 1);
+			// the second message of this one is in the next epoch
+			let item = vec![epoch as u8; MAX_CHUNK_LEN + 1];
+			assert_eq!(b.decrypt(&encrypt(&a, &item)).unwrap(), item);
+			assert_eq!(b.decrypt(&encrypt(&a, b"hello")).unwrap(), &b"hello"[..]);
+		}
+	}
+
+	#[test]
+	fn forged_frame_changes_nothing() {
+		let (a, b) = NoiseCipher::pair();
+		for epoch in 1..4 {
+			// a forged frame of the next epoch must not move the receiving keys on
+			assert!(b.decrypt(&forged(epoch * REKEY_INTERVAL)).is_err());
+			assert_eq!(b.decrypt(&encrypt(&a, b"before")).unwrap(), &b"before"[..]);
+			skip_to(&a, epoch * REKEY_INTERVAL);
+			assert!(b.decrypt(&forged(epoch * REKEY_INTERVAL + 1)).is_err());
+			assert_eq!(b.decrypt(&encrypt(&a, b"after")).unwrap(), &b"after"[..]);
+		}
+	}
+
+	#[test]
+	fn rejects_replays_and_far_nonces() {
+		let (a, b) = NoiseCipher::pair();
+		let frame = encrypt(&a, b"hello");
+		b.decrypt(&frame).unwrap();
+		assert!(b.decrypt(&frame).is_err());
+
+		let err = b.decrypt(&forged(2 * REKEY_INTERVAL)).unwrap_err();
+		assert!(err.to_string().contains("epoch"), "{}", err);
+		assert!(b.decrypt(&forged(u64::MAX)).is_err());
+
+		// still in sync after all that
+		assert_eq!(b.decrypt(&encrypt(&a, b"hello")).unwrap(), &b"hello"[..]);
+	}
+
+	#[test]
+	fn rejects_short_messages() {
+		let (a, b) = NoiseCipher::pair();
+		let mut frame = encrypt(&a, &vec![0; MAX_CHUNK_LEN + 1]);
+		frame.truncate(frame.len() - TAG_LEN);
+		assert!(b.decrypt(&frame).is_err());
+	}
+}
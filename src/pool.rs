@@ -0,0 +1,933 @@
+//! Several Wisp servers behind one handle.
+//!
+//! Every `--url` gets `--connections` multiplexors. New streams go to the servers that are up,
+//! picked by the [`Strategy`], and within a server to the connection with the fewest open streams.
+//! A stream stays on its connection, so a connection that dies only takes its own streams with it.
+//! Connections that go down are reconnected in the background every `--reprobe-interval` seconds.
+//! Whisper stops once every connection is down at the same time, which with a single server and
+//! connection is the same as before. Servers reached through a `--helper` are reconnected for as
+//! long as the helper is supervised instead.
+//!
+//! With `--lazy` nothing is connected up front. The first flow connects every server while later
+//! flows wait for it, and the connections are closed again after `--idle-timeout` seconds without
+//! open streams. Failing to connect only fails the waiting flows; the next flow tries again. While
+//! connected, connections that go down are reconnected like above, but the pool is never closed.
+
+use std::{
+	error::Error,
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+		Arc, Mutex, RwLock, Weak,
+	},
+	time::{Duration, Instant},
+};
+
+use clap::{Args, ValueEnum};
+use futures_util::future::join_all;
+use log::{info, warn};
+use tokio::{
+	sync::{watch, Mutex as AsyncMutex},
+	time::sleep,
+};
+use wisp_mux::{MuxStream, StreamType};
+
+use crate::{
+	helper::Helper,
+	mux::{MuxHandle, StreamGuard},
+	util::{connect_to_wisp, resolve_server, WhisperError},
+	WispServer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+	/// Use the first server that is up, in the order they were given
+	Failover,
+	/// Take turns between the servers that are up
+	RoundRobin,
+	/// Use the server with the lowest round-trip time
+	LeastLatency,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct PoolOptions {
+	/// How new streams are spread over the Wisp servers when --url is given more than once
+	#[arg(long, value_enum, default_value_t = Strategy::Failover)]
+	pub strategy: Strategy,
+	/// Seconds between reconnect attempts to a Wisp server that is down
+	#[arg(long, default_value_t = 10)]
+	pub reprobe_interval: u64,
+	/// Parallel connections to each Wisp server. New streams go to the least loaded one.
+	#[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+	pub connections: u16,
+	/// Connect only once the first flow arrives instead of at startup
+	#[arg(long)]
+	pub lazy: bool,
+	/// Seconds without open streams after which a --lazy connection is closed. 0 keeps it open.
+	#[arg(long, default_value_t = 60)]
+	pub idle_timeout: u64,
+}
+
+impl Default for PoolOptions {
+	fn default() -> Self {
+		Self {
+			strategy: Strategy::Failover,
+			reprobe_interval: 10,
+			connections: 1,
+			lazy: false,
+			idle_timeout: 60,
+		}
+	}
+}
+
+/// Health of one Wisp server.
+#[derive(Debug, Clone)]
+pub struct ServerHealth {
+	pub url: String,
+	pub up: bool,
+	/// Connections that are up
+	pub connections: usize,
+	/// Streams open on all connections
+	pub streams: usize,
+	/// Whether the server takes UDP streams. Without it whisper runs TCP-only, see
+	/// [`fallback`](crate::fallback).
+	pub udp: bool,
+	/// Lowest round-trip time of the connections, measured by the WebSocket keepalive
+	pub rtt: Option<Duration>,
+	/// Time the last successful connect took
+	pub connect_time: Option<Duration>,
+	/// Address the last successful connect reached
+	pub addr: Option<SocketAddr>,
+	pub last_error: Option<String>,
+}
+
+impl ServerHealth {
+	/// Latency used by [`Strategy::LeastLatency`].
+	pub fn latency(&self) -> Option<Duration> {
+		self.rtt.or(self.connect_time)
+	}
+}
+
+struct Server {
+	url: String,
+	opts: WispServer,
+	connections: Vec<RwLock<Option<MuxHandle>>>,
+	/// Generation of the monitor of each connection. Bumped when a new monitor takes over, so a
+	/// stale one stops instead of reconnecting the connection a second time.
+	monitors: Vec<AtomicU64>,
+	connect_time: AtomicU64,
+	addr: Mutex<Option<SocketAddr>>,
+	last_error: Mutex<Option<String>>,
+}
+
+impl Server {
+	fn name(&self, slot: usize) -> String {
+		if self.connections.len() == 1 {
+			self.url.clone()
+		} else {
+			format!("{} (connection {})", self.url, slot + 1)
+		}
+	}
+
+	fn handles(&self) -> Vec<MuxHandle> {
+		self.connections
+			.iter()
+			.filter_map(|x| x.read().unwrap().clone())
+			.filter(|x| x.error().is_none())
+			.collect()
+	}
+
+	/// Least loaded connection that is up.
+	fn handle(&self) -> Option<MuxHandle> {
+		self.handles().into_iter().min_by_key(|x| x.streams())
+	}
+
+	/// Whether `handle` is still the connection in `slot`.
+	fn holds(&self, slot: usize, handle: &MuxHandle) -> bool {
+		self.connections[slot]
+			.read()
+			.unwrap()
+			.as_ref()
+			.is_some_and(|x| x.ptr_eq(handle))
+	}
+
+	/// Whether `generation` is still the monitor of `slot`.
+	fn monitors(&self, slot: usize, generation: u64) -> bool {
+		self.monitors[slot].load(Ordering::Relaxed) == generation
+	}
+
+	fn up(&self, slot: usize, handle: MuxHandle, connect_time: Duration, addr: Option<SocketAddr>) {
+		if !handle.udp() {
+			warn!(
+				"Wisp server {} doesn't support UDP, running TCP-only: DNS goes over TCP and other UDP is refused",
+				self.name(slot)
+			);
+		}
+		*self.connections[slot].write().unwrap() = Some(handle);
+		self.connect_time
+			.store(connect_time.as_micros() as u64, Ordering::Relaxed);
+		if addr.is_some() {
+			*self.addr.lock().unwrap() = addr;
+		}
+	}
+
+	fn down(&self, slot: usize, reason: String) {
+		*self.connections[slot].write().unwrap() = None;
+		*self.last_error.lock().unwrap() = Some(reason);
+	}
+
+	fn health(&self) -> ServerHealth {
+		let handles = self.handles();
+		ServerHealth {
+			url: self.url.clone(),
+			up: !handles.is_empty(),
+			connections: handles.len(),
+			streams: handles.iter().map(|x| x.streams()).sum(),
+			udp: handles.iter().any(|x| x.udp()),
+			rtt: handles.iter().filter_map(|x| x.rtt()).min(),
+			connect_time: match self.connect_time.load(Ordering::Relaxed) {
+				0 => None,
+				micros => Some(Duration::from_micros(micros)),
+			},
+			addr: *self.addr.lock().unwrap(),
+			last_error: self.last_error.lock().unwrap().clone(),
+		}
+	}
+
+	async fn connect(&self, slot: usize, v2: bool) -> Result<Option<SocketAddr>, String> {
+		let start = Instant::now();
+		match connect_to_wisp(&self.opts, v2).await {
+			Ok((handle, socketaddr)) => {
+				self.up(slot, handle, start.elapsed(), socketaddr);
+				Ok(socketaddr)
+			}
+			Err(err) => {
+				let err = err.to_string();
+				self.down(slot, err.clone());
+				Err(err)
+			}
+		}
+	}
+}
+
+struct PoolInner {
+	servers: Vec<Arc<Server>>,
+	opts: PoolOptions,
+	next: AtomicUsize,
+	closed: watch::Sender<Option<String>>,
+	v2: bool,
+	/// Held while connecting on demand, so flows that arrive meanwhile wait for it.
+	connecting: AsyncMutex<()>,
+	/// Last time a stream was open, for the idle timeout.
+	last_active: Mutex<Instant>,
+}
+
+/// Handle to the Wisp servers used by [`start_whisper`](crate::start_whisper).
+#[derive(Clone)]
+pub struct ServerPool(Arc<PoolInner>);
+
+impl ServerPool {
+	/// Connects to every server given in `opts`. Fails only if none of them can be reached.
+	/// Returns the addresses of the servers that were reached.
+	pub async fn connect(
+		opts: &WispServer,
+		v2: bool,
+	) -> Result<(Self, Vec<SocketAddr>), Box<dyn Error>> {
+		let connections = opts.pool.connections as usize;
+		if connections > 1 && opts.url.is_empty() {
+			return Err(Box::new(WhisperError::ParallelConnectionsNotSupported));
+		}
+		let servers: Vec<Arc<Server>> = opts
+			.split_servers()
+			.into_iter()
+			.map(|mut opts| {
+				if opts.helper.helper.is_some() && opts.helper_handle.is_none() {
+					opts.helper_handle = Some(Helper::start(&opts)?);
+				}
+				Ok(Arc::new(Server {
+					url: opts.describe(),
+					opts,
+					connections: (0..connections).map(|_| RwLock::new(None)).collect(),
+					monitors: (0..connections).map(|_| AtomicU64::new(0)).collect(),
+					connect_time: AtomicU64::new(0),
+					addr: Mutex::new(None),
+					last_error: Mutex::new(None),
+				}))
+			})
+			.collect::<Result<_, WhisperError>>()?;
+
+		if opts.pool.lazy {
+			let socketaddrs = join_all(servers.iter().map(|x| resolve_server(&x.opts))).await;
+			let pool = Self::new(servers, opts, v2);
+			tokio::spawn(idle_loop(Arc::downgrade(&pool.0)));
+			return Ok((pool, socketaddrs.into_iter().flatten().collect()));
+		}
+
+		if let ([server], 1) = (&servers[..], connections) {
+			// keep the original error type when there is nothing to fail over to
+			let start = Instant::now();
+			let (handle, socketaddr) = connect_to_wisp(&server.opts, v2).await?;
+			server.up(0, handle, start.elapsed(), socketaddr);
+			let pool = Self::new(servers, opts, v2);
+			pool.spawn_monitors();
+			return Ok((pool, socketaddr.into_iter().collect()));
+		}
+
+		let socketaddrs = connect_all(&servers, v2).await?;
+		let pool = Self::new(servers, opts, v2);
+		pool.spawn_monitors();
+		Ok((pool, socketaddrs))
+	}
+
+	fn new(servers: Vec<Arc<Server>>, opts: &WispServer, v2: bool) -> Self {
+		Self(Arc::new(PoolInner {
+			servers,
+			opts: opts.pool.clone(),
+			next: AtomicUsize::new(0),
+			closed: watch::Sender::new(None),
+			v2,
+			connecting: AsyncMutex::new(()),
+			last_active: Mutex::new(Instant::now()),
+		}))
+	}
+
+	fn touch(&self) {
+		*self.0.last_active.lock().unwrap() = Instant::now();
+	}
+
+	fn idle_for(&self) -> Duration {
+		self.0.last_active.lock().unwrap().elapsed()
+	}
+
+	fn is_up(&self) -> bool {
+		self.0.servers.iter().any(|x| x.handle().is_some())
+	}
+
+	/// Connects a `--lazy` pool if it isn't connected yet.
+	async fn wake(&self) -> Result<(), WhisperError> {
+		self.touch();
+		if self.is_up() {
+			return Ok(());
+		}
+		let _connecting = self.0.connecting.lock().await;
+		if self.is_up() {
+			return Ok(());
+		}
+		info!("Connecting to the Wisp servers on demand");
+		connect_all(&self.0.servers, self.0.v2).await?;
+		self.spawn_monitors();
+		self.touch();
+		Ok(())
+	}
+
+	fn spawn_monitors(&self) {
+		for server in &self.0.servers {
+			for slot in 0..server.connections.len() {
+				let generation = server.monitors[slot].fetch_add(1, Ordering::Relaxed) + 1;
+				tokio::spawn(monitor(
+					Arc::downgrade(&self.0),
+					server.clone(),
+					slot,
+					generation,
+				));
+			}
+		}
+	}
+
+	/// Health of every server, in the order they were given.
+	pub fn health(&self) -> Vec<ServerHealth> {
+		self.0.servers.iter().map(|x| x.health()).collect()
+	}
+
+	/// Servers that are up, in the order the strategy wants to try them.
+	fn candidates(&self) -> Vec<(Arc<Server>, MuxHandle)> {
+		let mut up: Vec<_> = self
+			.0
+			.servers
+			.iter()
+			.filter_map(|x| x.handle().map(|handle| (x.clone(), handle)))
+			.collect();
+		match self.0.opts.strategy {
+			Strategy::Failover => {}
+			Strategy::RoundRobin => {
+				if !up.is_empty() {
+					let next = self.0.next.fetch_add(1, Ordering::Relaxed) % up.len();
+					up.rotate_left(next);
+				}
+			}
+			Strategy::LeastLatency => {
+				up.sort_by_key(|(server, _)| server.health().latency().unwrap_or(Duration::MAX))
+			}
+		}
+		up
+	}
+
+	/// Opens a stream on a server picked by the strategy, falling back to the other servers that
+	/// are up if it fails. UDP streams only go to servers that support UDP, and fail with
+	/// [`WhisperError::UdpNotSupported`] if none of them do. Keep the guard for as long as the flow
+	/// lives.
+	pub async fn new_stream(
+		&self,
+		stream_type: StreamType,
+		host: String,
+		port: u16,
+	) -> Result<(MuxStream, StreamGuard), WhisperError> {
+		if self.0.opts.lazy {
+			self.wake().await?;
+		}
+		let mut candidates = self.candidates();
+		if stream_type == StreamType::Udp && !candidates.is_empty() {
+			candidates.retain(|(_, handle)| handle.udp());
+			if candidates.is_empty() {
+				return Err(WhisperError::UdpNotSupported);
+			}
+		}
+		let mut last_err = None;
+		for (server, handle) in candidates {
+			match handle.new_stream(stream_type, host.clone(), port).await {
+				Ok(stream) => return Ok(stream),
+				Err(err) => {
+					warn!(
+						"Wisp server {} failed to open a stream: {:?}",
+						server.url, err
+					);
+					last_err = Some(err);
+				}
+			}
+		}
+		Err(last_err
+			.map(WhisperError::other)
+			.unwrap_or(WhisperError::NoServerAvailable))
+	}
+
+	fn check_down(&self) {
+		// a --lazy pool connects again on the next flow
+		if self.0.opts.lazy {
+			return;
+		}
+		// a supervised helper comes back on its own, keep reconnecting through it until it is given
+		// up on
+		if self.0.servers.iter().any(|x| {
+			x.opts
+				.helper_handle
+				.as_ref()
+				.is_some_and(|helper| !helper.failed())
+		}) {
+			return;
+		}
+		if self.0.servers.iter().all(|x| x.handle().is_none()) {
+			let reasons: Vec<_> = self
+				.0
+				.servers
+				.iter()
+				.map(|x| {
+					let health = x.health();
+					format!("{}: {}", health.url, health.last_error.unwrap_or_default())
+				})
+				.collect();
+			self.0.closed.send_replace(Some(reasons.join("; ")));
+		}
+	}
+
+	/// Error the pool was closed with, if it was.
+	pub fn error(&self) -> Option<WhisperError> {
+		let reason = self.0.closed.borrow().clone()?;
+		match &self.0.servers[..] {
+			// a single server reports its own error as before
+			[server] => Some(WhisperError::MuxClosed(
+				server.health().last_error.unwrap_or(reason),
+			)),
+			_ => Some(WhisperError::AllServersDown(reason)),
+		}
+	}
+
+	/// Wait until every server is down.
+	pub async fn closed(&self) {
+		let mut rx = self.0.closed.subscribe();
+		let _ = rx.wait_for(|x| x.is_some()).await;
+	}
+}
+
+/// Connects every connection of every server. Fails only if none of them can be reached.
+async fn connect_all(servers: &[Arc<Server>], v2: bool) -> Result<Vec<SocketAddr>, WhisperError> {
+	let slots: Vec<_> = servers
+		.iter()
+		.flat_map(|server| (0..server.connections.len()).map(move |slot| (server, slot)))
+		.collect();
+	let results = join_all(slots.iter().map(|(server, slot)| server.connect(*slot, v2))).await;
+	let mut socketaddrs = Vec::new();
+	let mut errors = Vec::new();
+	for ((server, slot), result) in slots.iter().zip(results) {
+		match result {
+			Ok(socketaddr) => socketaddrs.extend(socketaddr),
+			Err(err) => {
+				warn!(
+					"Failed to connect to Wisp server {}: {}",
+					server.name(*slot),
+					err
+				);
+				errors.push(format!("{}: {}", server.name(*slot), err));
+			}
+		}
+	}
+	if errors.len() == slots.len() {
+		return Err(WhisperError::AllServersDown(errors.join("; ")));
+	}
+	socketaddrs.dedup();
+	Ok(socketaddrs)
+}
+
+/// Closes the connections of a `--lazy` pool once no stream has been open for `--idle-timeout`
+/// seconds.
+async fn idle_loop(pool: Weak<PoolInner>) {
+	loop {
+		sleep(Duration::from_secs(1)).await;
+		let Some(inner) = pool.upgrade() else {
+			return;
+		};
+		let pool = ServerPool(inner);
+		let idle_timeout = Duration::from_secs(pool.0.opts.idle_timeout);
+		if idle_timeout.is_zero() {
+			return;
+		}
+		let handles: Vec<_> = pool.0.servers.iter().flat_map(|x| x.handles()).collect();
+		if handles.iter().any(|x| x.streams() > 0) {
+			pool.touch();
+			continue;
+		}
+		if handles.is_empty() || pool.idle_for() < idle_timeout {
+			continue;
+		}
+
+		let _connecting = pool.0.connecting.lock().await;
+		if pool.idle_for() < idle_timeout {
+			continue;
+		}
+		info!(
+			"Closing idle Wisp connections after {}s without streams",
+			pool.0.opts.idle_timeout
+		);
+		for server in &pool.0.servers {
+			for connection in &server.connections {
+				let Some(handle) = connection.write().unwrap().take() else {
+					continue;
+				};
+				handle.close("idle".to_string());
+				tokio::spawn(async move {
+					let _ = handle.get().close().await;
+				});
+			}
+		}
+	}
+}
+
+/// Marks a connection down when its multiplexor dies and reconnects it, until a monitor of a
+/// newer `generation` takes over.
+async fn monitor(pool: Weak<PoolInner>, server: Arc<Server>, slot: usize, generation: u64) {
+	loop {
+		let handle = server.connections[slot].read().unwrap().clone();
+		if let Some(handle) = handle {
+			handle.closed().await;
+			// closed for being idle, or replaced by connecting on demand
+			if !server.holds(slot, &handle) || !server.monitors(slot, generation) {
+				return;
+			}
+			let reason = match handle.error() {
+				Some(WhisperError::MuxClosed(reason)) => reason,
+				Some(err) => err.to_string(),
+				None => "connection closed".to_string(),
+			};
+			warn!("Wisp server {} is down: {}", server.name(slot), reason);
+			server.down(slot, reason);
+		}
+
+		let Some(inner) = pool.upgrade() else {
+			return;
+		};
+		ServerPool(inner.clone()).check_down();
+		if inner.closed.borrow().is_some() || inner.opts.reprobe_interval == 0 {
+			return;
+		}
+		let interval = Duration::from_secs(inner.opts.reprobe_interval);
+		drop(inner);
+
+		loop {
+			sleep(interval).await;
+			let Some(inner) = pool.upgrade() else {
+				return;
+			};
+			// connecting on demand holds this until the new monitors are spawned
+			let _connecting = inner.connecting.lock().await;
+			// connected on demand meanwhile, with a monitor of its own
+			if inner.closed.borrow().is_some() || !server.monitors(slot, generation) {
+				return;
+			}
+			// once nothing is up, a --lazy pool waits for the next flow instead
+			if inner.opts.lazy && !ServerPool(inner.clone()).is_up() {
+				return;
+			}
+			match server.connect(slot, inner.v2).await {
+				Ok(_) => {
+					info!("Wisp server {} is up again", server.name(slot));
+					break;
+				}
+				Err(err) => {
+					warn!("Wisp server {} is still down: {}", server.name(slot), err);
+					// its helper may have been given up on meanwhile
+					ServerPool(inner.clone()).check_down();
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use tokio::{
+		net::TcpListener,
+		sync::mpsc::{unbounded_channel, UnboundedReceiver},
+		time::timeout,
+	};
+	use wisp_mux::ServerMux;
+
+	use super::*;
+	use crate::{
+		codec::DEFAULT_MAX_FRAME_LENGTH,
+		mux::tests::handle,
+		pty::{StreamRead, StreamWrite},
+	};
+
+	fn server(url: &str, connections: usize) -> Arc<Server> {
+		Arc::new(Server {
+			url: url.to_string(),
+			opts: WispServer::default(),
+			connections: (0..connections).map(|_| RwLock::new(None)).collect(),
+			monitors: (0..connections).map(|_| AtomicU64::new(0)).collect(),
+			connect_time: AtomicU64::new(0),
+			addr: Mutex::new(None),
+			last_error: Mutex::new(None),
+		})
+	}
+
+	/// Brings up `slot` of `server` over an in-memory mux that took `connect_ms` to connect.
+	async fn up(server: &Server, slot: usize, connect_ms: u64) -> (MuxHandle, ServerMux) {
+		let (handle, mux) = handle().await;
+		server.up(
+			slot,
+			handle.clone(),
+			Duration::from_millis(connect_ms),
+			None,
+		);
+		(handle, mux)
+	}
+
+	/// Wisp server over TCP. Returns options connecting to it, and the server side of every
+	/// connection it accepts.
+	async fn listen() -> (WispServer, UnboundedReceiver<ServerMux>) {
+		listen_slowly(Duration::ZERO).await
+	}
+
+	/// Like [`listen`], but takes `delay` to answer the Wisp handshake.
+	async fn listen_slowly(delay: Duration) -> (WispServer, UnboundedReceiver<ServerMux>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("tcp://{}", listener.local_addr().unwrap());
+		let (tx, rx) = unbounded_channel();
+		tokio::spawn(async move {
+			loop {
+				let (socket, _) = listener.accept().await.unwrap();
+				sleep(delay).await;
+				let (read, write) = socket.into_split();
+				let (mux, fut) = ServerMux::create(
+					StreamRead::new(read, DEFAULT_MAX_FRAME_LENGTH),
+					StreamWrite::new(write, DEFAULT_MAX_FRAME_LENGTH),
+					128,
+					None,
+				)
+				.await
+				.unwrap()
+				.with_no_required_extensions();
+				tokio::spawn(fut);
+				let _ = tx.send(mux);
+			}
+		});
+		let opts = WispServer {
+			url: vec![url.parse().unwrap()],
+			..Default::default()
+		};
+		(opts, rx)
+	}
+
+	fn lazy(opts: WispServer, idle_timeout: u64) -> WispServer {
+		WispServer {
+			pool: PoolOptions {
+				lazy: true,
+				idle_timeout,
+				..Default::default()
+			},
+			..opts
+		}
+	}
+
+	fn new_pool(servers: &[Arc<Server>], strategy: Strategy) -> ServerPool {
+		let opts = WispServer {
+			pool: PoolOptions {
+				strategy,
+				..Default::default()
+			},
+			..Default::default()
+		};
+		ServerPool::new(servers.to_vec(), &opts, false)
+	}
+
+	/// Pool of one server that is up over an in-memory mux, and the server side of it.
+	pub(crate) async fn single() -> (ServerPool, ServerMux) {
+		let servers = [server("a", 1)];
+		let (_, mux) = up(&servers[0], 0, 10).await;
+		(new_pool(&servers, Strategy::Failover), mux)
+	}
+
+	fn order(pool: &ServerPool) -> Vec<String> {
+		pool.candidates()
+			.into_iter()
+			.map(|(server, _)| server.url.clone())
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn candidates() {
+		let servers = [
+			server("a", 1),
+			server("b", 1),
+			server("c", 1),
+			server("d", 1),
+		];
+		let _a = up(&servers[0], 0, 30).await;
+		let _b = up(&servers[1], 0, 10).await;
+		let _c = up(&servers[2], 0, 20).await;
+		// d is down
+
+		let failover = new_pool(&servers, Strategy::Failover);
+		assert_eq!(order(&failover), ["a", "b", "c"]);
+		assert_eq!(order(&failover), ["a", "b", "c"]);
+
+		let round_robin = new_pool(&servers, Strategy::RoundRobin);
+		assert_eq!(order(&round_robin), ["a", "b", "c"]);
+		assert_eq!(order(&round_robin), ["b", "c", "a"]);
+		assert_eq!(order(&round_robin), ["c", "a", "b"]);
+		assert_eq!(order(&round_robin), ["a", "b", "c"]);
+
+		let least_latency = new_pool(&servers, Strategy::LeastLatency);
+		assert_eq!(order(&least_latency), ["b", "c", "a"]);
+
+		// closed connections are not candidates
+		_b.0.close("gone".into());
+		assert_eq!(order(&failover), ["a", "c"]);
+		assert_eq!(order(&least_latency), ["c", "a"]);
+	}
+
+	#[tokio::test]
+	async fn new_stream_falls_back() {
+		let servers = [server("a", 1), server("b", 1)];
+		let (a, _a) = up(&servers[0], 0, 10).await;
+		let (_, b) = up(&servers[1], 0, 10).await;
+		let pool = new_pool(&servers, Strategy::Failover);
+
+		// a still looks up but can't open streams
+		a.get().close().await.unwrap();
+		while a.get().close().await.is_ok() {
+			sleep(Duration::from_millis(10)).await;
+		}
+		let (_stream, _guard) = pool
+			.new_stream(StreamType::Tcp, "example.com".into(), 80)
+			.await
+			.unwrap();
+		let (connect, _) = b.server_new_stream().await.unwrap();
+		assert_eq!(connect.destination_hostname, "example.com");
+		assert_eq!(pool.health()[1].streams, 1);
+
+		// Wisp v1 servers don't take UDP
+		assert!(matches!(
+			pool.new_stream(StreamType::Udp, "example.com".into(), 53)
+				.await,
+			Err(WhisperError::UdpNotSupported)
+		));
+
+		servers[0].down(0, "down".into());
+		servers[1].down(0, "down".into());
+		assert!(matches!(
+			pool.new_stream(StreamType::Tcp, "example.com".into(), 80)
+				.await,
+			Err(WhisperError::NoServerAvailable)
+		));
+	}
+
+	#[tokio::test]
+	async fn least_loaded_connection() {
+		let servers = [server("a", 2)];
+		let (first, _first) = up(&servers[0], 0, 10).await;
+		let (second, _second) = up(&servers[0], 1, 10).await;
+		let pool = new_pool(&servers, Strategy::Failover);
+
+		let (_stream, guard) = pool
+			.new_stream(StreamType::Tcp, "example.com".into(), 80)
+			.await
+			.unwrap();
+		let (_stream, _guard) = pool
+			.new_stream(StreamType::Tcp, "example.com".into(), 80)
+			.await
+			.unwrap();
+		assert_eq!((first.streams(), second.streams()), (1, 1));
+		assert_eq!(pool.health()[0].connections, 2);
+		assert_eq!(pool.health()[0].streams, 2);
+		drop(guard);
+		assert_eq!(first.streams() + second.streams(), 1);
+	}
+
+	#[tokio::test]
+	async fn check_down() {
+		let servers = [server("a", 1), server("b", 2)];
+		let _a = up(&servers[0], 0, 10).await;
+		let _b = up(&servers[1], 0, 10).await;
+		let _b2 = up(&servers[1], 1, 10).await;
+		let pool = new_pool(&servers, Strategy::Failover);
+
+		// closes only once every connection of every server is down
+		servers[0].down(0, "refused".into());
+		pool.check_down();
+		servers[1].down(0, "reset".into());
+		pool.check_down();
+		assert!(pool.error().is_none());
+		servers[1].down(1, "timed out".into());
+		pool.check_down();
+		match pool.error() {
+			Some(WhisperError::AllServersDown(x)) => assert_eq!(x, "a: refused; b: timed out"),
+			err => panic!("{:?}", err.map(|x| x.to_string())),
+		}
+		timeout(Duration::from_secs(1), pool.closed())
+			.await
+			.unwrap();
+
+		// a single server reports its own error
+		let servers = [server("a", 1)];
+		let pool = new_pool(&servers, Strategy::Failover);
+		servers[0].down(0, "refused".into());
+		pool.check_down();
+		assert!(matches!(pool.error(), Some(WhisperError::MuxClosed(x)) if x == "refused"));
+	}
+
+	#[tokio::test]
+	async fn lazy_connect() {
+		let (opts, mut accepted) = listen().await;
+		let (pool, socketaddrs) = ServerPool::connect(&lazy(opts.clone(), 0), false)
+			.await
+			.unwrap();
+		assert_eq!(socketaddrs.len(), 1);
+		sleep(Duration::from_millis(100)).await;
+		assert!(accepted.try_recv().is_err());
+		assert!(!pool.health()[0].up);
+
+		// flows that arrive while connecting wait for the same connect
+		let open = || pool.new_stream(StreamType::Tcp, "example.com".into(), 80);
+		let (first, second) = tokio::join!(open(), open());
+		first.unwrap();
+		second.unwrap();
+		assert!(pool.health()[0].up);
+		let server = accepted.recv().await.unwrap();
+		for _ in 0..2 {
+			let (connect, _) = server.server_new_stream().await.unwrap();
+			assert_eq!(connect.destination_hostname, "example.com");
+		}
+		assert!(accepted.try_recv().is_err());
+	}
+
+	#[tokio::test]
+	async fn lazy_connect_fails_only_flows() {
+		// nothing to resolve up front
+		let opts = WispServer {
+			url: vec!["unix:///nonexistent/whisper.sock".parse().unwrap()],
+			..Default::default()
+		};
+		let (pool, socketaddrs) = ServerPool::connect(&lazy(opts, 0), false).await.unwrap();
+		assert!(socketaddrs.is_empty());
+		for _ in 0..2 {
+			assert!(matches!(
+				pool.new_stream(StreamType::Tcp, "example.com".into(), 80)
+					.await,
+				Err(WhisperError::AllServersDown(_))
+			));
+		}
+		assert!(pool.error().is_none());
+	}
+
+	#[tokio::test]
+	async fn idle_close() {
+		let (opts, mut accepted) = listen().await;
+		let (pool, _) = ServerPool::connect(&lazy(opts, 1), false).await.unwrap();
+		let (_stream, guard) = pool
+			.new_stream(StreamType::Tcp, "example.com".into(), 80)
+			.await
+			.unwrap();
+		let _server = accepted.recv().await.unwrap();
+
+		// an open stream keeps the connection
+		sleep(Duration::from_millis(2500)).await;
+		assert!(pool.health()[0].up);
+
+		drop(guard);
+		timeout(Duration::from_secs(5), async {
+			while pool.health()[0].up {
+				sleep(Duration::from_millis(100)).await;
+			}
+		})
+		.await
+		.unwrap();
+		assert!(pool.error().is_none());
+
+		// the next flow connects again
+		pool.new_stream(StreamType::Tcp, "example.com".into(), 80)
+			.await
+			.unwrap();
+		accepted.recv().await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn stale_monitor() {
+		// slow enough for both monitors to be reconnecting at the same time
+		let (opts, mut accepted) = listen_slowly(Duration::from_millis(500)).await;
+		let mut a = server("a", 1);
+		Arc::get_mut(&mut a).unwrap().opts = opts;
+		let servers = [a, server("b", 1)];
+		let _b = up(&servers[1], 0, 10).await;
+		let pool = ServerPool::new(
+			servers.to_vec(),
+			&WispServer {
+				pool: PoolOptions {
+					reprobe_interval: 1,
+					..Default::default()
+				},
+				..Default::default()
+			},
+			false,
+		);
+
+		// the first monitor goes down and waits to reconnect
+		let (first, _first) = up(&servers[0], 0, 10).await;
+		pool.spawn_monitors();
+		first.close("gone".into());
+		sleep(Duration::from_millis(10)).await;
+		assert!(!pool.health()[0].up);
+
+		// the connection comes back with a monitor of its own, and goes down again
+		let (second, _second) = up(&servers[0], 0, 10).await;
+		pool.spawn_monitors();
+		second.close("gone".into());
+		sleep(Duration::from_millis(10)).await;
+		assert!(!pool.health()[0].up);
+
+		// only the second monitor reconnects
+		let _server = accepted.recv().await.unwrap();
+		sleep(Duration::from_millis(2000)).await;
+		assert!(pool.health()[0].up);
+		assert!(accepted.try_recv().is_err());
+	}
+}
@@ -1,37 +1,193 @@
-use std::{io, os::fd::AsFd, path::PathBuf};
+use std::{io, os::fd::AsFd, path::PathBuf, process::Stdio};
 
 use async_trait::async_trait;
-use bytes::Bytes;
-use futures_util::{SinkExt, StreamExt};
-use tokio::fs::File;
-use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use bytes::{Bytes, BytesMut};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use log::{info, warn};
+use nix::sys::termios::{
+	cfmakeraw, cfsetspeed, tcgetattr, tcsetattr, BaudRate, ControlFlags, InputFlags, SetArg,
+};
+use tokio::{
+	fs::File,
+	io::{AsyncRead, AsyncWrite},
+	process::{Child, ChildStdin, ChildStdout, Command},
+};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite};
 use wisp_mux::{
 	ws::{Frame, LockedWebSocketWrite, Payload, WebSocketRead, WebSocketWrite},
 	WispError,
 };
 
-pub async fn open_pty(file: &PathBuf) -> Result<(PtyRead, PtyWrite), io::Error> {
+use crate::{
+	codec::{is_wisp_packet, FrameCodec, Framing},
+	FlowControl, Parity, SerialOptions,
+};
+
+/// Read half of a frame transport, exposed for the handshakes that run before the Wisp mux.
+pub trait FrameRead: Send {
+	fn stream(&mut self) -> &mut (dyn Stream<Item = Result<BytesMut, io::Error>> + Unpin + Send);
+	fn codec_mut(&mut self) -> &mut FrameCodec;
+}
+
+/// Write half of a frame transport, exposed for the handshakes that run before the Wisp mux.
+pub trait FrameWrite: Send {
+	fn sink(&mut self) -> &mut (dyn Sink<Bytes, Error = io::Error> + Unpin + Send);
+	fn codec_mut(&mut self) -> &mut FrameCodec;
+}
+
+async fn read_frame<S>(stream: &mut S) -> Result<Frame<'static>, WispError>
+where
+	S: Stream<Item = Result<BytesMut, io::Error>> + Unpin,
+{
+	loop {
+		let frame = stream
+			.next()
+			.await
+			.ok_or(WispError::WsImplSocketClosed)?
+			.map_err(|x| WispError::WsImplError(Box::new(x)))?;
+		if is_wisp_packet(&frame) {
+			return Ok(Frame::binary(Payload::Bytes(frame)));
+		}
+		warn!(
+			"Dropping {} byte frame that is not a Wisp packet: {:02x?}",
+			frame.len(),
+			&frame[..frame.len().min(16)]
+		);
+	}
+}
+
+async fn write_frame<S>(sink: &mut S, frame: Frame<'_>) -> Result<(), WispError>
+where
+	S: Sink<Bytes, Error = io::Error> + Unpin,
+{
+	use wisp_mux::ws::OpCode as O;
+	match frame.opcode {
+		O::Text | O::Binary => sink
+			.send(Bytes::copy_from_slice(frame.payload.as_ref()))
+			.await
+			.map_err(|x| WispError::WsImplError(Box::new(x))),
+		O::Close => sink
+			.close()
+			.await
+			.map_err(|x| WispError::WsImplError(Box::new(x))),
+		_ => Err(WispError::WsImplNotSupported),
+	}
+}
+
+fn baud_rate(baud: u32) -> Option<BaudRate> {
+	Some(match baud {
+		50 => BaudRate::B50,
+		75 => BaudRate::B75,
+		110 => BaudRate::B110,
+		134 => BaudRate::B134,
+		150 => BaudRate::B150,
+		200 => BaudRate::B200,
+		300 => BaudRate::B300,
+		600 => BaudRate::B600,
+		1200 => BaudRate::B1200,
+		1800 => BaudRate::B1800,
+		2400 => BaudRate::B2400,
+		4800 => BaudRate::B4800,
+		9600 => BaudRate::B9600,
+		19200 => BaudRate::B19200,
+		38400 => BaudRate::B38400,
+		57600 => BaudRate::B57600,
+		115200 => BaudRate::B115200,
+		230400 => BaudRate::B230400,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		460800 => BaudRate::B460800,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		500000 => BaudRate::B500000,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		576000 => BaudRate::B576000,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		921600 => BaudRate::B921600,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		1000000 => BaudRate::B1000000,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		1152000 => BaudRate::B1152000,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		1500000 => BaudRate::B1500000,
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		2000000 => BaudRate::B2000000,
+		_ => return None,
+	})
+}
+
+fn configure_termios(file: &File, serial: &SerialOptions) -> Result<(), io::Error> {
+	let mut termios = tcgetattr(file.as_fd())?;
+	cfmakeraw(&mut termios);
+
+	if let Some(baud) = serial.baud {
+		let baud = baud_rate(baud).ok_or(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("unsupported baud rate: {}", baud),
+		))?;
+		cfsetspeed(&mut termios, baud)?;
+	}
+	if let Some(data_bits) = serial.data_bits {
+		termios.control_flags &= !ControlFlags::CSIZE;
+		termios.control_flags |= match data_bits {
+			5 => ControlFlags::CS5,
+			6 => ControlFlags::CS6,
+			7 => ControlFlags::CS7,
+			_ => ControlFlags::CS8,
+		};
+	}
+	if let Some(parity) = serial.parity {
+		termios.control_flags &= !(ControlFlags::PARENB | ControlFlags::PARODD);
+		termios.input_flags &= !InputFlags::INPCK;
+		match parity {
+			Parity::None => {}
+			Parity::Odd => {
+				termios.control_flags |= ControlFlags::PARENB | ControlFlags::PARODD;
+				termios.input_flags |= InputFlags::INPCK;
+			}
+			Parity::Even => {
+				termios.control_flags |= ControlFlags::PARENB;
+				termios.input_flags |= InputFlags::INPCK;
+			}
+		}
+	}
+	if let Some(stop_bits) = serial.stop_bits {
+		termios
+			.control_flags
+			.set(ControlFlags::CSTOPB, stop_bits == 2);
+	}
+	if let Some(flow_control) = serial.flow_control {
+		termios
+			.control_flags
+			.set(ControlFlags::CRTSCTS, flow_control == FlowControl::RtsCts);
+		termios.input_flags.set(
+			InputFlags::IXON | InputFlags::IXOFF,
+			flow_control == FlowControl::XonXoff,
+		);
+	}
+	if serial.baud.is_some() || serial.flow_control.is_some() {
+		// real serial line, ignore modem control lines and enable the receiver
+		termios.control_flags |= ControlFlags::CLOCAL | ControlFlags::CREAD;
+	}
+
+	tcsetattr(file.as_fd(), SetArg::TCSANOW, &termios)?;
+	Ok(())
+}
+
+pub async fn open_pty(
+	file: &PathBuf,
+	serial: &SerialOptions,
+	max_frame_length: usize,
+) -> Result<(PtyRead, PtyWrite), io::Error> {
 	let rx = File::options().read(true).write(true).open(file).await?;
-	let mut termios = nix::sys::termios::tcgetattr(rx.as_fd())?.clone();
-	nix::sys::termios::cfmakeraw(&mut termios);
-	nix::sys::termios::tcsetattr(rx.as_fd(), nix::sys::termios::SetArg::TCSANOW, &termios)?;
-	let rx = LengthDelimitedCodec::builder()
-		.little_endian()
-		.max_frame_length(usize::MAX)
-		.new_framed(rx);
+	configure_termios(&rx, serial)?;
+	let rx = Framed::new(rx, FrameCodec::new(serial.framing, max_frame_length));
 
 	let tx = File::options().read(true).write(true).open(file).await?;
-	let mut termios = nix::sys::termios::tcgetattr(tx.as_fd())?.clone();
-	nix::sys::termios::cfmakeraw(&mut termios);
-	nix::sys::termios::tcsetattr(tx.as_fd(), nix::sys::termios::SetArg::TCSANOW, &termios)?;
-	let tx = LengthDelimitedCodec::builder()
-		.little_endian()
-		.max_frame_length(usize::MAX)
-		.new_framed(tx);
+	configure_termios(&tx, serial)?;
+	let tx = Framed::new(tx, FrameCodec::new(serial.framing, max_frame_length));
 	Ok((PtyRead(rx), PtyWrite(tx)))
 }
 
-pub struct PtyRead(Framed<File, LengthDelimitedCodec>);
+pub struct PtyRead(Framed<File, FrameCodec>);
 
 #[async_trait]
 impl WebSocketRead for PtyRead {
@@ -39,35 +195,26 @@ impl WebSocketRead for PtyRead {
 		&mut self,
 		_: &LockedWebSocketWrite,
 	) -> Result<Frame<'static>, WispError> {
-		Ok(Frame::binary(Payload::Bytes(
-			self.0
-				.next()
-				.await
-				.ok_or(WispError::WsImplSocketClosed)?
-				.map_err(|x| WispError::WsImplError(Box::new(x)))?,
-		)))
+		read_frame(&mut self.0).await
 	}
 }
 
-pub struct PtyWrite(Framed<File, LengthDelimitedCodec>);
+impl FrameRead for PtyRead {
+	fn stream(&mut self) -> &mut (dyn Stream<Item = Result<BytesMut, io::Error>> + Unpin + Send) {
+		&mut self.0
+	}
+
+	fn codec_mut(&mut self) -> &mut FrameCodec {
+		self.0.codec_mut()
+	}
+}
+
+pub struct PtyWrite(Framed<File, FrameCodec>);
 
 #[async_trait]
 impl WebSocketWrite for PtyWrite {
 	async fn wisp_write_frame(&mut self, frame: Frame<'_>) -> Result<(), WispError> {
-		use wisp_mux::ws::OpCode as O;
-		match frame.opcode {
-			O::Text | O::Binary => self
-				.0
-				.send(Bytes::copy_from_slice(frame.payload.as_ref()))
-				.await
-				.map_err(|x| WispError::WsImplError(Box::new(x))),
-			O::Close => self
-				.0
-				.close()
-				.await
-				.map_err(|x| WispError::WsImplError(Box::new(x))),
-			_ => Err(WispError::WsImplNotSupported),
-		}
+		write_frame(&mut self.0, frame).await
 	}
 
 	async fn wisp_close(&mut self) -> Result<(), WispError> {
@@ -83,3 +230,155 @@ impl WebSocketWrite for PtyWrite {
 		Ok(())
 	}
 }
+
+impl FrameWrite for PtyWrite {
+	fn sink(&mut self) -> &mut (dyn Sink<Bytes, Error = io::Error> + Unpin + Send) {
+		&mut self.0
+	}
+
+	fn codec_mut(&mut self) -> &mut FrameCodec {
+		self.0.codec_mut()
+	}
+}
+
+/// Length-delimited Wisp frames read from any byte stream, using the same framing as [`PtyRead`].
+pub struct StreamRead<R: AsyncRead + Unpin + Send>(FramedRead<R, FrameCodec>);
+
+impl<R: AsyncRead + Unpin + Send> StreamRead<R> {
+	pub fn new(read: R, max_frame_length: usize) -> Self {
+		Self::with_framing(read, Framing::Length, max_frame_length)
+	}
+
+	pub fn with_framing(read: R, framing: Framing, max_frame_length: usize) -> Self {
+		Self(FramedRead::new(
+			read,
+			FrameCodec::new(framing, max_frame_length),
+		))
+	}
+}
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send> WebSocketRead for StreamRead<R> {
+	async fn wisp_read_frame(
+		&mut self,
+		_: &LockedWebSocketWrite,
+	) -> Result<Frame<'static>, WispError> {
+		read_frame(&mut self.0).await
+	}
+}
+
+impl<R: AsyncRead + Unpin + Send> FrameRead for StreamRead<R> {
+	fn stream(&mut self) -> &mut (dyn Stream<Item = Result<BytesMut, io::Error>> + Unpin + Send) {
+		&mut self.0
+	}
+
+	fn codec_mut(&mut self) -> &mut FrameCodec {
+		self.0.decoder_mut()
+	}
+}
+
+/// Length-delimited Wisp frames written to any byte stream, using the same framing as [`PtyWrite`].
+pub struct StreamWrite<W: AsyncWrite + Unpin + Send>(FramedWrite<W, FrameCodec>);
+
+impl<W: AsyncWrite + Unpin + Send> StreamWrite<W> {
+	pub fn new(write: W, max_frame_length: usize) -> Self {
+		Self(FramedWrite::new(
+			write,
+			FrameCodec::new(Framing::Length, max_frame_length),
+		))
+	}
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send> WebSocketWrite for StreamWrite<W> {
+	async fn wisp_write_frame(&mut self, frame: Frame<'_>) -> Result<(), WispError> {
+		write_frame(&mut self.0, frame).await
+	}
+
+	async fn wisp_close(&mut self) -> Result<(), WispError> {
+		self.0
+			.close()
+			.await
+			.map_err(|x| WispError::WsImplError(Box::new(x)))
+	}
+}
+
+impl<W: AsyncWrite + Unpin + Send> FrameWrite for StreamWrite<W> {
+	fn sink(&mut self) -> &mut (dyn Sink<Bytes, Error = io::Error> + Unpin + Send) {
+		&mut self.0
+	}
+
+	fn codec_mut(&mut self) -> &mut FrameCodec {
+		self.0.encoder_mut()
+	}
+}
+
+pub type StdioRead = StreamRead<tokio::io::Stdin>;
+pub type StdioWrite = StreamWrite<tokio::io::Stdout>;
+
+pub fn open_stdio(max_frame_length: usize) -> (StdioRead, StdioWrite) {
+	(
+		StreamRead::new(tokio::io::stdin(), max_frame_length),
+		StreamWrite::new(tokio::io::stdout(), max_frame_length),
+	)
+}
+
+pub type CommandRead = StreamRead<ChildStdout>;
+
+/// Writes to the command's stdin and owns the child so it is killed when the mux is dropped.
+pub struct CommandWrite(StreamWrite<ChildStdin>, Child);
+
+#[async_trait]
+impl WebSocketWrite for CommandWrite {
+	async fn wisp_write_frame(&mut self, frame: Frame<'_>) -> Result<(), WispError> {
+		self.0.wisp_write_frame(frame).await
+	}
+
+	async fn wisp_close(&mut self) -> Result<(), WispError> {
+		self.0.wisp_close().await?;
+		match self.1.wait().await {
+			Ok(status) => info!("Command exited with {}", status),
+			Err(err) => warn!("Failed to wait for command: {:?}", err),
+		}
+		Ok(())
+	}
+}
+
+impl FrameWrite for CommandWrite {
+	fn sink(&mut self) -> &mut (dyn Sink<Bytes, Error = io::Error> + Unpin + Send) {
+		self.0.sink()
+	}
+
+	fn codec_mut(&mut self) -> &mut FrameCodec {
+		self.0.codec_mut()
+	}
+}
+
+/// Spawns `command` through `sh -c`, in the style of an SSH `ProxyCommand`.
+///
+/// The command's stderr is inherited so its diagnostics show up next to ours.
+pub fn open_command(
+	command: &str,
+	max_frame_length: usize,
+) -> Result<(CommandRead, CommandWrite), io::Error> {
+	let mut child = Command::new("sh")
+		.arg("-c")
+		.arg(command)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::inherit())
+		.kill_on_drop(true)
+		.spawn()?;
+	let stdout = child.stdout.take().ok_or(io::Error::new(
+		io::ErrorKind::BrokenPipe,
+		"command has no stdout",
+	))?;
+	let stdin = child.stdin.take().ok_or(io::Error::new(
+		io::ErrorKind::BrokenPipe,
+		"command has no stdin",
+	))?;
+	Ok((
+		StreamRead::new(stdout, max_frame_length),
+		CommandWrite(StreamWrite::new(stdin, max_frame_length), child),
+	))
+}
@@ -0,0 +1,437 @@
+//! Destination-based selection between Wisp servers.
+//!
+//! Every `--outbound name=url` adds a server to the named outbound (giving a name several times
+//! puts all of its servers in one [`ServerPool`]). The rules file picks the outbound for each flow,
+//! one rule per line, first match wins:
+//!
+//! ```text
+//! # outbound  destination     ports
+//! eu          10.0.0.0/8
+//! us          *               443,8000-8999
+//! eu          2001:db8::/32   53
+//! ```
+//!
+//! The destination is a CIDR, a single address or `*`; ports are optional. Flows that match no rule
+//! go to the `default` outbound, which is the `--url` server.
+//!
+//! Named outbounds use the transport options of `--url`, except `--via`, the Access credentials and
+//! the helper, which belong to the `--url` server. Whisper keeps running when every server of a
+//! named outbound is down and logs it; flows routed to that outbound fail from then on.
+
+use std::{
+	collections::HashMap,
+	error::Error,
+	fmt::Display,
+	io,
+	net::{IpAddr, SocketAddr},
+	ops::RangeInclusive,
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::Arc,
+};
+
+use clap::Args;
+use futures_util::future::{join_all, select};
+use log::warn;
+
+use crate::{
+	access::AccessOptions, helper::HelperOptions, pool::ServerPool, util::WhisperError, WispServer,
+	WispUrl,
+};
+
+pub const DEFAULT_OUTBOUND: &str = "default";
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct RouteOptions {
+	/// Named Wisp server as name=url. Giving a name more than once adds servers to that outbound.
+	#[arg(long, value_parser = parse_outbound)]
+	pub outbound: Vec<(String, WispUrl)>,
+	/// File with rules that pick the outbound for each destination
+	#[arg(long)]
+	pub rules: Option<PathBuf>,
+}
+
+fn parse_outbound(s: &str) -> Result<(String, WispUrl), String> {
+	let (name, url) = s
+		.split_once('=')
+		.ok_or("outbound must be given as name=url")?;
+	if name.is_empty() || name == DEFAULT_OUTBOUND {
+		return Err(format!("invalid outbound name {:?}", name));
+	}
+	Ok((
+		name.to_string(),
+		url.parse().map_err(|x: WhisperError| x.to_string())?,
+	))
+}
+
+/// IP network in CIDR notation. A bare address is a network with the full prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+	addr: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	pub fn contains(&self, addr: &IpAddr) -> bool {
+		match (self.addr, addr.to_canonical()) {
+			(IpAddr::V4(net), IpAddr::V4(addr)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+				u32::from(net) & mask == u32::from(addr) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(addr)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+				u128::from(net) & mask == u128::from(addr) & mask
+			}
+			_ => false,
+		}
+	}
+
+	pub fn is_ipv4(&self) -> bool {
+		self.addr.is_ipv4()
+	}
+
+	/// The network without the host bits, e.g. `192.168.1.0/24` for `192.168.1.1/24`.
+	pub fn network(&self) -> Self {
+		let addr = match self.addr {
+			IpAddr::V4(addr) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+				IpAddr::V4((u32::from(addr) & mask).into())
+			}
+			IpAddr::V6(addr) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+				IpAddr::V6((u128::from(addr) & mask).into())
+			}
+		};
+		Self {
+			addr,
+			prefix: self.prefix,
+		}
+	}
+}
+
+impl FromStr for Cidr {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix) = match s.split_once('/') {
+			Some((addr, prefix)) => (addr, Some(prefix)),
+			None => (s, None),
+		};
+		let addr: IpAddr = addr
+			.parse()
+			.map_err(|_| format!("invalid address {:?}", addr))?;
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix
+				.parse()
+				.ok()
+				.filter(|x| *x <= max)
+				.ok_or(format!("invalid prefix length {:?}", prefix))?,
+			None => max,
+		};
+		Ok(Self { addr, prefix })
+	}
+}
+
+impl Display for Cidr {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix)
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+	pub outbound: String,
+	/// `None` matches every address.
+	pub destination: Option<Cidr>,
+	/// Empty matches every port.
+	pub ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Rule {
+	pub fn matches(&self, dest: &SocketAddr) -> bool {
+		self.destination
+			.as_ref()
+			.is_none_or(|x| x.contains(&dest.ip()))
+			&& (self.ports.is_empty() || self.ports.iter().any(|x| x.contains(&dest.port())))
+	}
+}
+
+fn parse_ports(s: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+	s.split(',')
+		.map(|port| {
+			let range = match port.split_once('-') {
+				Some((start, end)) => start.parse().and_then(|start| Ok(start..=end.parse()?)),
+				None => port.parse().map(|x| x..=x),
+			};
+			range
+				.ok()
+				.filter(|x| !x.is_empty())
+				.ok_or(format!("invalid port {:?}", port))
+		})
+		.collect()
+}
+
+pub fn parse_rules(rules: &str) -> Result<Vec<Rule>, String> {
+	let mut parsed = Vec::new();
+	for (i, line) in rules.lines().enumerate() {
+		let line = line.split('#').next().unwrap_or_default().trim();
+		if line.is_empty() {
+			continue;
+		}
+		let fields: Vec<_> = line.split_whitespace().collect();
+		let rule = match fields[..] {
+			[outbound, destination] | [outbound, destination, _] => {
+				let destination = match destination {
+					"*" => Ok(None),
+					x => x.parse().map(Some),
+				};
+				let ports = fields.get(2).map_or(Ok(Vec::new()), |x| parse_ports(x));
+				destination.and_then(|destination| {
+					Ok(Rule {
+						outbound: outbound.to_string(),
+						destination,
+						ports: ports?,
+					})
+				})
+			}
+			_ => Err("expected: outbound destination [ports]".to_string()),
+		};
+		parsed.push(rule.map_err(|x| format!("line {}: {}", i + 1, x))?);
+	}
+	Ok(parsed)
+}
+
+pub fn load_rules(path: &Path) -> Result<Vec<Rule>, io::Error> {
+	parse_rules(&std::fs::read_to_string(path)?).map_err(|x| {
+		io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("{}: {}", path.display(), x),
+		)
+	})
+}
+
+pub struct Outbound {
+	pub name: String,
+	pub pool: ServerPool,
+}
+
+struct RouterInner {
+	outbounds: Vec<Outbound>,
+	/// Rules with the index of their outbound.
+	rules: Vec<(Rule, usize)>,
+}
+
+/// Picks the [`Outbound`] for each flow of [`start_whisper`](crate::start_whisper).
+#[derive(Clone)]
+pub struct Router(Arc<RouterInner>);
+
+impl Router {
+	/// Router that sends everything to one pool.
+	pub fn new(pool: ServerPool) -> Self {
+		Self(Arc::new(RouterInner {
+			outbounds: vec![Outbound {
+				name: DEFAULT_OUTBOUND.to_string(),
+				pool,
+			}],
+			rules: Vec::new(),
+		}))
+	}
+
+	/// Connects every named outbound next to the default pool and loads the rules. Returns the
+	/// addresses of the servers that were reached.
+	pub async fn connect(
+		default: ServerPool,
+		opts: &WispServer,
+		route: &RouteOptions,
+		v2: bool,
+	) -> Result<(Self, Vec<SocketAddr>), Box<dyn Error>> {
+		let mut names: Vec<String> = Vec::new();
+		let mut urls: HashMap<String, Vec<WispUrl>> = HashMap::new();
+		for (name, url) in &route.outbound {
+			if !urls.contains_key(name) {
+				names.push(name.clone());
+			}
+			urls.entry(name.clone()).or_default().push(url.clone());
+		}
+		let rules = match &route.rules {
+			Some(path) => load_rules(path)?,
+			None => Vec::new(),
+		};
+
+		let mut outbounds = vec![Outbound {
+			name: DEFAULT_OUTBOUND.to_string(),
+			pool: default,
+		}];
+		let rules = rules
+			.into_iter()
+			.map(|rule| {
+				match std::iter::once(DEFAULT_OUTBOUND)
+					.chain(names.iter().map(String::as_str))
+					.position(|x| x == rule.outbound)
+				{
+					Some(i) => Ok((rule, i)),
+					None => Err(WhisperError::UnknownOutbound(rule.outbound)),
+				}
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let pools = join_all(names.iter().map(|name| {
+			let opts = WispServer {
+				pty: None,
+				stdio: false,
+				command: None,
+				url: urls[name].clone(),
+				via: Vec::new(),
+				access: AccessOptions::default(),
+				helper: HelperOptions::default(),
+				helper_handle: None,
+				..opts.clone()
+			};
+			async move { ServerPool::connect(&opts, v2).await }
+		}))
+		.await;
+		let mut socketaddrs = Vec::new();
+		for (name, pool) in names.into_iter().zip(pools) {
+			let (pool, addrs) =
+				pool.map_err(|x| WhisperError::OutboundDown(name.clone(), x.to_string()))?;
+			socketaddrs.extend(addrs);
+			outbounds.push(Outbound { name, pool });
+		}
+
+		Ok((
+			Self(Arc::new(RouterInner { outbounds, rules })),
+			socketaddrs,
+		))
+	}
+
+	/// Outbound that should carry a flow to `dest`.
+	pub fn route(&self, dest: &SocketAddr) -> &Outbound {
+		let i = self
+			.0
+			.rules
+			.iter()
+			.find(|(rule, _)| rule.matches(dest))
+			.map_or(0, |(_, i)| *i);
+		&self.0.outbounds[i]
+	}
+
+	pub fn outbounds(&self) -> &[Outbound] {
+		&self.0.outbounds
+	}
+
+	/// Error whisper stopped with, if the default outbound is down.
+	pub fn error(&self) -> Option<WhisperError> {
+		self.0.outbounds[0].pool.error()
+	}
+
+	/// Wait until the default outbound is down. Named outbounds that go down are only logged.
+	pub async fn closed(&self) {
+		let named = async {
+			join_all(self.0.outbounds[1..].iter().map(|outbound| async move {
+				outbound.pool.closed().await;
+				if let Some(err) = outbound.pool.error() {
+					warn!(
+						"{}, flows routed to it will fail",
+						WhisperError::OutboundDown(outbound.name.clone(), err.to_string())
+					);
+				}
+			}))
+			.await;
+			std::future::pending::<()>().await
+		};
+		select(Box::pin(self.0.outbounds[0].pool.closed()), Box::pin(named)).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn addr(s: &str) -> SocketAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn cidr() {
+		let net: Cidr = "10.1.0.0/16".parse().unwrap();
+		assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+		assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+		// IPv4-mapped addresses match IPv4 networks
+		assert!(net.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+		assert!(!net.contains(&"2001:db8::1".parse().unwrap()));
+		assert_eq!(net.to_string(), "10.1.0.0/16");
+
+		let any: Cidr = "0.0.0.0/0".parse().unwrap();
+		assert!(any.contains(&"192.0.2.1".parse().unwrap()));
+		let v6: Cidr = "2001:db8::/32".parse().unwrap();
+		assert!(v6.contains(&"2001:db8:1::1".parse().unwrap()));
+		assert!(!v6.contains(&"2001:db9::1".parse().unwrap()));
+		let host: Cidr = "192.0.2.1".parse().unwrap();
+		assert_eq!(host.to_string(), "192.0.2.1/32");
+
+		for bad in [
+			"10.0.0.0/33",
+			"::/129",
+			"10.0.0/8",
+			"example.com",
+			"10.0.0.0/",
+		] {
+			assert!(bad.parse::<Cidr>().is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn rules() {
+		let rules = parse_rules(
+			"# outbound destination ports\n\
+			 eu 10.0.0.0/8\n\
+			 \n\
+			 us * 443,8000-8999 # web\n\
+			 eu 2001:db8::/32 53\n",
+		)
+		.unwrap();
+		assert_eq!(rules.len(), 3);
+		let first = |dest: &str| {
+			rules
+				.iter()
+				.find(|x| x.matches(&addr(dest)))
+				.map(|x| x.outbound.as_str())
+		};
+		assert_eq!(first("10.1.1.1:22"), Some("eu"));
+		assert_eq!(first("192.0.2.1:443"), Some("us"));
+		assert_eq!(first("192.0.2.1:8500"), Some("us"));
+		assert_eq!(first("192.0.2.1:9000"), None);
+		assert_eq!(first("[2001:db8::1]:53"), Some("eu"));
+		assert_eq!(first("[2001:db8::1]:54"), None);
+	}
+
+	#[test]
+	fn bad_rules() {
+		for (rules, err) in [
+			("eu", "line 1: expected"),
+			("eu 10.0.0.0/8 53 extra", "line 1: expected"),
+			("\neu 10.0.0.0/40", "line 2: invalid prefix length"),
+			("eu * 0-", "line 1: invalid port"),
+			("eu * 100-10", "line 1: invalid port"),
+			("eu * 70000", "line 1: invalid port"),
+		] {
+			let parsed = parse_rules(rules).unwrap_err();
+			assert!(parsed.starts_with(err), "{}: {}", rules, parsed);
+		}
+	}
+
+	#[test]
+	fn outbound() {
+		let (name, url) = parse_outbound("eu=wss://eu.example.com/").unwrap();
+		assert_eq!(name, "eu");
+		assert_eq!(url.to_string(), "wss://eu.example.com/");
+		for bad in [
+			"eu",
+			"=wss://eu.example.com/",
+			"default=wss://eu.example.com/",
+		] {
+			assert!(parse_outbound(bad).is_err(), "{}", bad);
+		}
+	}
+}
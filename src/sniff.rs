@@ -0,0 +1,334 @@
+//! Hostname sniffing for TCP flows.
+//!
+//! The TUN only sees IP addresses, so by default every Wisp stream is opened by IP. With `--sniff`,
+//! whisper reads the first bytes of each TCP flow and looks for the SNI of a TLS ClientHello or the
+//! `Host` header of an HTTP/1 request. If it finds one the stream is opened by that name, and the
+//! bytes read so far are sent on the stream before anything else. Protocols where the server speaks
+//! first send nothing, so sniffing gives up after `--sniff-timeout` milliseconds and the flow goes
+//! out by IP.
+
+use std::time::Duration;
+
+use clap::Args;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt},
+	time::{timeout_at, Instant},
+};
+
+/// Largest TLS record plus its header, enough for any ClientHello that fits in one record.
+const MAX_SNIFF: usize = 16 * 1024 + 5;
+
+const HTTP_METHODS: &[&[u8]] = &[
+	b"GET ",
+	b"POST ",
+	b"HEAD ",
+	b"PUT ",
+	b"DELETE ",
+	b"OPTIONS ",
+	b"PATCH ",
+	b"CONNECT ",
+	b"TRACE ",
+];
+
+#[derive(Debug, Clone, Args)]
+pub struct SniffOptions {
+	/// Open TCP streams by the hostname from TLS SNI or the HTTP Host header instead of by IP
+	#[arg(long)]
+	pub sniff: bool,
+	/// Milliseconds to wait for the first bytes of a TCP flow before opening it by IP
+	#[arg(long, default_value_t = 300)]
+	pub sniff_timeout: u64,
+}
+
+impl Default for SniffOptions {
+	fn default() -> Self {
+		Self {
+			sniff: false,
+			sniff_timeout: 300,
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+	Name(String),
+	NeedMore,
+	NotFound,
+}
+
+/// Reads the start of a flow and returns the bytes read, which must be replayed, and the hostname
+/// if one was found.
+pub async fn sniff<S: AsyncRead + Unpin>(
+	stream: &mut S,
+	opts: &SniffOptions,
+) -> (Vec<u8>, Option<String>) {
+	let deadline = Instant::now() + Duration::from_millis(opts.sniff_timeout);
+	let mut buf = Vec::new();
+	let mut chunk = vec![0; 4096];
+	loop {
+		match timeout_at(deadline, stream.read(&mut chunk)).await {
+			Ok(Ok(n)) if n > 0 => buf.extend_from_slice(&chunk[..n]),
+			_ => return (buf, None),
+		}
+		match parse(&buf) {
+			Sniffed::Name(name) => return (buf, Some(name)),
+			Sniffed::NeedMore if buf.len() < MAX_SNIFF => {}
+			_ => return (buf, None),
+		}
+	}
+}
+
+fn parse(buf: &[u8]) -> Sniffed {
+	match buf.first() {
+		Some(0x16) => parse_client_hello(buf),
+		Some(_) => parse_http(buf),
+		None => Sniffed::NeedMore,
+	}
+}
+
+fn valid_hostname(name: &str) -> bool {
+	!name.is_empty()
+		&& name.len() <= 253
+		&& name
+			.bytes()
+			.all(|x| x.is_ascii_alphanumeric() || matches!(x, b'-' | b'.' | b'_'))
+}
+
+fn hostname(name: &[u8]) -> Sniffed {
+	match std::str::from_utf8(name) {
+		Ok(name) if valid_hostname(name) => Sniffed::Name(name.to_ascii_lowercase()),
+		_ => Sniffed::NotFound,
+	}
+}
+
+/// Cursor over a ClientHello that runs out with `NeedMore`.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, n: usize) -> Result<&'a [u8], Sniffed> {
+		if self.0.len() < n {
+			return Err(Sniffed::NeedMore);
+		}
+		let (head, rest) = self.0.split_at(n);
+		self.0 = rest;
+		Ok(head)
+	}
+
+	fn u8(&mut self) -> Result<usize, Sniffed> {
+		Ok(self.take(1)?[0] as usize)
+	}
+
+	fn u16(&mut self) -> Result<usize, Sniffed> {
+		let x = self.take(2)?;
+		Ok(u16::from_be_bytes([x[0], x[1]]) as usize)
+	}
+}
+
+fn parse_client_hello(buf: &[u8]) -> Sniffed {
+	let sni = || {
+		let mut r = Reader(buf);
+		// record header: type, version, length
+		r.take(3)?;
+		let record_len = r.u16()?;
+		// handshake header: type and 24-bit length
+		if r.u8()? != 0x01 {
+			return Err(Sniffed::NotFound);
+		}
+		r.take(3)?;
+		// version and random
+		r.take(2 + 32)?;
+		let session_id = r.u8()?;
+		r.take(session_id)?;
+		let cipher_suites = r.u16()?;
+		r.take(cipher_suites)?;
+		let compression = r.u8()?;
+		r.take(compression)?;
+		let extensions = r.u16()?;
+		let mut extensions = Reader(r.take(extensions).map_err(|_| {
+			// the extensions are cut off by the end of the record, not of the buffer
+			if buf.len() >= 5 + record_len {
+				Sniffed::NotFound
+			} else {
+				Sniffed::NeedMore
+			}
+		})?);
+		while !extensions.0.is_empty() {
+			let kind = extensions.u16()?;
+			let len = extensions.u16()?;
+			let mut data = Reader(extensions.take(len)?);
+			if kind != 0 {
+				continue;
+			}
+			// server_name: list length, then entries of type and name
+			data.u16()?;
+			while !data.0.is_empty() {
+				let name_type = data.u8()?;
+				let len = data.u16()?;
+				let name = data.take(len)?;
+				if name_type == 0 {
+					return Ok(hostname(name));
+				}
+			}
+		}
+		Err(Sniffed::NotFound)
+	};
+	match sni() {
+		Ok(x) | Err(x) => x,
+	}
+}
+
+fn parse_http(buf: &[u8]) -> Sniffed {
+	let prefix = &buf[..buf.len().min(8)];
+	if !HTTP_METHODS.iter().any(|method| {
+		buf.starts_with(method) || (buf.len() < method.len() && method.starts_with(prefix))
+	}) {
+		return Sniffed::NotFound;
+	}
+	// only complete lines, skipping the request line
+	let Some(end) = buf.windows(2).rposition(|x| x == b"\r\n") else {
+		return Sniffed::NeedMore;
+	};
+	for line in buf[..end].split(|x| *x == b'\n').skip(1) {
+		let line = line.strip_suffix(b"\r").unwrap_or(line);
+		if line.is_empty() {
+			return Sniffed::NotFound;
+		}
+		let Some(colon) = line.iter().position(|x| *x == b':') else {
+			continue;
+		};
+		if line[..colon].eq_ignore_ascii_case(b"host") {
+			let value = line[colon + 1..].trim_ascii();
+			// strip the port, but not from an IPv6 literal
+			let host = match value.iter().rposition(|x| *x == b':') {
+				Some(i) if !value.contains(&b']') => &value[..i],
+				_ => value,
+			};
+			return hostname(host);
+		}
+	}
+	Sniffed::NeedMore
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// TLS record with a ClientHello that carries `extensions`.
+	fn client_hello(extensions: &[u8]) -> Vec<u8> {
+		let mut hello = vec![0x03, 0x03];
+		hello.extend_from_slice(&[0x42; 32]);
+		// session id, cipher suites and compression methods
+		hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+		hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+		hello.extend_from_slice(extensions);
+
+		let mut record = vec![0x16, 0x03, 0x01];
+		record.extend_from_slice(&(hello.len() as u16 + 4).to_be_bytes());
+		record.push(0x01);
+		record.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+		record.extend_from_slice(&hello);
+		record
+	}
+
+	fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+		let mut ext = kind.to_be_bytes().to_vec();
+		ext.extend_from_slice(&(data.len() as u16).to_be_bytes());
+		ext.extend_from_slice(data);
+		ext
+	}
+
+	fn server_name(name: &[u8]) -> Vec<u8> {
+		let mut list = vec![0x00];
+		list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+		list.extend_from_slice(name);
+		let mut data = (list.len() as u16).to_be_bytes().to_vec();
+		data.extend_from_slice(&list);
+		extension(0, &data)
+	}
+
+	fn name(x: &str) -> Sniffed {
+		Sniffed::Name(x.to_string())
+	}
+
+	#[test]
+	fn tls_sni() {
+		// ALPN before the server name
+		let mut extensions = extension(16, b"\x00\x03\x02h2");
+		extensions.extend(server_name(b"Example.COM"));
+		let hello = client_hello(&extensions);
+		assert_eq!(parse(&hello), name("example.com"));
+		for len in 0..hello.len() {
+			assert_eq!(parse(&hello[..len]), Sniffed::NeedMore, "{}", len);
+		}
+	}
+
+	#[test]
+	fn tls_without_sni() {
+		assert_eq!(
+			parse(&client_hello(&extension(16, b"\x00\x03\x02h2"))),
+			Sniffed::NotFound
+		);
+		assert_eq!(parse(&client_hello(&[])), Sniffed::NotFound);
+		assert_eq!(
+			parse(&client_hello(&server_name(b"bad name"))),
+			Sniffed::NotFound
+		);
+
+		// a ServerHello
+		let mut hello = client_hello(&server_name(b"example.com"));
+		hello[5] = 0x02;
+		assert_eq!(parse(&hello), Sniffed::NotFound);
+
+		// extensions longer than the record
+		let mut hello = client_hello(&server_name(b"example.com"));
+		let len = hello.len();
+		hello[len - server_name(b"example.com").len() - 1] += 1;
+		assert_eq!(parse(&hello), Sniffed::NotFound);
+	}
+
+	#[test]
+	fn http_host() {
+		let request = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nhost: Example.com:8080\r\n\r\n";
+		assert_eq!(parse(request), name("example.com"));
+		assert_eq!(
+			parse(b"POST /x HTTP/1.1\r\nHost:  example.org \r\n"),
+			name("example.org")
+		);
+		for len in [0, 2, 16, 40, 50] {
+			assert_eq!(parse(&request[..len]), Sniffed::NeedMore, "{}", len);
+		}
+	}
+
+	#[test]
+	fn http_without_host() {
+		assert_eq!(
+			parse(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"),
+			Sniffed::NotFound
+		);
+		assert_eq!(
+			parse(b"GET / HTTP/1.1\r\nHost: [::1]:8080\r\n"),
+			Sniffed::NotFound
+		);
+		assert_eq!(parse(b"SSH-2.0-OpenSSH_9.6\r\n"), Sniffed::NotFound);
+		assert_eq!(parse(b"GETX"), Sniffed::NotFound);
+	}
+
+	#[tokio::test]
+	async fn sniff_stream() {
+		let opts = SniffOptions::default();
+		let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\nbody".to_vec();
+		let (buf, name) = sniff(&mut &request[..], &opts).await;
+		assert_eq!(buf, request);
+		assert_eq!(name.as_deref(), Some("example.com"));
+
+		// the server speaks first
+		let (mut client, _server) = tokio::io::duplex(64);
+		let opts = SniffOptions {
+			sniff: true,
+			sniff_timeout: 10,
+		};
+		let (buf, name) = sniff(&mut client, &opts).await;
+		assert!(buf.is_empty());
+		assert_eq!(name, None);
+	}
+}
@@ -0,0 +1,677 @@
+//! Options for the TCP connection to the Wisp server.
+//!
+//! Once the TUN device holds the default route, the connection to the Wisp server would be routed
+//! into the tunnel itself unless there is a host route for the server. Binding the socket to the
+//! physical interface with `--bind-interface`, or marking it with `--fwmark` for an `ip rule`
+//! that sends marked packets to the main table, keeps it out of the tunnel. Apps embedding whisper
+//! through FFI can instead register a callback that protects each socket before it connects, as
+//! Android's `VpnService.protect` requires.
+//!
+//! The server's name is looked up with the system resolver, which may itself point into the tunnel.
+//! `--resolve host=address` pins the address of a name, and `--bootstrap-dns` asks the given DNS
+//! server over UDP instead, on a socket with the same options. The addresses are tried as in RFC
+//! 8305 (Happy Eyeballs): alternating between IPv6 and IPv4, starting with the family of the first
+//! address, and starting the next attempt every 250ms until one connects. `--connect-timeout`
+//! bounds the lookup and all attempts together.
+
+use std::{
+	io,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	os::fd::{AsFd, AsRawFd, RawFd},
+	pin::pin,
+	sync::RwLock,
+	time::Duration,
+};
+
+use clap::Args;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use nix::sys::socket::{setsockopt, sockopt};
+use tokio::{
+	net::{lookup_host, TcpSocket, TcpStream, UdpSocket},
+	select,
+	time::{sleep, timeout},
+};
+
+use crate::dns::{addresses, build_query, TYPE_A, TYPE_AAAA};
+
+/// Time between the start of connection attempts, from RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Time to wait for the other family's answer once one has arrived, from RFC 8305.
+const RESOLUTION_DELAY: Duration = Duration::from_millis(50);
+/// Queries sent to the bootstrap DNS server before giving up.
+const DNS_ATTEMPTS: usize = 3;
+/// Time to wait for an answer before the query is sent again.
+const DNS_RETRY: Duration = Duration::from_secs(2);
+
+/// Called with each socket before it connects, returns false if it couldn't protect it.
+pub type Protect = Box<dyn Fn(RawFd) -> bool + Send + Sync>;
+
+static PROTECT: RwLock<Option<Protect>> = RwLock::new(None);
+
+/// Sets the callback that protects sockets to the Wisp server from being routed into the tunnel.
+pub fn set_protect(protect: Option<Protect>) {
+	*PROTECT.write().unwrap() = protect;
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct SocketOptions {
+	/// Interface the connection to the Wisp server is bound to (SO_BINDTODEVICE, Linux only)
+	#[arg(long)]
+	pub bind_interface: Option<String>,
+	/// Firewall mark of the connection to the Wisp server (SO_MARK, Linux only)
+	#[arg(long, value_parser = parse_mark)]
+	pub fwmark: Option<u32>,
+	/// Local address the connection to the Wisp server is made from
+	#[arg(long)]
+	pub bind_address: Option<IpAddr>,
+	/// Seconds the connection to the Wisp server is idle before TCP keepalive probes are sent, 0
+	/// to disable
+	#[arg(long, default_value_t = 0)]
+	pub tcp_keepalive: u64,
+	/// Send small writes to the Wisp server right away instead of coalescing them (TCP_NODELAY)
+	#[arg(long)]
+	pub tcp_nodelay: bool,
+	/// Seconds to look up and connect to the Wisp server before giving up, 0 to wait forever
+	#[arg(long, default_value_t = 10)]
+	pub connect_timeout: u64,
+	/// Address to use for a name instead of looking it up, as host=address. Can be given multiple
+	/// times.
+	#[arg(long, value_parser = parse_resolve)]
+	pub resolve: Vec<(String, IpAddr)>,
+	/// DNS server to look up the Wisp server with instead of the system resolver, as address or
+	/// address:port
+	#[arg(long, value_parser = parse_dns_server)]
+	pub bootstrap_dns: Option<SocketAddr>,
+}
+
+impl Default for SocketOptions {
+	fn default() -> Self {
+		Self {
+			bind_interface: None,
+			fwmark: None,
+			bind_address: None,
+			tcp_keepalive: 0,
+			tcp_nodelay: false,
+			connect_timeout: 10,
+			resolve: Vec::new(),
+			bootstrap_dns: None,
+		}
+	}
+}
+
+fn parse_resolve(s: &str) -> Result<(String, IpAddr), String> {
+	let (host, addr) = s
+		.split_once('=')
+		.ok_or("address must be given as host=address")?;
+	let addr = addr
+		.parse()
+		.map_err(|_| format!("invalid address {:?}", addr))?;
+	Ok((normalize(host), addr))
+}
+
+fn parse_dns_server(s: &str) -> Result<SocketAddr, String> {
+	s.parse()
+		.or_else(|_| s.parse::<IpAddr>().map(|x| SocketAddr::new(x, 53)))
+		.map_err(|_| format!("invalid DNS server {:?}", s))
+}
+
+fn normalize(host: &str) -> String {
+	host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Parses a firewall mark, in decimal or in hex with `0x`.
+pub fn parse_mark(s: &str) -> Result<u32, String> {
+	match s.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(hex, 16),
+		None => s.parse(),
+	}
+	.map_err(|_| format!("invalid firewall mark {:?}", s))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(option: &str) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::Unsupported,
+		format!("{} is only supported on Linux", option),
+	)
+}
+
+impl SocketOptions {
+	/// Applies the options that keep any socket out of the tunnel and protects it.
+	fn apply_route(&self, socket: &impl AsFd) -> Result<(), io::Error> {
+		if let Some(interface) = &self.bind_interface {
+			#[cfg(any(target_os = "linux", target_os = "android"))]
+			setsockopt(
+				socket,
+				sockopt::BindToDevice,
+				&std::ffi::OsString::from(interface),
+			)?;
+			#[cfg(not(any(target_os = "linux", target_os = "android")))]
+			return Err(unsupported("--bind-interface"));
+		}
+		if let Some(mark) = self.fwmark {
+			#[cfg(target_os = "linux")]
+			setsockopt(socket, sockopt::Mark, &mark)?;
+			#[cfg(not(target_os = "linux"))]
+			return Err(unsupported("--fwmark"));
+		}
+		protect(socket)
+	}
+
+	fn apply(&self, socket: &TcpSocket) -> Result<(), io::Error> {
+		self.apply_route(socket)?;
+		if let Some(addr) = self.bind_address {
+			socket.bind(SocketAddr::new(addr, 0))?;
+		}
+		if self.tcp_keepalive != 0 {
+			let idle = self.tcp_keepalive.try_into().unwrap_or(u32::MAX);
+			setsockopt(socket, sockopt::KeepAlive, &true)?;
+			#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+			setsockopt(socket, sockopt::TcpKeepIdle, &idle)?;
+			#[cfg(any(target_os = "macos", target_os = "ios"))]
+			setsockopt(socket, sockopt::TcpKeepAlive, &idle)?;
+		}
+		if self.tcp_nodelay {
+			setsockopt(socket, sockopt::TcpNoDelay, &true)?;
+		}
+		Ok(())
+	}
+}
+
+fn protect(socket: &impl AsFd) -> Result<(), io::Error> {
+	match &*PROTECT.read().unwrap() {
+		Some(protect) if !protect(socket.as_fd().as_raw_fd()) => Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			"the socket to the Wisp server could not be protected",
+		)),
+		_ => Ok(()),
+	}
+}
+
+async fn connect_addr(addr: SocketAddr, opts: &SocketOptions) -> Result<TcpStream, io::Error> {
+	let socket = match addr {
+		SocketAddr::V4(_) => TcpSocket::new_v4()?,
+		SocketAddr::V6(_) => TcpSocket::new_v6()?,
+	};
+	opts.apply(&socket)?;
+	socket.connect(addr).await
+}
+
+/// Asks the bootstrap DNS server for the `kind` records of `host`.
+async fn query(
+	server: SocketAddr,
+	host: &str,
+	kind: u16,
+	opts: &SocketOptions,
+) -> Result<Vec<IpAddr>, io::Error> {
+	let local = match (opts.bind_address, server) {
+		(Some(addr), _) if addr.is_ipv4() == server.is_ipv4() => addr,
+		(_, SocketAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+		(_, SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+	};
+	let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+	opts.apply_route(&socket)?;
+	socket.connect(server).await?;
+
+	let id = rand::random();
+	let msg = build_query(id, host, kind);
+	let mut buf = vec![0; u16::MAX.into()];
+	for _ in 0..DNS_ATTEMPTS {
+		socket.send(&msg).await?;
+		let answer = timeout(DNS_RETRY, async {
+			loop {
+				let len = socket.recv(&mut buf).await?;
+				if len >= 12 && buf[..2] == id.to_be_bytes() {
+					return Ok::<_, io::Error>(len);
+				}
+			}
+		})
+		.await;
+		let answer = match answer {
+			Ok(len) => &buf[..len?],
+			Err(_) => continue,
+		};
+		return Ok(match addresses(answer) {
+			Some((name, addrs)) if name == host => addrs
+				.into_iter()
+				.map(|(addr, _)| addr)
+				.filter(|x| x.is_ipv6() == (kind == TYPE_AAAA))
+				.collect(),
+			_ => Vec::new(),
+		});
+	}
+	Err(io::Error::new(
+		io::ErrorKind::TimedOut,
+		format!("no answer from {} for {}", server, host),
+	))
+}
+
+/// Looks up `host` on the bootstrap DNS server, asking for IPv6 and IPv4 at the same time.
+async fn bootstrap(
+	server: SocketAddr,
+	host: &str,
+	opts: &SocketOptions,
+) -> Result<Vec<IpAddr>, io::Error> {
+	if host.split('.').any(|x| x.is_empty() || x.len() > 63) {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!("invalid host name {:?}", host),
+		));
+	}
+	let mut v6 = pin!(query(server, host, TYPE_AAAA, opts));
+	let mut v4 = pin!(query(server, host, TYPE_A, opts));
+	// once one family has addresses, the other only gets a moment longer
+	let wait = |first: &Result<Vec<IpAddr>, io::Error>| {
+		if first.as_ref().is_ok_and(|x| !x.is_empty()) {
+			RESOLUTION_DELAY
+		} else {
+			Duration::MAX
+		}
+	};
+	let (v6, v4) = select! {
+		v6 = &mut v6 => {
+			let v4 = timeout(wait(&v6), v4).await.unwrap_or(Ok(Vec::new()));
+			(v6, v4)
+		}
+		v4 = &mut v4 => {
+			let v6 = timeout(wait(&v4), v6).await.unwrap_or(Ok(Vec::new()));
+			(v6, v4)
+		}
+	};
+	match (v6, v4) {
+		(Err(err), Err(_)) => Err(err),
+		(v6, v4) => Ok(v6
+			.unwrap_or_default()
+			.into_iter()
+			.chain(v4.unwrap_or_default())
+			.collect()),
+	}
+}
+
+/// Addresses of `host`, from `--resolve`, the bootstrap DNS server or the system resolver.
+pub async fn resolve(
+	host: &str,
+	port: u16,
+	opts: &SocketOptions,
+) -> Result<Vec<SocketAddr>, io::Error> {
+	let host = host.trim_start_matches('[').trim_end_matches(']');
+	if let Ok(addr) = host.parse::<IpAddr>() {
+		return Ok(vec![SocketAddr::new(addr, port)]);
+	}
+	let name = normalize(host);
+	let pinned: Vec<_> = opts
+		.resolve
+		.iter()
+		.filter(|(x, _)| *x == name)
+		.map(|(_, addr)| SocketAddr::new(*addr, port))
+		.collect();
+	if !pinned.is_empty() {
+		return Ok(pinned);
+	}
+	match opts.bootstrap_dns {
+		Some(server) => Ok(bootstrap(server, &name, opts)
+			.await?
+			.into_iter()
+			.map(|addr| SocketAddr::new(addr, port))
+			.collect()),
+		None => Ok(lookup_host((host, port)).await?.collect()),
+	}
+}
+
+/// Orders addresses for Happy Eyeballs: alternating between the families, starting with the
+/// family of the first address.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+	let first = addrs.first().is_some_and(SocketAddr::is_ipv6);
+	let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+		addrs.into_iter().partition(|x| x.is_ipv6() == first);
+	let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+	preferred.reverse();
+	other.reverse();
+	loop {
+		match (preferred.pop(), other.pop()) {
+			(None, None) => return interleaved,
+			(a, b) => interleaved.extend(a.into_iter().chain(b)),
+		}
+	}
+}
+
+/// Races connections to `addrs`, starting the next attempt whenever one fails or takes longer
+/// than [`ATTEMPT_DELAY`].
+async fn happy_eyeballs(
+	addrs: Vec<SocketAddr>,
+	opts: &SocketOptions,
+) -> Result<TcpStream, io::Error> {
+	let mut addrs = addrs.into_iter();
+	let mut attempts = FuturesUnordered::new();
+	let mut last = None;
+	loop {
+		match addrs.next() {
+			Some(addr) => attempts.push(connect_addr(addr, opts)),
+			None if attempts.is_empty() => return Err(last.unwrap()),
+			None => {}
+		}
+		let more = addrs.len() != 0;
+		select! {
+			Some(result) = attempts.next() => match result {
+				Ok(stream) => return Ok(stream),
+				Err(err) => last = Some(err),
+			},
+			_ = sleep(ATTEMPT_DELAY), if more => {}
+		}
+	}
+}
+
+/// Connects to `host` with the socket options.
+pub async fn connect(host: &str, port: u16, opts: &SocketOptions) -> Result<TcpStream, io::Error> {
+	let connect = async {
+		let addrs: Vec<_> = resolve(host, port, opts)
+			.await?
+			.into_iter()
+			// a source address only works for its own family
+			.filter(|x| {
+				opts.bind_address
+					.is_none_or(|addr| addr.is_ipv4() == x.is_ipv4())
+			})
+			.collect();
+		if addrs.is_empty() {
+			return Err(io::Error::new(
+				io::ErrorKind::AddrNotAvailable,
+				format!("{} has no address to connect to", host),
+			));
+		}
+		happy_eyeballs(interleave(addrs), opts).await
+	};
+	if opts.connect_timeout == 0 {
+		return connect.await;
+	}
+	timeout(Duration::from_secs(opts.connect_timeout), connect)
+		.await
+		.unwrap_or_else(|_| {
+			Err(io::Error::new(
+				io::ErrorKind::TimedOut,
+				format!("connecting to {}:{} timed out", host, port),
+			))
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		cell::RefCell,
+		net::{SocketAddrV4, TcpListener},
+		os::fd::OwnedFd,
+		time::Instant,
+	};
+
+	use nix::sys::socket::{
+		bind, getsockname, listen, socket, AddressFamily, Backlog, SockFlag, SockType, SockaddrIn,
+	};
+
+	use super::*;
+	use crate::dns::{self, CLASS_IN};
+
+	thread_local! {
+		/// Whether the protect callback lets sockets of this thread through, and the fds it was
+		/// given. Other tests connect at the same time, the callback leaves their threads alone.
+		static PROTECTED: RefCell<Option<(bool, Vec<RawFd>)>> = const { RefCell::new(None) };
+	}
+
+	fn protect_with(allow: bool) {
+		set_protect(Some(Box::new(|fd| {
+			PROTECTED.with_borrow_mut(|x| match x {
+				Some((allow, fds)) => {
+					fds.push(fd);
+					*allow
+				}
+				None => true,
+			})
+		})));
+		PROTECTED.set(Some((allow, Vec::new())));
+	}
+
+	fn protected() -> Vec<RawFd> {
+		PROTECTED.take().map(|(_, fds)| fds).unwrap_or_default()
+	}
+
+	#[test]
+	fn resolve_option() {
+		assert_eq!(
+			parse_resolve("Wisp.Example.COM.=192.0.2.1"),
+			Ok(("wisp.example.com".into(), "192.0.2.1".parse().unwrap()))
+		);
+		assert_eq!(
+			parse_resolve("wisp.example.com=2001:db8::1"),
+			Ok(("wisp.example.com".into(), "2001:db8::1".parse().unwrap()))
+		);
+		for bad in [
+			"wisp.example.com",
+			"wisp.example.com=",
+			"wisp.example.com=[::1]",
+			"a=1.2.3",
+		] {
+			assert!(parse_resolve(bad).is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn dns_server_option() {
+		assert_eq!(
+			parse_dns_server("192.0.2.1"),
+			Ok("192.0.2.1:53".parse().unwrap())
+		);
+		assert_eq!(
+			parse_dns_server("192.0.2.1:5353"),
+			Ok("192.0.2.1:5353".parse().unwrap())
+		);
+		assert_eq!(
+			parse_dns_server("2001:db8::1"),
+			Ok("[2001:db8::1]:53".parse().unwrap())
+		);
+		assert_eq!(
+			parse_dns_server("[2001:db8::1]:5353"),
+			Ok("[2001:db8::1]:5353".parse().unwrap())
+		);
+		for bad in [
+			"",
+			"dns.example.com",
+			"192.0.2.1:",
+			"192.0.2.1:65536",
+			"[2001:db8::1]",
+		] {
+			assert!(parse_dns_server(bad).is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn mark_option() {
+		assert_eq!(parse_mark("0"), Ok(0));
+		assert_eq!(parse_mark("51820"), Ok(51820));
+		assert_eq!(parse_mark("0xca6c"), Ok(0xca6c));
+		assert_eq!(parse_mark("0xFFFFFFFF"), Ok(u32::MAX));
+		for bad in [
+			"",
+			"0x",
+			"0xg",
+			"-1",
+			"4294967296",
+			"0x100000000",
+			"0Xff",
+			" 1",
+		] {
+			assert!(parse_mark(bad).is_err(), "{}", bad);
+		}
+	}
+
+	#[tokio::test]
+	async fn protect_callback() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		protect_with(true);
+		let stream = connect_addr(addr, &SocketOptions::default()).await.unwrap();
+		assert_eq!(protected(), [stream.as_raw_fd()]);
+
+		protect_with(false);
+		let err = connect_addr(addr, &SocketOptions::default())
+			.await
+			.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+		assert_eq!(protected().len(), 1);
+	}
+
+	/// Address of a listener whose accept queue is full, so that connecting to it never completes.
+	fn unanswered() -> (OwnedFd, std::net::TcpStream, SocketAddr) {
+		let fd = socket(
+			AddressFamily::Inet,
+			SockType::Stream,
+			SockFlag::empty(),
+			None,
+		)
+		.unwrap();
+		bind(fd.as_raw_fd(), &SockaddrIn::new(127, 0, 0, 1, 0)).unwrap();
+		listen(&fd, Backlog::new(0).unwrap()).unwrap();
+		let addr: SockaddrIn = getsockname(fd.as_raw_fd()).unwrap();
+		let addr = SocketAddrV4::from(addr).into();
+		let queued = std::net::TcpStream::connect(addr).unwrap();
+		(fd, queued, addr)
+	}
+
+	/// Answer to `msg` with the address of the type asked for and the given id.
+	fn answer(msg: &[u8], id: u16, v4: Ipv4Addr, v6: Ipv6Addr) -> Vec<u8> {
+		let question = dns::query(msg).unwrap();
+		let data = match question.kind {
+			TYPE_A => v4.octets().to_vec(),
+			_ => v6.octets().to_vec(),
+		};
+		let mut answer = msg[..question.end].to_vec();
+		answer[..2].copy_from_slice(&id.to_be_bytes());
+		answer[2] = 0x81;
+		answer[3] = 0x80;
+		answer[7] = 1;
+		answer.extend_from_slice(&[0xc0, 12]);
+		answer.extend_from_slice(&question.kind.to_be_bytes());
+		answer.extend_from_slice(&CLASS_IN.to_be_bytes());
+		answer.extend_from_slice(&60u32.to_be_bytes());
+		answer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+		answer.extend_from_slice(&data);
+		answer
+	}
+
+	/// DNS server that answers every query twice: first with the wrong id and other addresses,
+	/// then with the right id.
+	async fn dns_server() -> SocketAddr {
+		let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = socket.local_addr().unwrap();
+		tokio::spawn(async move {
+			let mut buf = [0; 512];
+			loop {
+				let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+				let msg = &buf[..len];
+				let id = u16::from_be_bytes([msg[0], msg[1]]);
+				let wrong = answer(
+					msg,
+					!id,
+					Ipv4Addr::new(198, 51, 100, 1),
+					"2001:db8::bad".parse().unwrap(),
+				);
+				socket.send_to(&wrong, peer).await.unwrap();
+				let right = answer(
+					msg,
+					id,
+					Ipv4Addr::new(192, 0, 2, 1),
+					"2001:db8::1".parse().unwrap(),
+				);
+				socket.send_to(&right, peer).await.unwrap();
+			}
+		});
+		addr
+	}
+
+	#[test]
+	fn interleave_families() {
+		let v4 = |x| SocketAddr::new(Ipv4Addr::new(192, 0, 2, x).into(), 443);
+		let v6 = |x| SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, x).into(), 443);
+		assert_eq!(
+			interleave(vec![v4(1), v4(2), v6(1), v4(3), v6(2), v6(3), v6(4)]),
+			[v4(1), v6(1), v4(2), v6(2), v4(3), v6(3), v6(4)]
+		);
+		assert_eq!(
+			interleave(vec![v6(1), v4(1), v4(2), v4(3)]),
+			[v6(1), v4(1), v4(2), v4(3)]
+		);
+		assert_eq!(interleave(vec![v4(1), v4(2)]), [v4(1), v4(2)]);
+		assert_eq!(interleave(Vec::new()), []);
+	}
+
+	#[tokio::test]
+	async fn next_attempt_wins() {
+		let (_fd, _queued, unanswered) = unanswered();
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let start = Instant::now();
+		let stream = happy_eyeballs(vec![unanswered, addr], &SocketOptions::default())
+			.await
+			.unwrap();
+		assert!(start.elapsed() >= ATTEMPT_DELAY);
+		assert_eq!(stream.peer_addr().unwrap(), addr);
+	}
+
+	#[tokio::test]
+	async fn bootstrap_merges_families() {
+		let server = dns_server().await;
+		let addrs = bootstrap(server, "wisp.example.com", &SocketOptions::default())
+			.await
+			.unwrap();
+		assert_eq!(
+			addrs,
+			[
+				"2001:db8::1".parse::<IpAddr>().unwrap(),
+				"192.0.2.1".parse().unwrap()
+			]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn bootstrap_times_out() {
+		let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let err = bootstrap(
+			server.local_addr().unwrap(),
+			"wisp.example.com",
+			&SocketOptions::default(),
+		)
+		.await
+		.unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+	}
+
+	#[tokio::test]
+	async fn pinned_addresses() {
+		let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let opts = SocketOptions {
+			resolve: vec![
+				parse_resolve("wisp.example.com=192.0.2.1").unwrap(),
+				parse_resolve("other.example.com=192.0.2.2").unwrap(),
+				parse_resolve("wisp.example.com=2001:db8::1").unwrap(),
+			],
+			bootstrap_dns: Some(server.local_addr().unwrap()),
+			..Default::default()
+		};
+		assert_eq!(
+			resolve("Wisp.Example.COM.", 443, &opts).await.unwrap(),
+			[
+				"192.0.2.1:443".parse::<SocketAddr>().unwrap(),
+				"[2001:db8::1]:443".parse().unwrap()
+			]
+		);
+		assert_eq!(
+			resolve("[2001:db8::2]", 443, &opts).await.unwrap(),
+			["[2001:db8::2]:443".parse::<SocketAddr>().unwrap()]
+		);
+		let mut buf = [0; 512];
+		assert_eq!(
+			server.try_recv(&mut buf).unwrap_err().kind(),
+			io::ErrorKind::WouldBlock
+		);
+	}
+}
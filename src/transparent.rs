@@ -0,0 +1,703 @@
+//! Transparent proxy inbound for Linux routers and gateways.
+//!
+//! Instead of a TUN device, `whisper transparent` takes flows that the firewall diverts to it and
+//! forwards them like [`start_whisper`](crate::start_whisper) does. With `--method redirect`, TCP is
+//! sent to the listener by iptables `REDIRECT` and the destination is read with `SO_ORIGINAL_DST`.
+//! With `--method tproxy`, TCP and UDP are sent by iptables `TPROXY`, the listener accepts
+//! connections for any address with `IP_TRANSPARENT`, and UDP destinations come from
+//! `IP_RECVORIGDSTADDR`. Replies to UDP are sent from the original destination, so clients see the
+//! server they talked to.
+//!
+//! `--firewall` installs the rules for the method in a `WHISPER` chain on start and removes them on
+//! exit. Traffic to reserved and local addresses is left alone.
+
+use std::{
+	error::Error,
+	io::{self, IoSliceMut},
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+	os::fd::{AsFd, AsRawFd, OwnedFd},
+	process::Command,
+	sync::Arc,
+};
+
+use log::{debug, error, info, warn};
+use nix::{
+	cmsg_space, libc,
+	sys::socket::{
+		bind, getsockopt, listen, recvmsg, setsockopt, socket, sockopt, AddressFamily, Backlog,
+		ControlMessageOwned, MsgFlags, SockFlag, SockType, SockaddrStorage,
+	},
+};
+use tokio::{
+	io::unix::AsyncFd,
+	net::{TcpListener, TcpStream},
+	select,
+};
+
+use crate::{
+	flow::FlowContext, forward_tcp, route::Router, TransparentMethod, TransparentOptions,
+	UdpForwarder, UdpReply,
+};
+
+/// Chain the firewall rules are installed in.
+const CHAIN: &str = "WHISPER";
+/// Destinations that are never diverted.
+pub(crate) const RESERVED_V4: &[&str] = &[
+	"0.0.0.0/8",
+	"10.0.0.0/8",
+	"100.64.0.0/10",
+	"127.0.0.0/8",
+	"169.254.0.0/16",
+	"172.16.0.0/12",
+	"192.168.0.0/16",
+	"224.0.0.0/4",
+	"240.0.0.0/4",
+];
+const RESERVED_V6: &[&str] = &["::1/128", "fc00::/7", "fe80::/10", "ff00::/8"];
+
+fn family(addr: &SocketAddr) -> AddressFamily {
+	match addr {
+		SocketAddr::V4(_) => AddressFamily::Inet,
+		SocketAddr::V6(_) => AddressFamily::Inet6,
+	}
+}
+
+/// Lets the socket use addresses that aren't local, as TPROXY needs.
+fn set_transparent(sock: &OwnedFd, addr: &SocketAddr) -> Result<(), io::Error> {
+	match addr {
+		SocketAddr::V4(_) => setsockopt(sock, sockopt::IpTransparent, &true)?,
+		SocketAddr::V6(_) => {
+			let on: libc::c_int = 1;
+			// SAFETY: the option value is a c_int that outlives the call
+			let ret = unsafe {
+				libc::setsockopt(
+					sock.as_raw_fd(),
+					libc::SOL_IPV6,
+					libc::IPV6_TRANSPARENT,
+					&on as *const _ as *const libc::c_void,
+					std::mem::size_of_val(&on) as libc::socklen_t,
+				)
+			};
+			if ret < 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+	}
+	Ok(())
+}
+
+fn transparent_socket(addr: SocketAddr, kind: SockType) -> Result<OwnedFd, io::Error> {
+	let sock = socket(
+		family(&addr),
+		kind,
+		SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+		None,
+	)?;
+	setsockopt(&sock, sockopt::ReuseAddr, &true)?;
+	set_transparent(&sock, &addr)?;
+	Ok(sock)
+}
+
+fn tcp_listener(opts: &TransparentOptions) -> Result<TcpListener, io::Error> {
+	let sock = match opts.method {
+		TransparentMethod::Redirect => socket(
+			family(&opts.listen),
+			SockType::Stream,
+			SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+			None,
+		)?,
+		TransparentMethod::Tproxy => transparent_socket(opts.listen, SockType::Stream)?,
+	};
+	setsockopt(&sock, sockopt::ReuseAddr, &true)?;
+	bind(sock.as_raw_fd(), &SockaddrStorage::from(opts.listen))?;
+	listen(&sock, Backlog::MAXCONN)?;
+	TcpListener::from_std(sock.into())
+}
+
+/// Destination a diverted TCP connection was meant for.
+fn original_dst(stream: &TcpStream, method: TransparentMethod) -> Result<SocketAddr, io::Error> {
+	let local = stream.local_addr()?;
+	if method == TransparentMethod::Tproxy {
+		return Ok(local);
+	}
+	Ok(match local {
+		SocketAddr::V4(_) => v4(getsockopt(&stream.as_fd(), sockopt::OriginalDst)?),
+		SocketAddr::V6(_) => v6(getsockopt(&stream.as_fd(), sockopt::Ip6tOriginalDst)?),
+	})
+}
+
+fn v4(addr: libc::sockaddr_in) -> SocketAddr {
+	SocketAddr::V4(SocketAddrV4::new(
+		Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+		u16::from_be(addr.sin_port),
+	))
+}
+
+fn v6(addr: libc::sockaddr_in6) -> SocketAddr {
+	SocketAddr::V6(SocketAddrV6::new(
+		Ipv6Addr::from(addr.sin6_addr.s6_addr),
+		u16::from_be(addr.sin6_port),
+		addr.sin6_flowinfo,
+		addr.sin6_scope_id,
+	))
+}
+
+/// Whether `dest` is the listener itself rather than somewhere a flow was diverted from.
+fn is_listener(dest: SocketAddr, listen: SocketAddr) -> bool {
+	dest.port() == listen.port()
+		&& (listen.ip().is_unspecified() || dest.ip() == listen.ip() || dest.ip().is_loopback())
+}
+
+/// UDP socket that receives datagrams diverted by TPROXY with their original destination.
+struct UdpListener(AsyncFd<OwnedFd>);
+
+impl UdpListener {
+	fn bind(addr: SocketAddr) -> Result<Self, io::Error> {
+		let sock = transparent_socket(addr, SockType::Datagram)?;
+		match addr {
+			SocketAddr::V4(_) => setsockopt(&sock, sockopt::Ipv4OrigDstAddr, &true)?,
+			SocketAddr::V6(_) => setsockopt(&sock, sockopt::Ipv6OrigDstAddr, &true)?,
+		}
+		bind(sock.as_raw_fd(), &SockaddrStorage::from(addr))?;
+		Ok(Self(AsyncFd::new(sock)?))
+	}
+
+	/// Receives a datagram and returns its length, source and original destination.
+	async fn recv(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr, SocketAddr), io::Error> {
+		loop {
+			let mut guard = self.0.readable().await?;
+			if let Ok(x) = guard.try_io(|sock| recv_orig_dst(sock.as_raw_fd(), buf)) {
+				return x;
+			}
+		}
+	}
+}
+
+fn recv_orig_dst(
+	sock: std::os::fd::RawFd,
+	buf: &mut [u8],
+) -> Result<(usize, SocketAddr, SocketAddr), io::Error> {
+	let mut iov = [IoSliceMut::new(buf)];
+	let mut cmsg = cmsg_space!(libc::sockaddr_in6);
+	let msg = recvmsg::<SockaddrStorage>(sock, &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+	let src = msg.address.and_then(|x| {
+		x.as_sockaddr_in()
+			.map(|x| SocketAddr::V4((*x).into()))
+			.or_else(|| x.as_sockaddr_in6().map(|x| SocketAddr::V6((*x).into())))
+	});
+	let dest = msg.cmsgs().find_map(|x| match x {
+		ControlMessageOwned::Ipv4OrigDstAddr(addr) => Some(v4(addr)),
+		ControlMessageOwned::Ipv6OrigDstAddr(addr) => Some(v6(addr)),
+		_ => None,
+	});
+	match (src, dest) {
+		(Some(src), Some(dest)) => Ok((msg.bytes, src, dest)),
+		_ => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"datagram without original destination",
+		)),
+	}
+}
+
+/// Way back to a UDP client, from a socket bound to the destination it sent to.
+fn udp_reply(src: SocketAddr, dest: SocketAddr) -> Result<UdpReply, io::Error> {
+	let sock = transparent_socket(dest, SockType::Datagram)?;
+	bind(sock.as_raw_fd(), &SockaddrStorage::from(dest))?;
+	let sock = std::net::UdpSocket::from(sock);
+	Ok(Arc::new(move |pkt: &[u8]| {
+		if let Err(err) = sock.send_to(pkt, src) {
+			debug!("error while sending udp packet to {:?}: {:?}", src, err);
+		}
+	}))
+}
+
+/// Forwards flows diverted to whisper by the firewall until an outbound is down.
+pub async fn start_transparent(
+	router: Router,
+	context: FlowContext,
+	opts: &TransparentOptions,
+) -> Result<(), Box<dyn Error>> {
+	let tcp = tcp_listener(opts)?;
+	let udp = match opts.method {
+		TransparentMethod::Redirect => None,
+		TransparentMethod::Tproxy => Some(UdpListener::bind(opts.listen)?),
+	};
+
+	let tcp_router = router.clone();
+	let tcp_context = context.clone();
+	let (method, listen) = (opts.method, opts.listen);
+	let tcp_handle = tokio::spawn(async move {
+		loop {
+			let (stream, src) = match tcp.accept().await {
+				Ok(x) => x,
+				Err(err) => {
+					warn!("error while accepting tcp: {:?}", err);
+					continue;
+				}
+			};
+			let dest = match original_dst(&stream, method) {
+				Ok(dest) => dest,
+				// conntrack has no NAT entry for connections made to the listener directly
+				Err(err) if err.kind() == io::ErrorKind::NotFound => {
+					debug!("refused tcp from {:?}: not redirected", src);
+					continue;
+				}
+				Err(err) => {
+					error!("error while reading destination of {:?}: {:?}", src, err);
+					continue;
+				}
+			};
+			// connected to the listener without being diverted, forwarding would loop
+			if is_listener(dest, listen) {
+				debug!("refused tcp from {:?}: not redirected", src);
+				continue;
+			}
+			tokio::spawn(forward_tcp(
+				tcp_router.clone(),
+				tcp_context.clone(),
+				stream,
+				src,
+				dest,
+			));
+		}
+	});
+
+	let forwarder = UdpForwarder::new(router.clone(), context);
+	let udp_handle = tokio::spawn(async move {
+		let Some(udp) = udp else {
+			return std::future::pending().await;
+		};
+		let mut buf = vec![0; u16::MAX.into()];
+		let mut warned = false;
+		loop {
+			let (len, src, dest) = match udp.recv(&mut buf).await {
+				Ok(x) => x,
+				Err(err) => {
+					debug!("error while receiving udp: {:?}", err);
+					continue;
+				}
+			};
+			let pkt = buf[..len].to_vec();
+			let reply = || udp_reply(src, dest);
+			// without a TUN device there is no way to answer with an ICMP error
+			let refuse = |_: &[u8]| {
+				if !std::mem::replace(&mut warned, true) {
+					warn!(
+						"Dropping udp from {:?} to {:?} that can't be forwarded, e.g. because the Wisp server has no UDP support. Further drops are only logged at debug level.",
+						src, dest
+					);
+				}
+			};
+			forwarder.forward(pkt, src, dest, reply, refuse).await;
+		}
+	});
+
+	info!("Accepting diverted flows on {}", opts.listen);
+	select! {
+		x = tcp_handle => x?,
+		x = udp_handle => x?,
+		_ = router.closed() => {}
+	}
+
+	info!("Broke from whisper loop.");
+	if let Some(err) = router.error() {
+		return Err(Box::new(err));
+	}
+	Ok(())
+}
+
+/// Runs one firewall command.
+type Run = fn(&[String]) -> Result<(), io::Error>;
+
+/// Firewall rules for [`start_transparent`], removed again when dropped.
+pub struct Firewall {
+	/// Commands that undo what was installed, in the order they were installed.
+	undo: Vec<Vec<String>>,
+	run: Run,
+}
+
+/// Runs a command, failing with its stderr if it exits unsuccessfully.
+pub(crate) fn command(args: &[String]) -> Result<(), io::Error> {
+	output(args).map(drop)
+}
+
+/// Runs a command and returns what it printed.
+pub(crate) fn output(args: &[String]) -> Result<String, io::Error> {
+	let output = Command::new(&args[0])
+		.args(&args[1..])
+		.output()
+		.map_err(|x| io::Error::new(x.kind(), format!("{}: {}", args[0], x)))?;
+	if output.status.success() {
+		return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+	}
+	Err(io::Error::other(format!(
+		"{} failed: {}",
+		args.join(" "),
+		String::from_utf8_lossy(&output.stderr).trim()
+	)))
+}
+
+pub(crate) fn words(s: &str) -> Vec<String> {
+	s.split_whitespace().map(str::to_string).collect()
+}
+
+impl Firewall {
+	/// Commands that install the rules for `opts`, each with the commands that undo it.
+	fn rules(opts: &TransparentOptions) -> Vec<(Vec<String>, Vec<Vec<String>>)> {
+		let (iptables, ip, reserved) = match opts.listen {
+			SocketAddr::V4(_) => ("iptables", "ip", RESERVED_V4),
+			SocketAddr::V6(_) => ("ip6tables", "ip -6", RESERVED_V6),
+		};
+		let port = opts.listen.port();
+		let (table, jump) = match opts.method {
+			TransparentMethod::Redirect => ("nat", "-p tcp -j"),
+			TransparentMethod::Tproxy => ("mangle", "-j"),
+		};
+		let ipt = |x: String| words(&format!("{} -t {} {}", iptables, table, x));
+
+		let mut rules = Vec::new();
+		if opts.method == TransparentMethod::Tproxy {
+			let rule = format!("fwmark {} lookup {}", opts.tproxy_mark, opts.tproxy_table);
+			let route = format!("local default dev lo table {}", opts.tproxy_table);
+			rules.push((
+				words(&format!("{} rule add {}", ip, rule)),
+				vec![words(&format!("{} rule del {}", ip, rule))],
+			));
+			rules.push((
+				words(&format!("{} route add {}", ip, route)),
+				vec![words(&format!("{} route del {}", ip, route))],
+			));
+		}
+		rules.push((
+			ipt(format!("-N {}", CHAIN)),
+			vec![ipt(format!("-F {}", CHAIN)), ipt(format!("-X {}", CHAIN))],
+		));
+		rules.push((
+			ipt(format!(
+				"-A {} -m addrtype --dst-type LOCAL -j RETURN",
+				CHAIN
+			)),
+			Vec::new(),
+		));
+		for net in reserved {
+			rules.push((
+				ipt(format!("-A {} -d {} -j RETURN", CHAIN, net)),
+				Vec::new(),
+			));
+		}
+		match opts.method {
+			TransparentMethod::Redirect => rules.push((
+				ipt(format!(
+					"-A {} -p tcp -j REDIRECT --to-ports {}",
+					CHAIN, port
+				)),
+				Vec::new(),
+			)),
+			TransparentMethod::Tproxy => {
+				for protocol in ["tcp", "udp"] {
+					rules.push((
+						ipt(format!(
+							"-A {} -p {} -j TPROXY --on-port {} --tproxy-mark {}",
+							CHAIN, protocol, port, opts.tproxy_mark
+						)),
+						Vec::new(),
+					));
+				}
+			}
+		}
+		rules.push((
+			ipt(format!("-A PREROUTING {} {}", jump, CHAIN)),
+			vec![ipt(format!("-D PREROUTING {} {}", jump, CHAIN))],
+		));
+		rules
+	}
+
+	/// Installs the rules for `opts`, replacing rules left behind by an earlier run.
+	pub fn install(opts: &TransparentOptions) -> Result<Self, io::Error> {
+		Self::install_with(opts, command)
+	}
+
+	fn install_with(opts: &TransparentOptions, run: Run) -> Result<Self, io::Error> {
+		let rules = Self::rules(opts);
+		for (_, undo) in rules.iter().rev() {
+			for args in undo {
+				let _ = run(args);
+			}
+		}
+
+		let mut firewall = Self {
+			undo: Vec::new(),
+			run,
+		};
+		for (args, undo) in rules {
+			run(&args)?;
+			firewall.undo.extend(undo.into_iter().rev());
+		}
+		info!("Installed firewall rules for {:?}", opts.method);
+		Ok(firewall)
+	}
+}
+
+impl Drop for Firewall {
+	fn drop(&mut self) {
+		if self.undo.is_empty() {
+			return;
+		}
+		for args in self.undo.iter().rev() {
+			if let Err(err) = (self.run)(args) {
+				warn!("Failed to remove firewall rule: {}", err);
+			}
+		}
+		info!("Removed firewall rules");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, net::UdpSocket};
+
+	use super::*;
+
+	thread_local! {
+		static RAN: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+	}
+
+	fn record(args: &[String]) -> Result<(), io::Error> {
+		RAN.with_borrow_mut(|ran| ran.push(args.join(" ")));
+		Ok(())
+	}
+
+	fn fail_on_prerouting(args: &[String]) -> Result<(), io::Error> {
+		record(args)?;
+		match args.join(" ").contains("-A PREROUTING") {
+			true => Err(io::Error::other("no PREROUTING")),
+			false => Ok(()),
+		}
+	}
+
+	fn ran() -> Vec<String> {
+		RAN.with_borrow_mut(std::mem::take)
+	}
+
+	fn opts(method: TransparentMethod, listen: &str) -> TransparentOptions {
+		TransparentOptions {
+			method,
+			listen: listen.parse().unwrap(),
+			firewall: true,
+			tproxy_mark: 1,
+			tproxy_table: 100,
+		}
+	}
+
+	fn rules(opts: &TransparentOptions) -> Vec<(String, Vec<String>)> {
+		Firewall::rules(opts)
+			.into_iter()
+			.map(|(args, undo)| (args.join(" "), undo.iter().map(|x| x.join(" ")).collect()))
+			.collect()
+	}
+
+	#[test]
+	fn redirect_rules() {
+		let mut expected = vec![
+			(
+				"iptables -t nat -N WHISPER".to_string(),
+				vec![
+					"iptables -t nat -F WHISPER".to_string(),
+					"iptables -t nat -X WHISPER".to_string(),
+				],
+			),
+			(
+				"iptables -t nat -A WHISPER -m addrtype --dst-type LOCAL -j RETURN".to_string(),
+				Vec::new(),
+			),
+		];
+		for net in RESERVED_V4 {
+			expected.push((
+				format!("iptables -t nat -A WHISPER -d {} -j RETURN", net),
+				Vec::new(),
+			));
+		}
+		expected.push((
+			"iptables -t nat -A WHISPER -p tcp -j REDIRECT --to-ports 12345".to_string(),
+			Vec::new(),
+		));
+		expected.push((
+			"iptables -t nat -A PREROUTING -p tcp -j WHISPER".to_string(),
+			vec!["iptables -t nat -D PREROUTING -p tcp -j WHISPER".to_string()],
+		));
+		assert_eq!(
+			rules(&opts(TransparentMethod::Redirect, "0.0.0.0:12345")),
+			expected
+		);
+	}
+
+	#[test]
+	fn tproxy_rules() {
+		let rules = rules(&opts(TransparentMethod::Tproxy, "[::]:12345"));
+		assert_eq!(
+			rules[..4],
+			[
+				(
+					"ip -6 rule add fwmark 1 lookup 100".to_string(),
+					vec!["ip -6 rule del fwmark 1 lookup 100".to_string()],
+				),
+				(
+					"ip -6 route add local default dev lo table 100".to_string(),
+					vec!["ip -6 route del local default dev lo table 100".to_string()],
+				),
+				(
+					"ip6tables -t mangle -N WHISPER".to_string(),
+					vec![
+						"ip6tables -t mangle -F WHISPER".to_string(),
+						"ip6tables -t mangle -X WHISPER".to_string(),
+					],
+				),
+				(
+					"ip6tables -t mangle -A WHISPER -m addrtype --dst-type LOCAL -j RETURN"
+						.to_string(),
+					Vec::new(),
+				),
+			]
+		);
+		let reserved: Vec<_> = rules[4..4 + RESERVED_V6.len()]
+			.iter()
+			.map(|(args, _)| args.clone())
+			.collect();
+		assert_eq!(
+			reserved,
+			RESERVED_V6
+				.iter()
+				.map(|net| format!("ip6tables -t mangle -A WHISPER -d {} -j RETURN", net))
+				.collect::<Vec<_>>()
+		);
+		assert_eq!(
+			rules[4 + RESERVED_V6.len()..],
+			[
+				(
+					"ip6tables -t mangle -A WHISPER -p tcp -j TPROXY --on-port 12345 --tproxy-mark 1"
+						.to_string(),
+					Vec::new(),
+				),
+				(
+					"ip6tables -t mangle -A WHISPER -p udp -j TPROXY --on-port 12345 --tproxy-mark 1"
+						.to_string(),
+					Vec::new(),
+				),
+				(
+					"ip6tables -t mangle -A PREROUTING -j WHISPER".to_string(),
+					vec!["ip6tables -t mangle -D PREROUTING -j WHISPER".to_string()],
+				),
+			]
+		);
+	}
+
+	#[test]
+	fn undo_in_reverse() {
+		let opts = opts(TransparentMethod::Tproxy, "0.0.0.0:12345");
+		let undo = [
+			"iptables -t mangle -D PREROUTING -j WHISPER",
+			"iptables -t mangle -F WHISPER",
+			"iptables -t mangle -X WHISPER",
+			"ip route del local default dev lo table 100",
+			"ip rule del fwmark 1 lookup 100",
+		];
+
+		let firewall = Firewall::install_with(&opts, record).unwrap();
+		let installed = ran();
+		// rules left behind by an earlier run are removed first
+		assert_eq!(installed[..undo.len()], undo);
+		assert_eq!(
+			installed[undo.len()..],
+			rules(&opts)
+				.into_iter()
+				.map(|(args, _)| args)
+				.collect::<Vec<_>>()
+		);
+		drop(firewall);
+		assert_eq!(ran(), undo);
+
+		// a failed install removes what it installed so far
+		assert!(Firewall::install_with(&opts, fail_on_prerouting).is_err());
+		let ran = ran();
+		assert!(ran[ran.len() - undo.len()].contains("-A PREROUTING"));
+		assert_eq!(ran[ran.len() - undo.len() + 1..], undo[1..]);
+	}
+
+	#[test]
+	fn listener() {
+		let any = "0.0.0.0:12345".parse().unwrap();
+		assert!(is_listener("1.2.3.4:12345".parse().unwrap(), any));
+		assert!(!is_listener("1.2.3.4:443".parse().unwrap(), any));
+
+		let lan = "192.168.1.1:12345".parse().unwrap();
+		assert!(is_listener("192.168.1.1:12345".parse().unwrap(), lan));
+		assert!(is_listener("127.0.0.1:12345".parse().unwrap(), lan));
+		assert!(!is_listener("8.8.8.8:12345".parse().unwrap(), lan));
+		assert!(!is_listener("192.168.1.1:443".parse().unwrap(), lan));
+	}
+
+	#[test]
+	fn sockaddrs() {
+		// SAFETY: all-zero bytes are valid for these plain C structs
+		let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+		addr.sin_port = 443u16.to_be();
+		addr.sin_addr.s_addr = u32::from(Ipv4Addr::new(1, 2, 3, 4)).to_be();
+		assert_eq!(v4(addr), "1.2.3.4:443".parse().unwrap());
+
+		// SAFETY: as above
+		let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+		addr.sin6_port = 53u16.to_be();
+		addr.sin6_addr.s6_addr = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+		addr.sin6_scope_id = 2;
+		assert_eq!(
+			v6(addr),
+			SocketAddr::V6(SocketAddrV6::new("2001:db8::1".parse().unwrap(), 53, 0, 2))
+		);
+	}
+
+	#[test]
+	fn original_destination() {
+		let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let mut buf = [0; 16];
+
+		// only with the option set
+		sender
+			.send_to(b"ping", receiver.local_addr().unwrap())
+			.unwrap();
+		let err = recv_orig_dst(receiver.as_raw_fd(), &mut buf).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+		setsockopt(&receiver, sockopt::Ipv4OrigDstAddr, &true).unwrap();
+		sender
+			.send_to(b"ping", receiver.local_addr().unwrap())
+			.unwrap();
+		assert_eq!(
+			recv_orig_dst(receiver.as_raw_fd(), &mut buf).unwrap(),
+			(
+				4,
+				sender.local_addr().unwrap(),
+				receiver.local_addr().unwrap()
+			)
+		);
+		assert_eq!(&buf[..4], b"ping");
+
+		// IPv6 where the host has it
+		let Ok(receiver) = UdpSocket::bind("[::1]:0") else {
+			return;
+		};
+		let sender = UdpSocket::bind("[::1]:0").unwrap();
+		setsockopt(&receiver, sockopt::Ipv6OrigDstAddr, &true).unwrap();
+		sender
+			.send_to(b"pong", receiver.local_addr().unwrap())
+			.unwrap();
+		assert_eq!(
+			recv_orig_dst(receiver.as_raw_fd(), &mut buf).unwrap(),
+			(
+				4,
+				sender.local_addr().unwrap(),
+				receiver.local_addr().unwrap()
+			)
+		);
+	}
+}
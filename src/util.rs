@@ -1,17 +1,26 @@
-use std::{error::Error, fmt::Display, net::SocketAddr, process::abort};
+use std::{
+	error::Error, fmt::Display, io, net::SocketAddr, path::PathBuf, pin::Pin, sync::Arc,
+	time::Duration,
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use fastwebsockets::{handshake, FragmentCollectorRead};
+use fastwebsockets::{handshake, FragmentCollectorRead, WebSocketError};
 use futures_util::Future;
 use http_body_util::Empty;
 use hyper::{
-	header::{CONNECTION, UPGRADE},
+	header::{CONNECTION, SEC_WEBSOCKET_EXTENSIONS, UPGRADE},
 	rt::Executor,
 	Request,
 };
-use log::info;
-use tokio::net::TcpStream;
+use log::{error, info, warn};
+use nix::errno::Errno;
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::{TcpStream, UnixStream},
+	select,
+	time::{sleep, timeout},
+};
 #[cfg(feature = "native-tls")]
 use tokio_native_tls::{native_tls, TlsConnector};
 #[cfg(feature = "rustls")]
@@ -19,14 +28,31 @@ use tokio_rustls::{
 	rustls::{ClientConfig, RootCertStore},
 	TlsConnector,
 };
-use tokio_util::either::Either;
+use tokio_util::{
+	compat::{Compat, FuturesAsyncReadCompatExt},
+	either::Either,
+};
 use wisp_mux::{
 	extensions::{udp::UdpProtocolExtensionBuilder, ProtocolExtensionBuilder},
 	ws::{Frame, LockedWebSocketWrite, WebSocketRead, WebSocketWrite},
-	ClientMux, WispError,
+	ClientMux, MuxStreamAsyncRW, StreamType, WispError,
+};
+
+use crate::{
+	access::AccessOptions,
+	compress::{log_stats, negotiate},
+	deflate::{DeflateWebSocketRead, DeflateWebSocketWrite, WsDeflateOptions},
+	helper::Helper,
+	keepalive::{ping_loop, Keepalive, KeepaliveRead, RttMetric},
+	mux::{MuxHandle, StreamGuard},
+	noise::secure,
+	pty::{open_command, open_pty, open_stdio, FrameRead, FrameWrite, StreamRead, StreamWrite},
+	socket::{connect, resolve, SocketOptions},
+	WispServer, WispUrl,
 };
 
-use crate::{pty::open_pty, WispServer};
+/// How long a reopened PTY gets to complete the Wisp handshake.
+const PTY_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct SpawnExecutor;
 
@@ -45,12 +71,32 @@ pub enum WhisperError {
 	UriHasNoScheme,
 	UriHasInvalidScheme,
 	UriHasNoHost,
+	UriHasNoPort,
+	UriHasNoPath,
 	NoSocketAddr,
 	NotInitialized,
 	AlreadyInitialized,
 	NotStarted,
 	AlreadyStarted,
 	ChannelExited,
+	MuxClosed(String),
+	NoiseNotSupported,
+	CompressionNotSupported,
+	AllServersDown(String),
+	NoServerAvailable,
+	UnknownOutbound(String),
+	OutboundDown(String, String),
+	ParallelConnectionsNotSupported,
+	ViaNotSupported,
+	UdpNotSupported,
+	AccessDenied(u16),
+	AccessNoCredentials,
+	NoHelper,
+	HelperNotSupported,
+	HelperNotReady(String, u64),
+	HelperFailed(String, String),
+	HelperPortRequired,
+	LinuxOnly,
 	Other(Box<dyn Error>),
 }
 
@@ -60,12 +106,61 @@ impl Display for WhisperError {
 			Self::UriHasNoScheme => write!(f, "URI has no scheme"),
 			Self::UriHasInvalidScheme => write!(f, "URI has invalid scheme"),
 			Self::UriHasNoHost => write!(f, "URI has no host"),
+			Self::UriHasNoPort => write!(f, "URI has no port"),
+			Self::UriHasNoPath => write!(f, "URI has no path"),
 			Self::NoSocketAddr => write!(f, "No socket addr"),
 			Self::NotInitialized => write!(f, "Whisper not initialized"),
 			Self::AlreadyInitialized => write!(f, "Whisper already initialized"),
 			Self::NotStarted => write!(f, "Whisper not started"),
 			Self::AlreadyStarted => write!(f, "Whisper already started"),
 			Self::ChannelExited => write!(f, "Channel exited"),
+			Self::MuxClosed(reason) => write!(f, "Wisp multiplexor closed: {}", reason),
+			Self::NoiseNotSupported => write!(
+				f,
+				"Noise encryption is only supported on length-delimited transports"
+			),
+			Self::CompressionNotSupported => write!(
+				f,
+				"Frame compression is only supported on length-delimited transports"
+			),
+			Self::AllServersDown(reason) => write!(f, "All Wisp servers are down: {}", reason),
+			Self::NoServerAvailable => write!(f, "No Wisp server is up"),
+			Self::UnknownOutbound(name) => write!(f, "Unknown outbound {:?}", name),
+			Self::OutboundDown(name, reason) => write!(f, "Outbound {} is down: {}", name, reason),
+			Self::ParallelConnectionsNotSupported => {
+				write!(f, "Parallel connections are only supported with --url")
+			}
+			Self::ViaNotSupported => write!(
+				f,
+				"--via only supports ws://, wss:// and tcp:// servers after the first hop"
+			),
+			Self::UdpNotSupported => write!(f, "Wisp server doesn't support UDP"),
+			Self::AccessDenied(status) => write!(
+				f,
+				"Cloudflare Access rejected the connection (HTTP {}), check the service token or token file",
+				status
+			),
+			Self::AccessNoCredentials => write!(
+				f,
+				"--cf needs a service token (--cf-client-id and --cf-client-secret) or --cf-token-file"
+			),
+			Self::NoHelper => write!(f, "No helper command given"),
+			Self::HelperNotSupported => {
+				write!(f, "--helper only supports ws://, wss:// and tcp:// servers")
+			}
+			Self::HelperNotReady(name, secs) => write!(
+				f,
+				"Helper {} didn't accept connections within {}s",
+				name, secs
+			),
+			Self::HelperFailed(name, reason) => {
+				write!(f, "Helper {} was given up on: {}", name, reason)
+			}
+			Self::HelperPortRequired => write!(
+				f,
+				"--helper needs --helper-port on this platform, the helper's listener can't be verified"
+			),
+			Self::LinuxOnly => write!(f, "exec and transparent are only supported on Linux"),
 			Self::Other(err) => err.fmt(f),
 		}
 	}
@@ -121,94 +216,693 @@ impl<L: WebSocketWrite + Send, R: WebSocketWrite + Send> WebSocketWrite
 	}
 }
 
-pub async fn connect_to_wisp(
-	opts: &WispServer,
+async fn connect_websocket<S>(
+	socket: S,
+	host: &str,
+	path: &str,
+	deflate: &WsDeflateOptions,
+	access: &AccessOptions,
+	keepalive: Arc<Keepalive>,
 	v2: bool,
-) -> Result<(ClientMux, Option<SocketAddr>), Box<dyn Error>> {
-	let (rx, tx, socketaddr) = if let Some(pty) = &opts.pty {
-		info!("Connecting to PTY: {:?}", pty);
-		let (rx, tx) = open_pty(pty).await?;
-		(
-			EitherWebSocketRead::Right(rx),
-			EitherWebSocketWrite::Right(tx),
-			None,
+) -> Result<(ClientMux, MuxFuture), Box<dyn Error>>
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let mut req = Request::builder()
+		.method("GET")
+		.uri(path)
+		.header("Host", host)
+		.header(UPGRADE, "websocket")
+		.header(CONNECTION, "upgrade")
+		.header(
+			"Sec-WebSocket-Key",
+			fastwebsockets::handshake::generate_key(),
 		)
-	} else if let Some(url) = &opts.url {
-		info!("Connecting to WebSocket: {:?}", url);
-
-		let tls = match url.scheme_str().ok_or(WhisperError::UriHasNoScheme)? {
-			"wss" => Ok(true),
-			"ws" => Ok(false),
-			_ => Err(Box::new(WhisperError::UriHasInvalidScheme)),
-		}?;
-		let host = url.host().ok_or(WhisperError::UriHasNoHost)?;
-		let port = url.port_u16().unwrap_or(if tls { 443 } else { 80 });
-
-		let socket = TcpStream::connect(format!("{}:{}", host, port)).await?;
-		let peer_addr = socket.peer_addr()?;
-		let socket = if tls {
-			#[cfg(feature = "native-tls")]
-			let cx = TlsConnector::from(native_tls::TlsConnector::builder().build()?);
-			#[cfg(feature = "rustls")]
-			let cx = {
-				let mut root_cert_store = RootCertStore::empty();
-				root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-				let config = ClientConfig::builder()
-					.with_root_certificates(root_cert_store)
-					.with_no_client_auth();
-				TlsConnector::from(std::sync::Arc::new(config))
-			};
-			#[cfg(feature = "rustls")]
-			let host = rustls_pki_types::ServerName::try_from(host.to_string())?;
-			Either::Left(cx.connect(host, socket).await?)
-		} else {
-			Either::Right(socket)
-		};
-
-		let req = Request::builder()
-			.method("GET")
-			.uri(url.path())
-			.header("Host", host)
-			.header(UPGRADE, "websocket")
-			.header(CONNECTION, "upgrade")
-			.header(
-				"Sec-WebSocket-Key",
-				fastwebsockets::handshake::generate_key(),
-			)
-			.header("Sec-WebSocket-Version", "13")
-			.body(Empty::<Bytes>::new())?;
+		.header("Sec-WebSocket-Version", "13");
+	if let Some(offer) = deflate.offer() {
+		req = req.header(SEC_WEBSOCKET_EXTENSIONS, offer);
+	}
+	for (name, value) in access.headers()? {
+		req = req.header(name, value);
+	}
+	let req = req.body(Empty::<Bytes>::new())?;
 
-		let (ws, _) = handshake::client(&SpawnExecutor, req, socket).await?;
+	let (ws, resp) = match handshake::client(&SpawnExecutor, req, socket).await {
+		Ok(x) => x,
+		// Access answers with a redirect to its login page or a 403
+		Err(WebSocketError::InvalidStatusCode(status))
+			if access.enabled() && matches!(status, 302 | 401 | 403) =>
+		{
+			return Err(Box::new(WhisperError::AccessDenied(status)))
+		}
+		Err(err) => return Err(Box::new(err)),
+	};
+	access.store(resp.headers());
 
-		let (rx, tx) = ws.split(tokio::io::split);
-		let rx = FragmentCollectorRead::new(rx);
-		(
-			EitherWebSocketRead::Left(rx),
-			EitherWebSocketWrite::Left(tx),
-			Some(peer_addr),
+	let extensions = resp
+		.headers()
+		.get(SEC_WEBSOCKET_EXTENSIONS)
+		.map(|x| x.to_str())
+		.transpose()?;
+	let (mux, fut) = if let Some(params) = deflate.accept(extensions)? {
+		info!("Server accepted permessage-deflate: {:?}", params);
+		let (rx, tx) = tokio::io::split(ws.into_inner());
+		create_mux(
+			KeepaliveRead::new(DeflateWebSocketRead::new(rx, params), keepalive.clone()),
+			DeflateWebSocketWrite::new(tx, params),
+			v2,
 		)
+		.await?
 	} else {
-		unreachable!("neither pty nor url specified");
+		if deflate.ws_deflate {
+			info!("Server declined permessage-deflate");
+		}
+		let (rx, tx) = ws.split(tokio::io::split);
+		let rx = KeepaliveRead::new(FragmentCollectorRead::new(rx), keepalive.clone());
+		create_mux(rx, tx, v2).await?
 	};
 
+	let fut = async move {
+		select! {
+			x = fut => x,
+			x = ping_loop(keepalive) => x,
+		}
+	};
+	Ok((mux, Box::pin(fut)))
+}
+
+pub type MuxFuture = Pin<Box<dyn Future<Output = Result<(), WispError>> + Send>>;
+
+async fn create_mux<R, W>(rx: R, tx: W, v2: bool) -> Result<(ClientMux, MuxFuture), Box<dyn Error>>
+where
+	R: WebSocketRead + Send + 'static,
+	W: WebSocketWrite + Send + 'static,
+{
 	let ext: &[Box<dyn ProtocolExtensionBuilder + Send + Sync>] =
 		&[Box::new(UdpProtocolExtensionBuilder)];
 
 	let muxresp = ClientMux::create(rx, tx, if v2 { Some(ext) } else { None }).await?;
 
-	let (mux, fut) = if v2 {
-		muxresp.with_udp_extension_required().await?
+	// servers without UDP are still usable, see fallback
+	let (mux, fut) = muxresp.with_no_required_extensions();
+
+	info!("Connected.");
+	Ok((mux, Box::pin(fut)))
+}
+
+fn is_hangup(err: &WispError) -> bool {
+	match err {
+		WispError::WsImplSocketClosed => true,
+		WispError::WsImplError(err) => err.downcast_ref::<io::Error>().is_some_and(|x| {
+			x.raw_os_error() == Some(Errno::EIO as i32)
+				|| matches!(
+					x.kind(),
+					io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe
+				)
+		}),
+		_ => false,
+	}
+}
+
+/// Creates the mux over a length-delimited transport, after the Noise and compression
+/// handshakes if they are enabled.
+async fn create_frame_mux<R, W>(
+	mut rx: R,
+	mut tx: W,
+	opts: &WispServer,
+	v2: bool,
+) -> Result<(ClientMux, MuxFuture), Box<dyn Error>>
+where
+	R: WebSocketRead + FrameRead + 'static,
+	W: WebSocketWrite + FrameWrite + 'static,
+{
+	if opts.noise.enabled() {
+		secure(&mut rx, &mut tx, &opts.noise).await?;
+	}
+	if opts.compression.enabled() {
+		let stats = negotiate(&mut rx, &mut tx, &opts.compression, opts.max_frame_length).await?;
+		tokio::spawn(log_stats(Arc::downgrade(&stats)));
+	}
+	create_mux(rx, tx, v2).await
+}
+
+async fn reopen_pty(
+	pty: &PathBuf,
+	opts: &WispServer,
+	v2: bool,
+) -> Result<(ClientMux, MuxFuture), Box<dyn Error>> {
+	let (rx, tx) = open_pty(pty, &opts.serial, opts.max_frame_length).await?;
+	timeout(PTY_HANDSHAKE_TIMEOUT, create_frame_mux(rx, tx, opts, v2)).await?
+}
+
+/// Watches the PTY's multiplexor and rebuilds it when the other end hangs up.
+async fn supervise_pty(
+	handle: MuxHandle,
+	mut fut: MuxFuture,
+	pty: PathBuf,
+	opts: WispServer,
+	v2: bool,
+) {
+	let serial = &opts.serial;
+	loop {
+		let err = match fut.await {
+			Ok(()) => WispError::WsImplSocketClosed,
+			Err(err) => err,
+		};
+		if !is_hangup(&err) || serial.reopen_attempts == 0 {
+			error!("Error in Wisp multiplexor future: {:?}", err);
+			handle.close(err.to_string());
+			return;
+		}
+		warn!("PTY {:?} hung up: {}", pty, err);
+
+		let mut attempt = 0;
+		fut = loop {
+			attempt += 1;
+			if attempt > serial.reopen_attempts {
+				error!(
+					"Giving up on PTY {:?} after {} reopen attempts",
+					pty, serial.reopen_attempts
+				);
+				handle.close(format!("PTY hung up: {}", err));
+				return;
+			}
+			sleep(Duration::from_secs(serial.reopen_delay)).await;
+
+			if tokio::fs::metadata(&pty).await.is_err() {
+				info!(
+					"Waiting for PTY {:?} to reappear ({}/{})",
+					pty, attempt, serial.reopen_attempts
+				);
+				continue;
+			}
+			info!(
+				"Reopening PTY {:?} ({}/{})",
+				pty, attempt, serial.reopen_attempts
+			);
+			match reopen_pty(&pty, &opts, v2).await {
+				Ok((mux, fut)) => {
+					handle.replace(mux);
+					info!("Reopened PTY {:?}", pty);
+					break fut;
+				}
+				Err(err) => warn!("Failed to reopen PTY {:?}: {}", pty, err),
+			}
+		};
+	}
+}
+
+async fn watch_mux(handle: MuxHandle, fut: MuxFuture) {
+	match fut.await {
+		Ok(()) => {
+			info!("Wisp multiplexor closed.");
+			handle.close("connection closed".to_string());
+		}
+		Err(err) => {
+			error!("Error in Wisp multiplexor future: {:?}", err);
+			handle.close(err.to_string());
+		}
+	}
+}
+
+/// Wisp servers a connection is tunneled through. Closes them once the connection is gone.
+struct HopChain(Vec<MuxHandle>);
+
+impl Drop for HopChain {
+	fn drop(&mut self) {
+		for hop in self.0.drain(..).rev() {
+			hop.close("connection through it closed".to_string());
+			tokio::spawn(async move {
+				let _ = hop.get().close().await;
+			});
+		}
+	}
+}
+
+/// Connects the `--via` hops in order, each one through the previous. Returns the address of the
+/// first hop.
+async fn connect_hops(
+	opts: &WispServer,
+	v2: bool,
+) -> Result<(HopChain, Option<SocketAddr>), Box<dyn Error>> {
+	let mut chain = HopChain(Vec::new());
+	let mut socketaddr = None;
+	for (i, hop) in opts.via.iter().enumerate() {
+		let hop_opts = WispServer {
+			pty: None,
+			stdio: false,
+			command: None,
+			url: vec![hop.clone()],
+			via: Vec::new(),
+			// noise, compression and Access credentials are for the final server
+			noise: Default::default(),
+			compression: Default::default(),
+			access: Default::default(),
+			helper: Default::default(),
+			helper_handle: None,
+			..opts.clone()
+		};
+		let rtt = Arc::new(RttMetric::default());
+		let (mux, fut, addr) = connect_transport(&hop_opts, chain.0.last(), &rtt, v2).await?;
+		if i == 0 {
+			socketaddr = addr;
+		}
+		let handle = MuxHandle::new(mux, rtt);
+		tokio::spawn(watch_mux(handle.clone(), fut));
+		chain.0.push(handle);
+	}
+	Ok((chain, socketaddr))
+}
+
+type Socket = Either<Compat<MuxStreamAsyncRW>, TcpStream>;
+
+/// Opens a TCP connection, through the Wisp server `via` or the local `helper` if given.
+async fn dial(
+	host: &str,
+	port: u16,
+	via: Option<&MuxHandle>,
+	helper: Option<&Helper>,
+	socket_opts: &SocketOptions,
+) -> Result<(Socket, Option<SocketAddr>, Option<StreamGuard>), Box<dyn Error>> {
+	match (via, helper) {
+		(Some(via), _) => {
+			let (stream, guard) = via
+				.new_stream(StreamType::Tcp, host.to_string(), port)
+				.await?;
+			Ok((
+				Either::Left(stream.into_io().into_asyncrw().compat()),
+				None,
+				Some(guard),
+			))
+		}
+		(None, Some(helper)) => {
+			let addr = helper.ready().await?;
+			let socket = TcpStream::connect(addr).await?;
+			// the helper's address is of no use for allowlisting, the server's is if it resolves
+			let peer_addr = resolve(host, port, socket_opts)
+				.await
+				.ok()
+				.and_then(|x| x.first().copied());
+			Ok((Either::Right(socket), peer_addr, None))
+		}
+		(None, None) => {
+			let socket = connect(host, port, socket_opts).await?;
+			let peer_addr = socket.peer_addr()?;
+			Ok((Either::Right(socket), Some(peer_addr), None))
+		}
+	}
+}
+
+/// Keeps `value` alive for as long as the mux future runs.
+fn hold<T: Send + 'static>(fut: MuxFuture, value: T) -> MuxFuture {
+	Box::pin(async move {
+		let _value = value;
+		fut.await
+	})
+}
+
+/// Looks up the address [`connect_to_wisp`] would connect to, without connecting.
+pub async fn resolve_server(opts: &WispServer) -> Option<SocketAddr> {
+	let (host, port) = match opts.via.first().or(opts.url.first())? {
+		WispUrl::Uri(url) => {
+			let tls = matches!(url.scheme_str()?, "wss" | "https");
+			(
+				url.host()?,
+				url.port_u16().unwrap_or(if tls { 443 } else { 80 }),
+			)
+		}
+		WispUrl::Tcp { host, port } => (host.as_str(), *port),
+		WispUrl::UnixWebSocket { .. } | WispUrl::Unix(_) => return None,
+	};
+	resolve(host, port, &opts.socket)
+		.await
+		.ok()?
+		.first()
+		.copied()
+}
+
+pub async fn connect_to_wisp(
+	opts: &WispServer,
+	v2: bool,
+) -> Result<(MuxHandle, Option<SocketAddr>), Box<dyn Error>> {
+	let rtt = Arc::new(RttMetric::default());
+	let (chain, hop_socketaddr) = connect_hops(opts, v2).await?;
+	let (mux, fut, socketaddr) = connect_transport(opts, chain.0.last(), &rtt, v2).await?;
+	let (fut, socketaddr) = if chain.0.is_empty() {
+		(fut, socketaddr)
 	} else {
-		muxresp.with_no_required_extensions()
+		(hold(fut, chain), hop_socketaddr)
 	};
+	let handle = MuxHandle::new(mux, rtt);
+	if let Some(pty) = &opts.pty {
+		tokio::spawn(supervise_pty(
+			handle.clone(),
+			fut,
+			pty.clone(),
+			opts.clone(),
+			v2,
+		));
+	} else {
+		tokio::spawn(watch_mux(handle.clone(), fut));
+	}
+	Ok((handle, socketaddr))
+}
 
-	tokio::spawn(async move {
-		if let Err(err) = fut.await {
-			eprintln!("Error in Wisp multiplexor future: {:?}", err);
-			abort();
+async fn connect_transport(
+	opts: &WispServer,
+	via: Option<&MuxHandle>,
+	rtt: &Arc<RttMetric>,
+	v2: bool,
+) -> Result<(ClientMux, MuxFuture, Option<SocketAddr>), Box<dyn Error>> {
+	if via.is_some()
+		&& !matches!(
+			opts.url.first(),
+			Some(WispUrl::Uri(_) | WispUrl::Tcp { .. })
+		) {
+		return Err(Box::new(WhisperError::ViaNotSupported));
+	}
+	if let Some(pty) = &opts.pty {
+		info!("Connecting to PTY: {:?}", pty);
+		let (rx, tx) = open_pty(pty, &opts.serial, opts.max_frame_length).await?;
+		let (mux, fut) = create_frame_mux(rx, tx, opts, v2).await?;
+		Ok((mux, fut, None))
+	} else if opts.stdio {
+		info!("Connecting over stdio");
+		let (rx, tx) = open_stdio(opts.max_frame_length);
+		let (mux, fut) = create_frame_mux(rx, tx, opts, v2).await?;
+		Ok((mux, fut, None))
+	} else if let Some(command) = &opts.command {
+		info!("Connecting through command: {:?}", command);
+		let (rx, tx) = open_command(command, opts.max_frame_length)?;
+		let (mux, fut) = create_frame_mux(rx, tx, opts, v2).await?;
+		Ok((mux, fut, None))
+	} else if let Some(url) = opts.url.first() {
+		if matches!(url, WispUrl::Uri(_) | WispUrl::UnixWebSocket { .. }) {
+			if opts.noise.enabled() {
+				return Err(Box::new(WhisperError::NoiseNotSupported));
+			}
+			if opts.compression.enabled() {
+				return Err(Box::new(WhisperError::CompressionNotSupported));
+			}
 		}
-	});
+		match url {
+			WispUrl::Uri(url) => {
+				info!("Connecting to WebSocket: {:?}", url);
 
-	info!("Connected.");
-	Ok((mux, socketaddr))
+				let tls = match url.scheme_str().ok_or(WhisperError::UriHasNoScheme)? {
+					"wss" => Ok(true),
+					"ws" => Ok(false),
+					"https" if opts.access.enabled() => Ok(true),
+					"http" if opts.access.enabled() => Ok(false),
+					_ => Err(Box::new(WhisperError::UriHasInvalidScheme)),
+				}?;
+				let host = url.host().ok_or(WhisperError::UriHasNoHost)?;
+				let port = url.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+				let (socket, peer_addr, guard) =
+					dial(host, port, via, opts.helper_handle.as_ref(), &opts.socket).await?;
+				let socket = if tls {
+					#[cfg(feature = "native-tls")]
+					let cx = TlsConnector::from(native_tls::TlsConnector::builder().build()?);
+					#[cfg(feature = "rustls")]
+					let cx = {
+						let mut root_cert_store = RootCertStore::empty();
+						root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+						let config = ClientConfig::builder()
+							.with_root_certificates(root_cert_store)
+							.with_no_client_auth();
+						TlsConnector::from(std::sync::Arc::new(config))
+					};
+					#[cfg(feature = "rustls")]
+					let host = rustls_pki_types::ServerName::try_from(host.to_string())?;
+					Either::Left(cx.connect(host, socket).await?)
+				} else {
+					Either::Right(socket)
+				};
+
+				let (mux, fut) = connect_websocket(
+					socket,
+					host,
+					url.path(),
+					&opts.ws_deflate,
+					&opts.access,
+					Keepalive::new(opts.keepalive.clone(), rtt.clone()),
+					v2,
+				)
+				.await?;
+				Ok((mux, hold(fut, guard), peer_addr))
+			}
+			WispUrl::UnixWebSocket { socket, path } => {
+				info!("Connecting to WebSocket over Unix socket: {:?}", socket);
+				let socket = UnixStream::connect(socket).await?;
+				let (mux, fut) = connect_websocket(
+					socket,
+					"localhost",
+					path,
+					&opts.ws_deflate,
+					&opts.access,
+					Keepalive::new(opts.keepalive.clone(), rtt.clone()),
+					v2,
+				)
+				.await?;
+				Ok((mux, fut, None))
+			}
+			WispUrl::Unix(socket) => {
+				info!("Connecting to Unix socket: {:?}", socket);
+				let (rx, tx) = UnixStream::connect(socket).await?.into_split();
+				let (mux, fut) = create_frame_mux(
+					StreamRead::new(rx, opts.max_frame_length),
+					StreamWrite::new(tx, opts.max_frame_length),
+					opts,
+					v2,
+				)
+				.await?;
+				Ok((mux, fut, None))
+			}
+			WispUrl::Tcp { host, port } => {
+				info!("Connecting to TCP socket: {}:{}", host, port);
+				let (socket, peer_addr, guard) =
+					dial(host, *port, via, opts.helper_handle.as_ref(), &opts.socket).await?;
+				let (rx, tx) = tokio::io::split(socket);
+				let (mux, fut) = create_frame_mux(
+					StreamRead::new(rx, opts.max_frame_length),
+					StreamWrite::new(tx, opts.max_frame_length),
+					opts,
+					v2,
+				)
+				.await?;
+				Ok((mux, hold(fut, guard), peer_addr))
+			}
+		}
+	} else {
+		unreachable!("no transport specified");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, os::unix::fs::PermissionsExt};
+
+	use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+	use super::*;
+	use crate::{keepalive::KeepaliveOptions, SerialOptions};
+
+	/// Stands in for Cloudflare Access: reads the upgrade request and answers with `status`.
+	/// Returns the request.
+	async fn access(mut socket: DuplexStream, status: u16) -> String {
+		let mut request = Vec::new();
+		while !request.ends_with(b"\r\n\r\n") {
+			request.push(socket.read_u8().await.unwrap());
+		}
+		let response = match status {
+			101 => concat!(
+				"HTTP/1.1 101 Switching Protocols\r\n",
+				"Upgrade: websocket\r\n",
+				"Connection: Upgrade\r\n",
+				"Set-Cookie: CF_Authorization=new; Path=/; HttpOnly\r\n\r\n",
+			)
+			.to_string(),
+			302 => "HTTP/1.1 302 Found\r\nLocation: https://example.cloudflareaccess.com/\r\n\
+			        Content-Length: 0\r\n\r\n"
+				.to_string(),
+			status => format!("HTTP/1.1 {} Denied\r\nContent-Length: 0\r\n\r\n", status),
+		};
+		socket.write_all(response.as_bytes()).await.unwrap();
+		if status == 101 {
+			// Wisp v1 CONTINUE on stream 0
+			socket
+				.write_all(&[0x82, 9, 0x03, 0, 0, 0, 0, 0x80, 0, 0, 0])
+				.await
+				.unwrap();
+		}
+		// keep the connection open until the client is done
+		let _ = socket.read_u8().await;
+		String::from_utf8(request).unwrap()
+	}
+
+	async fn connect(
+		opts: &AccessOptions,
+		status: u16,
+	) -> (Result<(ClientMux, MuxFuture), Box<dyn Error>>, String) {
+		let (client, server) = duplex(4096);
+		let server = tokio::spawn(access(server, status));
+		let keepalive = Keepalive::new(KeepaliveOptions::default(), Arc::new(RttMetric::default()));
+		let result = connect_websocket(
+			client,
+			"wisp.example.com",
+			"/",
+			&WsDeflateOptions::default(),
+			opts,
+			keepalive,
+			false,
+		)
+		.await;
+		// the client half is dropped on failure, which ends the stand-in
+		let request = match &result {
+			Ok(_) => String::new(),
+			Err(_) => server.await.unwrap(),
+		};
+		(result, request)
+	}
+
+	fn token_file(name: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("whisper-{}-{}", name, std::process::id()));
+		let _ = fs::remove_file(&path);
+		path
+	}
+
+	#[tokio::test]
+	async fn access_denied() {
+		let opts = AccessOptions {
+			cf: true,
+			cf_client_id: Some("id.access".to_string()),
+			cf_client_secret: Some("secret".to_string()),
+			cf_token_file: None,
+		};
+		for status in [302, 401, 403] {
+			let (result, request) = connect(&opts, status).await;
+			let request = request.to_lowercase();
+			assert!(request.contains("cf-access-client-id: id.access\r\n"));
+			assert!(request.contains("cf-access-client-secret: secret\r\n"));
+			match result.err().unwrap().downcast_ref::<WhisperError>() {
+				Some(WhisperError::AccessDenied(x)) => assert_eq!(*x, status),
+				err => panic!("{}: {:?}", status, err.map(|x| x.to_string())),
+			}
+		}
+
+		// other errors are not mistaken for Access
+		let (result, _) = connect(&opts, 500).await;
+		assert!(result
+			.err()
+			.unwrap()
+			.downcast_ref::<WhisperError>()
+			.is_none());
+	}
+
+	#[tokio::test]
+	async fn access_token_file() {
+		let path = token_file("access-token");
+		fs::write(&path, "old\n").unwrap();
+		let opts = AccessOptions {
+			cf: true,
+			cf_client_id: None,
+			cf_client_secret: None,
+			cf_token_file: Some(path.clone()),
+		};
+		let (_, request) = connect(&opts, 403).await;
+		assert!(request.contains("cookie: CF_Authorization=old\r\n"));
+
+		fs::remove_file(&path).unwrap();
+		// a service token fills in a missing file
+		let opts = AccessOptions {
+			cf_client_id: Some("id.access".to_string()),
+			cf_client_secret: Some("secret".to_string()),
+			..opts
+		};
+		let (result, _) = connect(&opts, 101).await;
+		assert!(result.is_ok());
+		assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+		assert_eq!(
+			fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+			0o600
+		);
+
+		// an existing file readable by others is made private before the token is written
+		fs::write(&path, "old").unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+		let (result, _) = connect(&opts, 101).await;
+		assert!(result.is_ok());
+		assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+		assert_eq!(
+			fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+			0o600
+		);
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn hangups() {
+		let io = |x: io::Error| WispError::WsImplError(Box::new(x));
+		assert!(is_hangup(&WispError::WsImplSocketClosed));
+		assert!(is_hangup(&io(io::Error::from_raw_os_error(
+			Errno::EIO as i32
+		))));
+		assert!(is_hangup(&io(io::ErrorKind::UnexpectedEof.into())));
+		assert!(is_hangup(&io(io::ErrorKind::BrokenPipe.into())));
+
+		assert!(!is_hangup(&io(io::ErrorKind::ConnectionRefused.into())));
+		assert!(!is_hangup(&io(io::Error::from_raw_os_error(
+			Errno::EACCES as i32
+		))));
+		assert!(!is_hangup(&WispError::WsImplError("not io".into())));
+		assert!(!is_hangup(&WispError::InvalidStreamId));
+	}
+
+	/// Runs [`supervise_pty`] for a mux that fails with `err` right away and returns the error
+	/// the handle was closed with.
+	async fn supervise(err: WispError, pty: PathBuf, reopen_attempts: u32) -> String {
+		let (handle, _server) = crate::mux::tests::handle().await;
+		let opts = WispServer {
+			serial: SerialOptions {
+				reopen_attempts,
+				reopen_delay: 0,
+				..Default::default()
+			},
+			..Default::default()
+		};
+		let fut: MuxFuture = Box::pin(async move { Err(err) });
+		timeout(
+			Duration::from_secs(5),
+			supervise_pty(handle.clone(), fut, pty, opts, false),
+		)
+		.await
+		.expect("supervise_pty kept reopening");
+		match handle.error() {
+			Some(WhisperError::MuxClosed(x)) => x,
+			err => panic!("{:?}", err.map(|x| x.to_string())),
+		}
+	}
+
+	#[tokio::test]
+	async fn pty_reopen_gives_up() {
+		// a PTY that doesn't come back
+		let missing = token_file("missing-pty");
+		let err = supervise(WispError::WsImplSocketClosed, missing.clone(), 3).await;
+		assert!(err.starts_with("PTY hung up"), "{}", err);
+
+		// a PTY that comes back but can't be opened as one
+		let file = token_file("not-a-pty");
+		fs::write(&file, "").unwrap();
+		let eio = WispError::WsImplError(Box::new(io::Error::from_raw_os_error(Errno::EIO as i32)));
+		let err = supervise(eio, file.clone(), 3).await;
+		assert!(err.starts_with("PTY hung up"), "{}", err);
+		fs::remove_file(&file).unwrap();
+
+		// other errors and disabled reopening close right away
+		let err = supervise(WispError::InvalidStreamId, missing.clone(), 3).await;
+		assert_eq!(err, WispError::InvalidStreamId.to_string());
+		let err = supervise(WispError::WsImplSocketClosed, missing, 0).await;
+		assert_eq!(err, WispError::WsImplSocketClosed.to_string());
+	}
 }